@@ -0,0 +1,6 @@
+use lib::sftp::execute;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    execute();
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// 字典,键按照字节序排列
+pub type Dict = BTreeMap<Vec<u8>, Value>;
+
+/// bencode 编码的值
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(Dict),
+}
+
+impl Value {
+    /// 获取字典中的值
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// 字符串,不是合法的utf-8时替换非法字符
+    pub fn as_str(&self) -> Option<String> {
+        self.as_bytes()
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// 编码,字典的键按照字节序排列
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => buf.extend(format!("i{}e", i).as_bytes()),
+            Value::Bytes(bytes) => {
+                buf.extend(format!("{}:", bytes.len()).as_bytes());
+                buf.extend(bytes);
+            }
+            Value::List(list) => {
+                buf.push(b'l');
+                for value in list {
+                    value.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+            Value::Dict(dict) => {
+                buf.push(b'd');
+                for (key, value) in dict {
+                    buf.extend(format!("{}:", key.len()).as_bytes());
+                    buf.extend(key);
+                    value.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+/// 列表和字典最多嵌套的层数,避免恶意数据耗尽栈空间
+const MAX_DEPTH: usize = 64;
+
+/// 解码器,记录当前解析到的位置和嵌套的层数
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Decoder {
+            buf,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    /// 进入一层列表或字典
+    fn enter(&mut self) -> Result<()> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow!("bencode data is nested too deeply"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| anyhow!("unexpected end of bencode data"))
+    }
+
+    /// 读取到 end 为止的整数,不包括 end
+    fn read_number(&mut self, end: u8) -> Result<i64> {
+        let start = self.pos;
+        while self.peek()? != end {
+            self.pos += 1;
+        }
+        let number = std::str::from_utf8(&self.buf[start..self.pos])?;
+        self.pos += 1;
+        // 不允许 i-0e 和有前导0的整数
+        if number.starts_with("-0") || (number.starts_with('0') && number.len() > 1) {
+            return Err(anyhow!("invalid bencode integer {:?}", number));
+        }
+        Ok(number.parse::<i64>()?)
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_number(b':')?;
+        if len < 0 || self.pos + len as usize > self.buf.len() {
+            return Err(anyhow!("invalid bencode string length"));
+        }
+        let bytes = self.buf[self.pos..self.pos + len as usize].to_vec();
+        self.pos += len as usize;
+        Ok(bytes)
+    }
+
+    fn read_value(&mut self) -> Result<Value> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Int(self.read_number(b'e')?))
+            }
+            b'l' => {
+                self.enter()?;
+                self.pos += 1;
+                let mut list = vec![];
+                while self.peek()? != b'e' {
+                    list.push(self.read_value()?);
+                }
+                self.pos += 1;
+                self.depth -= 1;
+                Ok(Value::List(list))
+            }
+            b'd' => Ok(Value::Dict(self.read_dict(None)?.0)),
+            b'0'..=b'9' => Ok(Value::Bytes(self.read_bytes()?)),
+            c => Err(anyhow!("invalid bencode type {:?}", c as char)),
+        }
+    }
+
+    /// 读取字典,同时记录 key 对应的值在原始数据中的位置
+    fn read_dict(&mut self, key: Option<&[u8]>) -> Result<(Dict, Option<Range<usize>>)> {
+        self.enter()?;
+        self.pos += 1;
+        let mut dict = BTreeMap::new();
+        let mut span = None;
+        while self.peek()? != b'e' {
+            let name = self.read_bytes()?;
+            let start = self.pos;
+            let value = self.read_value()?;
+            if key == Some(name.as_slice()) {
+                span = Some(start..self.pos);
+            }
+            dict.insert(name, value);
+        }
+        self.pos += 1;
+        self.depth -= 1;
+        Ok((dict, span))
+    }
+}
+
+/// 解码 buf 开头的一个值,返回值和它占用的长度
+/// 用于值后面还跟着其它数据的情况
+pub fn decode_prefix(buf: &[u8]) -> Result<(Value, usize)> {
+    let mut decoder = Decoder::new(buf);
+    let value = decoder.read_value()?;
+    Ok((value, decoder.pos))
+}
+
+/// 解码完整的数据
+pub fn decode(buf: &[u8]) -> Result<Value> {
+    let (value, len) = decode_prefix(buf)?;
+    if len != buf.len() {
+        return Err(anyhow!("trailing data after bencode value"));
+    }
+    Ok(value)
+}
+
+/// 解码字典,并返回 key 对应的值在原始数据中的位置
+/// 计算 info_hash 时需要使用 info 字典的原始数据
+pub fn decode_with_span(buf: &[u8], key: &str) -> Result<(Value, Option<Range<usize>>)> {
+    let mut decoder = Decoder::new(buf);
+    if decoder.peek()? != b'd' {
+        return Err(anyhow!("bencode data is not a dictionary"));
+    }
+    let (dict, span) = decoder.read_dict(Some(key.as_bytes()))?;
+    if decoder.pos != buf.len() {
+        return Err(anyhow!("trailing data after bencode value"));
+    }
+    Ok((Value::Dict(dict), span))
+}
+
+#[cfg(test)]
+mod bencode_test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let value = decode(b"d3:agei-3e4:listl1:ai0ee4:name4:spame").unwrap();
+        assert_eq!(value.get("age").and_then(|v| v.as_int()), Some(-3));
+        assert_eq!(
+            value.get("name").and_then(|v| v.as_str()),
+            Some("spam".to_string())
+        );
+        assert_eq!(
+            value.get("list").and_then(|v| v.as_list()).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            value.encode(),
+            b"d3:agei-3e4:listl1:ai0ee4:name4:spame".to_vec()
+        );
+        assert!(decode(b"i03e").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+        let (value, len) = decode_prefix(b"d1:ai1eexyz").unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(value.get("a"), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn test_decode_with_span() {
+        // info 中含有 private 等其它键时也能取出原始数据
+        let buf = b"d8:announce3:url4:infod6:lengthi5e4:name1:x7:privatei1eee";
+        let (value, span) = decode_with_span(buf, "info").unwrap();
+        let span = span.unwrap();
+        assert_eq!(&buf[span], b"d6:lengthi5e4:name1:x7:privatei1ee");
+        assert_eq!(
+            value.get("info").and_then(|info| info.get("private")),
+            Some(&Value::Int(1))
+        );
+        assert_eq!(decode_with_span(b"d1:ai1ee", "info").unwrap().1, None);
+    }
+
+    #[test]
+    fn test_decode_depth() {
+        let nested = |depth: usize| {
+            let mut buf = vec![b'l'; depth];
+            buf.extend(vec![b'e'; depth]);
+            buf
+        };
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert!(decode(&nested(MAX_DEPTH + 1)).is_err());
+        // 嵌套很深的数据不会耗尽栈空间
+        assert!(decode(&nested(1_000_000)).is_err());
+        let mut buf = b"d1:a".repeat(MAX_DEPTH + 1);
+        buf.extend(b"i1e");
+        buf.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert!(decode_with_span(&buf, "a").is_err());
+    }
+}
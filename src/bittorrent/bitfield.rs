@@ -0,0 +1,76 @@
+/// piece的位图,最高位表示第一个piece
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    // piece数量
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// 从收到的数据建立位图,长度不足时补0
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Bitfield::new(len);
+        let n = bitfield.bits.len().min(bytes.len());
+        bitfield.bits[..n].copy_from_slice(&bytes[..n]);
+        bitfield
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] >> (7 - index % 8) & 1 != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] |= 1 << (7 - index % 8);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 已经拥有的piece数量
+    pub fn count(&self) -> usize {
+        (0..self.len).filter(|index| self.has(*index)).count()
+    }
+
+    /// 是否拥有所有piece
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+#[cfg(test)]
+mod bitfield_test {
+    use super::Bitfield;
+
+    #[test]
+    fn test_bitfield() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes().len(), 2);
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(10);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.has(9) && !bitfield.has(1) && !bitfield.has(10));
+        assert_eq!(bitfield.count(), 2);
+        let bitfield = Bitfield::from_bytes(&[0xff], 10);
+        assert_eq!(bitfield.count(), 8);
+        assert!(!bitfield.is_complete());
+    }
+}
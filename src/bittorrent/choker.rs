@@ -0,0 +1,221 @@
+use rand::seq::SliceRandom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+// 重新选择解除阻塞的peer的间隔
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// 按照速度解除阻塞的peer数量
+const REGULAR_SLOTS: usize = 3;
+// 每隔几轮更换一次乐观解除阻塞的peer,即30秒
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// 一个连接的状态,由连接的线程更新,choker 读取
+#[derive(Debug, Default)]
+pub struct PeerState {
+    // 对方是否对本机的piece感兴趣
+    interested: AtomicBool,
+    // 从对方下载的字节数
+    downloaded: AtomicU64,
+    // 上传给对方的字节数
+    uploaded: AtomicU64,
+    // choker 是否决定解除对对方的阻塞
+    unchoked: AtomicBool,
+}
+
+impl PeerState {
+    pub fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, length: u64) {
+        self.downloaded.fetch_add(length, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, length: u64) {
+        self.uploaded.fetch_add(length, Ordering::Relaxed);
+    }
+
+    /// 连接应该阻塞还是解除阻塞对方
+    pub fn is_unchoked(&self) -> bool {
+        self.unchoked.load(Ordering::Relaxed)
+    }
+}
+
+/// choker 记录的一个peer
+struct Entry {
+    state: Weak<PeerState>,
+    // 上一轮时的下载和上传量,用来计算这一轮的速度
+    downloaded: u64,
+    uploaded: u64,
+}
+
+#[derive(Default)]
+struct Peers {
+    entries: Vec<Entry>,
+    // 已经进行的轮数
+    round: u64,
+    // 乐观解除阻塞的peer
+    optimistic: Weak<PeerState>,
+}
+
+/// 决定向哪些peer上传
+/// 下载时解除阻塞向本机上传最快的peer(tit-for-tat),做种时解除阻塞下载最快的peer,
+/// 另外轮流乐观解除阻塞一个peer,让新的peer有机会证明自己
+#[derive(Clone, Default)]
+pub struct Choker {
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 加入一个连接,连接关闭时丢弃返回的状态即可
+    pub fn register(&self) -> Arc<PeerState> {
+        let state = Arc::new(PeerState::default());
+        self.peers.lock().unwrap().entries.push(Entry {
+            state: Arc::downgrade(&state),
+            downloaded: 0,
+            uploaded: 0,
+        });
+        state
+    }
+
+    /// 对方表示感兴趣,有空闲的位置时立即解除阻塞,不用等到下一轮
+    pub fn interested(&self, state: &PeerState) {
+        state.set_interested(true);
+        let peers = self.peers.lock().unwrap();
+        let unchoked = peers
+            .entries
+            .iter()
+            .filter_map(|entry| entry.state.upgrade())
+            .filter(|state| state.is_unchoked())
+            .count();
+        if unchoked < REGULAR_SLOTS + 1 {
+            state.unchoked.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// 对方不再感兴趣,空出的位置在下一轮分配
+    pub fn not_interested(&self, state: &PeerState) {
+        state.set_interested(false);
+        state.unchoked.store(false, Ordering::Relaxed);
+    }
+
+    /// 重新选择解除阻塞的peer,每隔 CHOKE_INTERVAL 调用一次
+    /// seeding 为 true 时按照上传给对方的速度排序
+    pub fn rechoke(&self, seeding: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entries.retain(|entry| entry.state.strong_count() > 0);
+
+        // 计算这一轮每个peer的速度
+        let mut interested = vec![];
+        for entry in peers.entries.iter_mut() {
+            let state = match entry.state.upgrade() {
+                Some(state) => state,
+                None => continue,
+            };
+            let downloaded = state.downloaded.load(Ordering::Relaxed);
+            let uploaded = state.uploaded.load(Ordering::Relaxed);
+            let rate = match seeding {
+                true => uploaded - entry.uploaded,
+                false => downloaded - entry.downloaded,
+            };
+            entry.downloaded = downloaded;
+            entry.uploaded = uploaded;
+            if state.interested.load(Ordering::Relaxed) {
+                interested.push((rate, state));
+            } else {
+                state.unchoked.store(false, Ordering::Relaxed);
+            }
+        }
+
+        // 速度相同时随机排序,避免总是选择同一个peer
+        interested.shuffle(&mut rand::thread_rng());
+        interested.sort_by_key(|(rate, _)| std::cmp::Reverse(*rate));
+        let (regular, rest) = interested.split_at(interested.len().min(REGULAR_SLOTS));
+
+        // 乐观解除阻塞的peer仍然被阻塞并且感兴趣时保持,直到轮换
+        let mut optimistic = peers
+            .optimistic
+            .upgrade()
+            .filter(|optimistic| rest.iter().any(|(_, state)| Arc::ptr_eq(state, optimistic)));
+        if optimistic.is_none() || peers.round.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            optimistic = rest
+                .choose(&mut rand::thread_rng())
+                .map(|(_, state)| state.clone());
+        }
+
+        for (_, state) in regular {
+            state.unchoked.store(true, Ordering::Relaxed);
+        }
+        for (_, state) in rest {
+            let unchoked = optimistic
+                .as_ref()
+                .is_some_and(|optimistic| Arc::ptr_eq(state, optimistic));
+            state.unchoked.store(unchoked, Ordering::Relaxed);
+        }
+        peers.optimistic = optimistic.as_ref().map(Arc::downgrade).unwrap_or_default();
+        peers.round += 1;
+    }
+}
+
+#[cfg(test)]
+mod choker_test {
+    use super::*;
+
+    #[test]
+    fn test_rechoke() {
+        let choker = Choker::new();
+        let states: Vec<Arc<PeerState>> = (0..6).map(|_| choker.register()).collect();
+        for (i, state) in states.iter().enumerate() {
+            state.add_downloaded(i as u64 * 100);
+            state.add_uploaded(1000 - i as u64 * 100);
+            state.set_interested(i != 5);
+        }
+
+        // 下载时解除阻塞上传最快的3个peer和1个乐观解除阻塞的peer
+        choker.rechoke(false);
+        let unchoked: Vec<bool> = states.iter().map(|s| s.is_unchoked()).collect();
+        assert_eq!(&unchoked[2..], &[true, true, true, false]);
+        assert_eq!(unchoked.iter().filter(|u| **u).count(), 4);
+        let optimistic = (0..2).find(|i| unchoked[*i]).unwrap();
+
+        // 保持乐观解除阻塞的peer直到轮换
+        for _ in 0..2 {
+            for state in &states[2..5] {
+                state.add_downloaded(100);
+            }
+            choker.rechoke(false);
+            assert!(states[optimistic].is_unchoked());
+        }
+
+        // 做种时按照上传速度排序
+        for state in states.iter() {
+            state.add_uploaded(1000);
+        }
+        states[4].add_uploaded(1);
+        choker.rechoke(true);
+        assert!(states[4].is_unchoked());
+        assert!(!states[5].is_unchoked());
+        assert_eq!(states.iter().filter(|s| s.is_unchoked()).count(), 4);
+
+        // 不感兴趣的peer立即阻塞,关闭的连接被移除
+        choker.not_interested(&states[4]);
+        assert!(!states[4].is_unchoked());
+        drop(states);
+        choker.rechoke(true);
+        assert!(choker.peers.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_interested_with_free_slot() {
+        let choker = Choker::new();
+        let state = choker.register();
+        assert!(!state.is_unchoked());
+        choker.interested(&state);
+        assert!(state.is_unchoked());
+    }
+}
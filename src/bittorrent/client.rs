@@ -1,3 +1,5 @@
+use crate::bittorrent::codec::MessageCodec;
+use crate::bittorrent::extension::*;
 use crate::bittorrent::handshake::*;
 use crate::bittorrent::message::*;
 use crate::bittorrent::piece::*;
@@ -6,9 +8,19 @@ use anyhow::{anyhow, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::bittorrent::peer::Peer;
-use std::io::{Cursor, Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use futures_util::{SinkExt, StreamExt};
+use std::io::Cursor;
+use std::net::SocketAddr;
 use std::time::Duration;
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+// 建立连接的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+// 默认的读写超时时间
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// 客户端定义
 pub struct Client {
@@ -17,38 +29,92 @@ pub struct Client {
     peer_id: Vec<u8>,
     // Torrent info hash
     info_hash: Vec<u8>,
-    // Connection to peer
-    conn: TcpStream,
+    // Connection to peer,握手之后按照 MessageCodec 的格式读写消息
+    conn: Framed<TcpStream, MessageCodec>,
+    // 读写的超时时间
+    timeout: Duration,
     // Bitfield 可以理解成一个二进制 bit 数组，数组值为1 ，就代表有这个块，0代表没有
     bitfield: Vec<u8>,
-    // Peer 是否断开此链接
-    choked: bool,
+    // 本机是否阻塞对方,阻塞时不响应对方的请求
+    am_choking: bool,
+    // 本机是否对对方的piece感兴趣
+    am_interested: bool,
+    // 对方是否阻塞本机
+    peer_choking: bool,
+    // 对方是否对本机的piece感兴趣
+    peer_interested: bool,
+    // 对方是否支持扩展协议
+    peer_extension_protocol: bool,
+    // 注册的扩展
+    extensions: Extensions,
 }
 
 impl Client {
-    pub fn new(peer: Peer, peer_id: Vec<u8>, info_hash: Vec<u8>) -> Result<Client> {
+    pub async fn new(peer: Peer, peer_id: Vec<u8>, info_hash: Vec<u8>) -> Result<Client> {
         // 与peer建立链接
-        let peer_socket = SocketAddr::new(IpAddr::V4(peer.ip), peer.port);
-        let conn = match TcpStream::connect_timeout(&peer_socket, Duration::from_secs(15)) {
-            Ok(conn) => conn,
-            Err(_) => return Err(anyhow!("could not connect to peer")),
+        let peer_socket = SocketAddr::new(peer.ip, peer.port);
+        let conn = match timeout(CONNECT_TIMEOUT, TcpStream::connect(peer_socket)).await {
+            Ok(Ok(conn)) => conn,
+            _ => return Err(anyhow!("could not connect to peer")),
         };
         info!("Connected to peer {:?}", peer.id);
         let client = Client {
             peer,
             peer_id,
             info_hash,
-            conn,
+            conn: Framed::new(conn, MessageCodec),
+            timeout: DEFAULT_TIMEOUT,
             bitfield: vec![],
-            choked: true,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_extension_protocol: false,
+            extensions: Extensions::default(),
         };
 
         Ok(client)
     }
 
+    /// 使用对方发起的连接建立客户端
+    pub fn from_stream(
+        peer: Peer,
+        conn: TcpStream,
+        peer_id: Vec<u8>,
+        info_hash: Vec<u8>,
+    ) -> Client {
+        info!("Accepted connection from peer {:?}", peer.id);
+        Client {
+            peer,
+            peer_id,
+            info_hash,
+            conn: Framed::new(conn, MessageCodec),
+            timeout: DEFAULT_TIMEOUT,
+            bitfield: vec![],
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_extension_protocol: false,
+            extensions: Extensions::default(),
+        }
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
     // 返回客户端是否被阻塞
-    pub fn is_choked(&self) -> bool {
-        self.choked
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
     }
 
     /// &公有函数\\
@@ -82,46 +148,64 @@ impl Client {
     }
 
     /// 设置超时时间
-    pub fn set_connection_timeout(&self, secs: u64) -> Result<()> {
-        // Set write timeout
-        if self
-            .conn
-            .set_write_timeout(Some(Duration::from_secs(secs)))
-            .is_err()
-        {
-            return Err(anyhow!("could not set write timeout"));
-        }
+    pub fn set_connection_timeout(&mut self, secs: u64) {
+        self.timeout = Duration::from_secs(secs);
+    }
 
-        // Set read timeout
-        if self
-            .conn
-            .set_read_timeout(Some(Duration::from_secs(secs)))
-            .is_err()
-        {
-            return Err(anyhow!("could not set read timeout"));
-        }
+    /// 完成握手
+    pub async fn handshake_with_peer(&mut self) -> Result<()> {
+        self.send_handshake().await?;
+        self.read_handshake().await?;
+        self.send_extended_handshake().await
+    }
 
-        Ok(())
+    /// 完成对方发起的握手,先读取对方的握手再回复
+    pub async fn handshake_from_peer(&mut self) -> Result<()> {
+        self.read_handshake().await?;
+        self.send_handshake().await?;
+        self.send_extended_handshake().await
     }
 
-    /// 完成握手
-    pub fn handshake_with_peer(&mut self) -> Result<()> {
+    /// 发送握手
+    /// 握手没有长度前缀,在读写任何消息之前直接通过连接收发
+    async fn send_handshake(&mut self) -> Result<()> {
         // 创建握手内容
         let peer_id = self.peer_id.clone();
         let info_hash = self.info_hash.clone();
-        let handshake = Handshake::new(peer_id, info_hash);
+        let mut handshake = Handshake::new(peer_id, info_hash);
+        handshake.set_extension_protocol();
 
         // 发送序列化信息
         let handshake_encoded: Vec<u8> = handshake.serialize()?;
-        if self.conn.write(&handshake_encoded).is_err() {
+        let conn = self.conn.get_mut();
+        if !matches!(
+            timeout(
+                self.timeout,
+                io::AsyncWriteExt::write_all(conn, &handshake_encoded)
+            )
+            .await,
+            Ok(Ok(_))
+        ) {
             return Err(anyhow!("could not send handshake to peer"));
         }
+        Ok(())
+    }
 
+    /// 读取对方的握手
+    async fn read_handshake(&mut self) -> Result<()> {
         // 读取收到的序列化信息
-        let handshake_len: usize = self.read_handshake_len()?;
+        let handshake_len: usize = self.read_handshake_len().await?;
         //读取序列化信息长度
         let mut handshake_buf: Vec<u8> = vec![0; 48 + handshake_len];
-        if self.conn.read_exact(&mut handshake_buf).is_err() {
+        let conn = self.conn.get_mut();
+        if !matches!(
+            timeout(
+                self.timeout,
+                io::AsyncReadExt::read_exact(conn, &mut handshake_buf)
+            )
+            .await,
+            Ok(Ok(_))
+        ) {
             return Err(anyhow!("could not read handshake received from peer"));
         }
 
@@ -130,13 +214,67 @@ impl Client {
         if handshake_decoded.info_hash != self.info_hash {
             return Err(anyhow!("invalid handshake received from peer"));
         }
+        self.peer_extension_protocol = handshake_decoded.supports_extension_protocol();
+        Ok(())
+    }
+
+    /// 双方都支持扩展协议时发送扩展握手
+    async fn send_extended_handshake(&mut self) -> Result<()> {
+        if self.peer_extension_protocol {
+            let payload = self.extensions.handshake();
+            self.send_extended(EXTENDED_HANDSHAKE_ID, &payload).await?;
+        }
+        Ok(())
+    }
+
+    /// 注册扩展,需要在握手之前调用
+    pub fn register_extension(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.register(extension)
+    }
+
+    /// 对方是否支持扩展协议
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.peer_extension_protocol
+    }
+
+    /// 对方是否支持某个扩展
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.peer_id(name).is_some()
+    }
+
+    /// 读取扩展消息,交给对应的扩展处理并发送扩展的回复
+    pub async fn read_extended(&mut self, message: Message) -> Result<()> {
+        info!("Receive MESSAGE_EXTENDED from peer {:?}", self.peer.id);
+        if message.id != MESSAGE_EXTENDED {
+            return Err(anyhow!("received invalid MESSAGE_EXTENDED from peer"));
+        }
+        for (id, payload) in self.extensions.handle(&message.payload)? {
+            self.send_extended(id, &payload).await?;
+        }
+        Ok(())
+    }
+
+    /// 发送扩展消息,第一个字节是扩展消息的id,0表示扩展握手
+    pub async fn send_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        let mut extended = vec![id];
+        extended.extend_from_slice(payload);
+        let message: Message = Message::new_with_payload(MESSAGE_EXTENDED, extended);
+
+        info!("Send MESSAGE_EXTENDED {:?} to peer {:?}", id, self.peer.id);
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_EXTENDED to peer"));
+        }
         Ok(())
     }
 
     /// 读取握手返回的内容长度
-    fn read_handshake_len(&mut self) -> Result<usize> {
+    async fn read_handshake_len(&mut self) -> Result<usize> {
         let mut buf = [0; 1];
-        if self.conn.read_exact(&mut buf).is_err() {
+        let conn = self.conn.get_mut();
+        if !matches!(
+            timeout(self.timeout, io::AsyncReadExt::read_exact(conn, &mut buf)).await,
+            Ok(Ok(_))
+        ) {
             return Err(anyhow!(
                 "could not read handshake length received from peer"
             ));
@@ -151,90 +289,126 @@ impl Client {
     }
 
     /// 读取bitfield内容
-    pub fn read_message(&mut self) -> Result<Message> {
-        let message_len: usize = self.read_message_len()?;
+    pub async fn read_message(&mut self) -> Result<Message> {
+        match self.read_next_message().await? {
+            Some(message) => Ok(message),
+            None => Err(anyhow!("keep-alive")),
+        }
+    }
+
+    /// 读取下一条消息,收到 keep-alive 时返回 None
+    /// 可以在 tokio::time::timeout 中调用,超时不会丢失数据
+    pub async fn read_next_message(&mut self) -> Result<Option<Message>> {
+        let message = match timeout(self.timeout, self.conn.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => return Err(anyhow!("peer closed the connection")),
+            Err(_) => return Err(anyhow!("timed out reading message from peer")),
+        };
         //如果未收到消息，即长度为0，保持连接
-        if message_len == 0 {
+        if message.is_none() {
             info!("Receive KEEP_ALIVE from peer {:?}", self.peer.id);
-            return Err(anyhow!("keep-alive"));
-        }
-        let mut message_buf: Vec<u8> = vec![0; message_len];
-        if self.conn.read_exact(&mut message_buf).is_err() {
-            return Err(anyhow!("could not read message received from peer"));
         }
-        // 反序列化
-        let message: Message = deserialize_message(&message_buf, message_len)?;
         Ok(message)
     }
 
-    /// 获取消息长度
-    fn read_message_len(&mut self) -> Result<usize> {
-        let mut buf = vec![0; 4];
-        if self.conn.read_exact(&mut buf).is_err() {
-            return Err(anyhow!("could not read message length received from peer"));
+    /// 发送消息
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        match timeout(self.timeout, self.conn.send(message)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out sending message to peer")),
         }
-
-        let mut cursor = Cursor::new(buf);
-        let message_len = cursor.read_u32::<BigEndian>()?;
-
-        Ok(message_len as usize)
     }
 
     /// Read CHOKE message from remote peer.
     pub fn read_choke(&mut self) {
         info!("Receive MESSAGE_CHOKE from peer {:?}", self.peer.id);
-        self.choked = true
+        self.peer_choking = true
     }
 
     /// 发送 解除阻塞的消息
-    pub fn send_unchoke(&mut self) -> Result<()> {
+    pub async fn send_unchoke(&mut self) -> Result<()> {
         let message: Message = Message::new(MESSAGE_UNCHOKE);
-        let message_encoded = message.serialize()?;
         info!("Send MESSAGE_UNCHOKE to peer {:?}", self.peer.id);
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.send_message(message).await.is_err() {
             return Err(anyhow!("could not send MESSAGE_UNCHOKE to peer"));
         }
+        self.am_choking = false;
+        Ok(())
+    }
 
+    /// 发送阻塞消息,不再响应对方的请求
+    pub async fn send_choke(&mut self) -> Result<()> {
+        let message: Message = Message::new(MESSAGE_CHOKE);
+        info!("Send MESSAGE_CHOKE to peer {:?}", self.peer.id);
+
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_CHOKE to peer"));
+        }
+        self.am_choking = true;
         Ok(())
     }
 
     /// 设置本机未阻塞
     pub fn read_unchoke(&mut self) {
         info!("Receive MESSAGE_UNCHOKE from peer {:?}", self.peer.id);
-        self.choked = false
+        self.peer_choking = false
+    }
+
+    /// 对方对本机的piece感兴趣
+    pub fn read_interested(&mut self) {
+        info!("Receive MESSAGE_INTERESTED from peer {:?}", self.peer.id);
+        self.peer_interested = true
+    }
+
+    /// 对方不再对本机的piece感兴趣
+    pub fn read_not_interested(&mut self) {
+        info!("Receive MESSAGE_NOTINTERSETED from peer {:?}", self.peer.id);
+        self.peer_interested = false
     }
 
     /// 发送有兴趣下载消息
-    pub fn send_interested(&mut self) -> Result<()> {
+    pub async fn send_interested(&mut self) -> Result<()> {
         let message: Message = Message::new(MESSAGE_INTERESTED);
-        let message_encoded = message.serialize()?;
 
         info!("Send MESSAGE_INTERESTED to peer {:?}", self.peer.id);
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.send_message(message).await.is_err() {
             return Err(anyhow!("could not send MESSAGE_INTERESTED to peer"));
         }
+        self.am_interested = true;
+        Ok(())
+    }
+
+    /// 发送不再感兴趣的消息
+    pub async fn send_not_interested(&mut self) -> Result<()> {
+        let message: Message = Message::new(MESSAGE_NOTINTERSETED);
+
+        info!("Send MESSAGE_NOTINTERSETED to peer {:?}", self.peer.id);
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_NOTINTERSETED to peer"));
+        }
+        self.am_interested = false;
         Ok(())
     }
 
     /// 发送已经下载某个piece的消息
-    pub fn send_have(&mut self, index: u32) -> Result<()> {
+    pub async fn send_have(&mut self, index: u32) -> Result<()> {
         let mut payload: Vec<u8> = vec![];
         payload.write_u32::<BigEndian>(index)?;
 
         let message: Message = Message::new_with_payload(MESSAGE_HAVE, payload);
-        let message_encoded = message.serialize()?;
 
         info!("Send MESSAGE_HAVE to peer {:?}", self.peer.id);
-        if self.conn.write(&message_encoded).is_err() {
+        if self.send_message(message).await.is_err() {
             return Err(anyhow!("could not send MESSAGE_HAVE to peer"));
         }
 
         Ok(())
     }
 
-    pub fn read_have(&mut self, message: Message) -> Result<()> {
+    /// 读取 HAVE 消息,返回对方新拥有的piece
+    pub fn read_have(&mut self, message: Message) -> Result<Option<u32>> {
         info!("Receive MESSAGE_HAVE from peer {:?}", self.peer.id);
         // 检查消息合法性
         if message.id != MESSAGE_HAVE || message.payload.to_vec().len() != 4 {
@@ -244,32 +418,106 @@ impl Client {
         let mut payload_cursor = Cursor::new(message.payload.to_vec());
         let index = payload_cursor.read_u32::<BigEndian>()?;
         // 更新索引
+        if self.has_piece(index) {
+            return Ok(None);
+        }
         self.set_piece(index);
-        Ok(())
+        Ok(Some(index).filter(|index| self.has_piece(*index)))
     }
 
-    /// 读取bitfield消息
-    pub fn read_bitfield(&mut self) -> Result<()> {
-        info!("Receive MESSAGE_BITFIELD from peer {:?}", self.peer.id);
-        let message: Message = self.read_message()?;
-        //只接收对下载piece编码的消息
+    /// 读取bitfield消息,之前收到的扩展握手交给扩展处理
+    /// 对方没有任何piece时可以不发送bitfield,这时返回收到的第一条其它消息
+    pub async fn read_bitfield(&mut self, nb_pieces: usize) -> Result<Option<Message>> {
+        let message: Message = loop {
+            match self.read_next_message().await? {
+                Some(message) if message.id == MESSAGE_EXTENDED => {
+                    self.read_extended(message).await?
+                }
+                Some(message) => break message,
+                None => continue,
+            }
+        };
         if message.id != MESSAGE_BITFIELD {
-            return Err(anyhow!("received invalid MESSAGE_BITFIELD from peer"));
+            info!("Peer {:?} did not send MESSAGE_BITFIELD", self.peer.id);
+            self.bitfield = vec![0; nb_pieces.div_ceil(8)];
+            return Ok(Some(message));
         }
 
         // 更新内容
+        info!("Receive MESSAGE_BITFIELD from peer {:?}", self.peer.id);
         self.bitfield = message.payload.to_vec();
+        Ok(None)
+    }
+
+    /// 发送本机已经拥有的piece
+    pub async fn send_bitfield(&mut self, bitfield: &[u8]) -> Result<()> {
+        let message: Message = Message::new_with_payload(MESSAGE_BITFIELD, bitfield.to_vec());
+
+        info!("Send MESSAGE_BITFIELD to peer {:?}", self.peer.id);
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_BITFIELD to peer"));
+        }
+        Ok(())
+    }
+
+    /// 读取 REQUEST 或者 CANCEL 消息中的块
+    pub fn read_block_request(&mut self, message: Message) -> Result<BlockRequest> {
+        // 检查消息合法性
+        if (message.id != MESSAGE_REQUEST && message.id != MESSAGE_CANCEL)
+            || message.payload.len() != 12
+        {
+            return Err(anyhow!("received invalid MESSAGE_REQUEST from peer"));
+        }
+        let mut payload_cursor = Cursor::new(&message.payload);
+        let request = BlockRequest {
+            index: payload_cursor.read_u32::<BigEndian>()?,
+            begin: payload_cursor.read_u32::<BigEndian>()?,
+            length: payload_cursor.read_u32::<BigEndian>()?,
+        };
+        info!(
+            "Receive {} for piece {:?} [{:?}:{:?}] from peer {:?}",
+            if message.id == MESSAGE_REQUEST {
+                "MESSAGE_REQUEST"
+            } else {
+                "MESSAGE_CANCEL"
+            },
+            request.index,
+            request.begin,
+            request.begin as u64 + request.length as u64,
+            self.peer.id
+        );
+        Ok(request)
+    }
+
+    /// 发送对方请求的块
+    pub async fn send_piece(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()> {
+        let mut payload: Vec<u8> = vec![];
+        payload.write_u32::<BigEndian>(index)?;
+        payload.write_u32::<BigEndian>(begin)?;
+        payload.extend_from_slice(block);
+
+        let message: Message = Message::new_with_payload(MESSAGE_PIECE, payload);
+
+        info!(
+            "Send MESSAGE_PIECE {:?} [{:?}:{:?}] to peer {:?}",
+            index,
+            begin,
+            begin as usize + block.len(),
+            self.peer.id
+        );
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_PIECE to peer"));
+        }
         Ok(())
     }
 
-    pub fn send_request(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
+    pub async fn send_request(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
         let mut payload: Vec<u8> = vec![];
         payload.write_u32::<BigEndian>(index)?;
         payload.write_u32::<BigEndian>(begin)?;
         payload.write_u32::<BigEndian>(length)?;
 
         let message: Message = Message::new_with_payload(MESSAGE_REQUEST, payload);
-        let message_encoded = message.serialize()?;
 
         info!(
             "Send MESSAGE_REQUEST for piece {:?} [{:?}:{:?}] to peer {:?}",
@@ -279,55 +527,51 @@ impl Client {
             self.peer.id
         );
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.send_message(message).await.is_err() {
             return Err(anyhow!("could not send MESSAGE_REQUEST to peer"));
         }
         Ok(())
     }
 
-    pub fn read_piece(&mut self, message: Message, piece_work: &mut PieceWork) -> Result<()> {
-        info!("Receive MESSAGE_PIECE from peer {:?}", self.peer.id);
+    /// 取消已经发送的请求
+    pub async fn send_cancel(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
+        let mut payload: Vec<u8> = vec![];
+        payload.write_u32::<BigEndian>(index)?;
+        payload.write_u32::<BigEndian>(begin)?;
+        payload.write_u32::<BigEndian>(length)?;
 
-        // 检查消息合法性
-        if message.id != MESSAGE_PIECE || message.payload.to_vec().len() < 8 {
-            return Err(anyhow!("received invalid MESSAGE_HAVE from peer"));
-        }
+        let message: Message = Message::new_with_payload(MESSAGE_CANCEL, payload);
 
-        let payload: Vec<u8> = message.payload.to_vec();
+        info!(
+            "Send MESSAGE_CANCEL for piece {:?} [{:?}:{:?}] to peer {:?}",
+            index,
+            begin,
+            begin + length,
+            self.peer.id
+        );
 
-        let mut payload_cursor = Cursor::new(&payload[0..4]);
-        let index = payload_cursor.read_u32::<BigEndian>()?;
+        if self.send_message(message).await.is_err() {
+            return Err(anyhow!("could not send MESSAGE_CANCEL to peer"));
+        }
+        Ok(())
+    }
 
+    pub fn read_piece(&mut self, message: Message, piece_work: &mut PieceWork) -> Result<()> {
+        let (index, begin, block) = self.read_block(message)?;
         if index != piece_work.index {
             return Err(anyhow!("received invalid piece from peer"));
         }
-
-        // 获得这个piece的index
-        let mut payload_cursor = Cursor::new(&payload[4..8]);
-        let begin: u32 = payload_cursor.read_u32::<BigEndian>()?; //piece的开始位置
-
-        let block: Vec<u8> = payload[8..].to_vec();
         let block_len: u32 = block.len() as u32; //长度
 
         // Check if byte offset is valid
-        if begin + block_len > piece_work.length as u32 {
+        if begin as u64 + block_len as u64 > piece_work.length as u64 {
             return Err(anyhow!(
                 "received invalid byte offset within piece from peer"
             ));
         }
 
-        info!(
-            "Download piece {:?} [{:?}:{:?}] from peer {:?}",
-            index,
-            begin,
-            begin + block_len,
-            self.peer.id
-        );
-
         // 将收到的内容保存
-        for i in 0..block_len {
-            piece_work.data[begin as usize + i as usize] = block[i as usize];
-        }
+        piece_work.data[begin as usize..(begin + block_len) as usize].copy_from_slice(&block);
 
         // 更新已经下载的数量
         piece_work.downloaded += block_len;
@@ -337,4 +581,28 @@ impl Client {
 
         Ok(())
     }
+
+    /// 解析 PIECE 消息,返回 piece 索引、块的开始位置和块的内容
+    pub fn read_block(&mut self, message: Message) -> Result<(u32, u32, Vec<u8>)> {
+        info!("Receive MESSAGE_PIECE from peer {:?}", self.peer.id);
+
+        // 检查消息合法性
+        if message.id != MESSAGE_PIECE || message.payload.len() < 8 {
+            return Err(anyhow!("received invalid MESSAGE_PIECE from peer"));
+        }
+
+        let mut payload_cursor = Cursor::new(&message.payload[0..8]);
+        let index = payload_cursor.read_u32::<BigEndian>()?;
+        let begin: u32 = payload_cursor.read_u32::<BigEndian>()?; //piece的开始位置
+        let block: Vec<u8> = message.payload[8..].to_vec();
+
+        info!(
+            "Download piece {:?} [{:?}:{:?}] from peer {:?}",
+            index,
+            begin,
+            begin as u64 + block.len() as u64,
+            self.peer.id
+        );
+        Ok((index, begin, block))
+    }
 }
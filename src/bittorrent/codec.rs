@@ -0,0 +1,89 @@
+use crate::bittorrent::message::*;
+
+use anyhow::{anyhow, Error};
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+// 消息长度前缀的大小
+const LENGTH_PREFIX_SIZE: usize = 4;
+// 消息的最大长度,超过时断开连接
+const MESSAGE_LENGTH_MAX: usize = 1 << 21;
+
+/// 握手之后的消息格式:4字节的长度,1字节的类型,然后是 payload
+/// 解码得到 None 表示 keep-alive
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Option<Message>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let mut length = [0; LENGTH_PREFIX_SIZE];
+        length.copy_from_slice(&src[..LENGTH_PREFIX_SIZE]);
+        let message_len = u32::from_be_bytes(length) as usize;
+        if message_len > MESSAGE_LENGTH_MAX {
+            return Err(anyhow!("received too long message from peer"));
+        }
+
+        // 等待完整的消息
+        if src.len() < LENGTH_PREFIX_SIZE + message_len {
+            src.reserve(LENGTH_PREFIX_SIZE + message_len - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_PREFIX_SIZE);
+        //如果未收到消息，即长度为0，保持连接
+        if message_len == 0 {
+            return Ok(Some(None));
+        }
+        let message_buf = src.split_to(message_len).to_vec();
+        let message = deserialize_message(&message_buf, message_len)?;
+        Ok(Some(Some(message)))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&message.serialize()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod codec_test {
+    use super::*;
+
+    #[test]
+    fn test_decode_partial_messages() {
+        let mut codec = MessageCodec;
+        let mut buf = BytesMut::new();
+        let message = Message::new_with_payload(MESSAGE_HAVE, vec![0, 0, 0, 3]);
+        codec.encode(message.clone(), &mut buf).unwrap();
+        // keep-alive
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        codec
+            .encode(Message::new(MESSAGE_UNCHOKE), &mut buf)
+            .unwrap();
+
+        // 不完整的消息等待更多数据
+        let mut partial = buf.split_to(6);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(Some(message)));
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(None));
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(Some(Message::new(MESSAGE_UNCHOKE)))
+        );
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        // 过长的消息
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff][..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::bittorrent::worker::Worker;
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+// 同时连接的peer数量,包括其它peer发起的连接
+pub const NB_CONNECTIONS_MAX: usize = 80;
+
+/// 管理和peer的连接,所有连接都是 tokio 运行时中的任务,由少数几个线程处理
+/// 连接数量达到上限时,新的peer排队等待其它连接关闭
+#[derive(Clone)]
+pub struct ConnectionManager {
+    // 每个连接占用一个许可
+    permits: Arc<Semaphore>,
+    // 连接数量的上限
+    max: usize,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager::new(NB_CONNECTIONS_MAX)
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(max: usize) -> Self {
+        ConnectionManager {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+        }
+    }
+
+    /// 排队连接worker的peer,连接关闭后空出的位置交给下一个排队的peer
+    pub fn connect(&self, worker: Worker) -> JoinHandle<()> {
+        let permits = self.permits.clone();
+        tokio::spawn(async move {
+            // 停止后排队的peer不再连接
+            let _permit = match permits.acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            worker.start_download().await;
+        })
+    }
+
+    /// 接受对方发起的连接,连接数量已满时返回 None
+    /// 连接关闭时丢弃返回的许可即可
+    pub fn try_accept(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    /// 正在使用的连接数量
+    pub fn connections(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// 停止建立新的连接
+    pub fn close(&self) {
+        self.permits.close();
+    }
+}
+
+#[cfg(test)]
+mod connection_test {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let manager = ConnectionManager::new(2);
+        let first = manager.try_accept().unwrap();
+        let _second = manager.try_accept().unwrap();
+        assert_eq!(manager.connections(), 2);
+        assert!(manager.try_accept().is_none());
+
+        // 连接关闭后可以接受新的连接
+        drop(first);
+        assert_eq!(manager.connections(), 1);
+        assert!(manager.try_accept().is_some());
+
+        manager.close();
+        assert!(manager.try_accept().is_none());
+    }
+}
@@ -0,0 +1,725 @@
+use crate::bittorrent::bencode::{decode, Dict, Value};
+use crate::bittorrent::piece::sha1;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// 节点id和info hash的长度
+const ID_SIZE: usize = 20;
+// 紧凑格式的节点信息长度: id + ip + 端口
+const NODE_SIZE: usize = 26;
+// 紧凑格式的peer信息长度: ip + 端口
+const PEER_SIZE: usize = 6;
+// token的长度
+const TOKEN_SIZE: usize = 8;
+// 每个桶保存的节点数量
+const K: usize = 8;
+// 查找时同时等待回复的节点数量
+const ALPHA: usize = 3;
+// 单个请求的超时时间
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// 一次查找的最长时间
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+// 每个info hash保存的peer数量
+const PEERS_MAX: usize = 100;
+// KRPC 错误码
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+// 默认的引导节点
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// DHT 的配置
+#[derive(Debug, Clone, Default)]
+pub struct DhtConfig {
+    // 是否启用DHT
+    pub enabled: bool,
+    // 引导节点,格式为 host:port
+    pub bootstrap: Vec<String>,
+    // 保存路由表的文件
+    pub state_path: Option<PathBuf>,
+}
+
+/// 默认保存路由表的文件
+pub fn default_state_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(".rust-downloader-dht")
+}
+
+/// DHT 中的节点
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub id: Vec<u8>,
+    pub addr: SocketAddrV4,
+}
+
+/// 两个id的异或距离
+fn distance(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+fn random_id() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    (0..ID_SIZE).map(|_| rng.gen()).collect()
+}
+
+fn encode_addr(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut buf = addr.ip().octets().to_vec();
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+fn decode_addr(buf: &[u8]) -> Option<SocketAddrV4> {
+    if buf.len() != PEER_SIZE {
+        return None;
+    }
+    let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+    let port = u16::from_be_bytes([buf[4], buf[5]]);
+    if port == 0 {
+        return None;
+    }
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// 紧凑格式的节点信息
+pub fn encode_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut buf = vec![];
+    for node in nodes {
+        buf.extend_from_slice(&node.id);
+        buf.extend(encode_addr(&node.addr));
+    }
+    buf
+}
+
+pub fn decode_nodes(buf: &[u8]) -> Vec<Node> {
+    buf.chunks_exact(NODE_SIZE)
+        .filter_map(|chunk| {
+            decode_addr(&chunk[ID_SIZE..]).map(|addr| Node {
+                id: chunk[..ID_SIZE].to_vec(),
+                addr,
+            })
+        })
+        .collect()
+}
+
+/// Kademlia 路由表
+/// 第i个桶保存与本机id前i位相同、第i+1位不同的节点
+pub struct RoutingTable {
+    id: Vec<u8>,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: Vec<u8>) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![vec![]; ID_SIZE * 8],
+        }
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// 节点所在的桶,即与本机id异或后前导0的个数
+    fn bucket_index(&self, id: &[u8]) -> Option<usize> {
+        distance(&self.id, id)
+            .iter()
+            .enumerate()
+            .find(|(_, x)| **x != 0)
+            .map(|(i, x)| i * 8 + x.leading_zeros() as usize)
+    }
+
+    /// 添加或者刷新节点,最近活跃的节点放在桶的末尾
+    /// 桶已满时保留原来的节点,长时间在线的节点更可靠
+    pub fn insert(&mut self, node: Node) -> bool {
+        if node.id.len() != ID_SIZE {
+            return false;
+        }
+        let index = match self.bucket_index(&node.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(pos) = bucket
+            .iter()
+            .position(|n| n.id == node.id || n.addr == node.addr)
+        {
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            return false;
+        }
+        bucket.push(node);
+        true
+    }
+
+    /// 删除没有回复的节点
+    pub fn remove(&mut self, addr: &SocketAddrV4) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|node| node.addr != *addr);
+        }
+    }
+
+    /// 距离 target 最近的 count 个节点
+    pub fn closest(&self, target: &[u8], count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 读取保存的路由表,内容为 bencode 字典 {id, nodes}
+    pub fn load(path: &Path) -> Option<RoutingTable> {
+        let value = decode(&fs::read(path).ok()?).ok()?;
+        let id = value.get("id")?.as_bytes()?;
+        if id.len() != ID_SIZE {
+            return None;
+        }
+        let mut table = RoutingTable::new(id.to_vec());
+        for node in decode_nodes(value.get("nodes")?.as_bytes()?) {
+            table.insert(node);
+        }
+        Some(table)
+    }
+
+    /// 保存路由表,先写临时文件再重命名
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        let mut dict = BTreeMap::new();
+        dict.insert(b"id".to_vec(), Value::Bytes(self.id.clone()));
+        dict.insert(b"nodes".to_vec(), Value::Bytes(encode_nodes(&nodes)));
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(".tmp");
+        if fs::write(&tmp, Value::Dict(dict).encode()).is_err() || fs::rename(&tmp, path).is_err() {
+            return Err(anyhow!("could not save dht state {:?}", path));
+        }
+        Ok(())
+    }
+}
+
+/// 一次查找的结果
+struct Lookup {
+    // 回复过的节点和它们给出的token,按距离排序
+    nodes: Vec<(Node, Option<Vec<u8>>)>,
+    // get_peers 得到的peer
+    peers: Vec<SocketAddrV4>,
+}
+
+/// Mainline DHT 节点(BEP 5)
+pub struct Dht {
+    socket: UdpSocket,
+    table: RoutingTable,
+    // 其它节点通过 announce_peer 声明的peer
+    peers: HashMap<Vec<u8>, Vec<SocketAddrV4>>,
+    // 生成token的密钥
+    secret: Vec<u8>,
+    // 上一个请求的transaction id
+    transaction: u16,
+}
+
+impl Dht {
+    /// 监听UDP端口,端口被占用时使用随机端口
+    /// state 中保存有路由表时继续使用之前的id和节点
+    pub fn bind(port: u16, state: Option<&Path>) -> Result<Dht> {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(_) => match UdpSocket::bind(("0.0.0.0", 0)) {
+                Ok(socket) => socket,
+                Err(_) => return Err(anyhow!("could not bind dht socket")),
+            },
+        };
+        let table = state
+            .and_then(RoutingTable::load)
+            .unwrap_or_else(|| RoutingTable::new(random_id()));
+        Ok(Dht {
+            socket,
+            table,
+            peers: HashMap::new(),
+            secret: random_id(),
+            transaction: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// 通过引导节点加入网络,返回路由表中的节点数量
+    pub fn bootstrap(&mut self, nodes: &[String]) -> Result<usize> {
+        let seeds: Vec<SocketAddrV4> = nodes
+            .iter()
+            .filter_map(|node| node.to_socket_addrs().ok())
+            .flatten()
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .collect();
+        let id = self.table.id.clone();
+        self.lookup(&id, "find_node", &seeds);
+        if self.table.is_empty() {
+            return Err(anyhow!("could not bootstrap dht"));
+        }
+        Ok(self.table.len())
+    }
+
+    /// 检查节点是否在线,返回节点的id
+    pub fn ping(&mut self, addr: SocketAddrV4) -> Result<Vec<u8>> {
+        let t = self.send_query(addr, "ping", BTreeMap::new());
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        while let Some((from, message)) = self.receive(deadline) {
+            if from != addr || message.get("t").and_then(|t| t.as_bytes()) != Some(&t[..]) {
+                continue;
+            }
+            let id = message
+                .get("r")
+                .and_then(|r| r.get("id"))
+                .and_then(|id| id.as_bytes())
+                .filter(|id| id.len() == ID_SIZE)
+                .ok_or_else(|| anyhow!("invalid ping response from {}", addr))?
+                .to_vec();
+            self.table.insert(Node {
+                id: id.clone(),
+                addr,
+            });
+            return Ok(id);
+        }
+        self.table.remove(&addr);
+        Err(anyhow!("dht node {} did not respond", addr))
+    }
+
+    /// 查找距离 target 最近的节点
+    pub fn find_node(&mut self, target: &[u8]) -> Vec<Node> {
+        let lookup = self.lookup(target, "find_node", &[]);
+        lookup
+            .nodes
+            .into_iter()
+            .map(|(node, _)| node)
+            .take(K)
+            .collect()
+    }
+
+    /// 查找正在下载 info_hash 的peer
+    pub fn get_peers(&mut self, info_hash: &[u8]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, "get_peers", &[]).peers
+    }
+
+    /// 查找peer,并向最近的节点声明本机在 port 上下载 info_hash
+    pub fn announce_peer(&mut self, info_hash: &[u8], port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(info_hash, "get_peers", &[]);
+        let mut pending = HashSet::new();
+        for (node, token) in lookup.nodes.iter().take(K) {
+            if let Some(token) = token {
+                let mut args = BTreeMap::new();
+                args.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                args.insert(b"port".to_vec(), Value::Int(port as i64));
+                args.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                args.insert(b"implied_port".to_vec(), Value::Int(0));
+                pending.insert(self.send_query(node.addr, "announce_peer", args));
+            }
+        }
+        // 等待回复,期间继续响应其它节点
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        while !pending.is_empty() {
+            match self.receive(deadline) {
+                Some((_, message)) => {
+                    if let Some(t) = message.get("t").and_then(|t| t.as_bytes()) {
+                        pending.remove(t);
+                    }
+                }
+                None => break,
+            }
+        }
+        lookup.peers
+    }
+
+    /// 在 duration 内响应其它节点的请求
+    pub fn serve(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.receive(deadline);
+        }
+    }
+
+    /// 保存路由表
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.table.save(path)
+    }
+
+    /// 迭代查找:不断向距离 target 最近且还没有查询过的节点发送请求,
+    /// 直到最近的K个节点都已经回复或者超时
+    /// seeds 是还不知道id的节点,例如引导节点
+    fn lookup(&mut self, target: &[u8], method: &str, seeds: &[SocketAddrV4]) -> Lookup {
+        let key: &[u8] = if method == "get_peers" {
+            b"info_hash"
+        } else {
+            b"target"
+        };
+        let mut candidates = self.table.closest(target, K);
+        let mut queried: HashSet<SocketAddrV4> = HashSet::new();
+        let mut pending: HashMap<Vec<u8>, (SocketAddrV4, Instant)> = HashMap::new();
+        let mut result = Lookup {
+            nodes: vec![],
+            peers: vec![],
+        };
+        let deadline = Instant::now() + LOOKUP_TIMEOUT;
+
+        while Instant::now() < deadline {
+            // 向最近的还没有查询过的节点发送请求
+            candidates.sort_by_key(|node| distance(&node.id, target));
+            let next: Vec<SocketAddrV4> = seeds
+                .iter()
+                .chain(candidates.iter().take(K).map(|node| &node.addr))
+                .filter(|addr| !queried.contains(addr))
+                .take(ALPHA.saturating_sub(pending.len()))
+                .copied()
+                .collect();
+            for addr in next {
+                queried.insert(addr);
+                let mut args = BTreeMap::new();
+                args.insert(key.to_vec(), Value::Bytes(target.to_vec()));
+                let t = self.send_query(addr, method, args);
+                pending.insert(t, (addr, Instant::now() + QUERY_TIMEOUT));
+            }
+            if pending.is_empty() {
+                break;
+            }
+
+            // 超时的节点从路由表中删除
+            let now = Instant::now();
+            let expired: Vec<Vec<u8>> = pending
+                .iter()
+                .filter(|(_, (_, timeout))| *timeout <= now)
+                .map(|(t, _)| t.clone())
+                .collect();
+            for t in expired {
+                if let Some((addr, _)) = pending.remove(&t) {
+                    self.table.remove(&addr);
+                    candidates.retain(|node| node.addr != addr);
+                }
+            }
+            let timeout = match pending.values().map(|(_, timeout)| *timeout).min() {
+                Some(timeout) => timeout,
+                None => continue,
+            };
+
+            // 处理一个回复
+            let (addr, message) = match self.receive(timeout.min(deadline)) {
+                Some(received) => received,
+                None => continue,
+            };
+            let t = message
+                .get("t")
+                .and_then(|t| t.as_bytes())
+                .unwrap_or_default();
+            match pending.get(t) {
+                Some((expected, _)) if *expected == addr => pending.remove(t),
+                _ => continue,
+            };
+            let reply = match message.get("r") {
+                Some(reply) => reply,
+                None => {
+                    candidates.retain(|node| node.addr != addr);
+                    continue;
+                }
+            };
+            let id = match reply.get("id").and_then(|id| id.as_bytes()) {
+                Some(id) if id.len() == ID_SIZE => id.to_vec(),
+                _ => continue,
+            };
+            let node = Node { id, addr };
+            self.table.insert(node.clone());
+            if !candidates.iter().any(|c| c.addr == addr) {
+                candidates.push(node.clone());
+            }
+            if let Some(nodes) = reply.get("nodes").and_then(|n| n.as_bytes()) {
+                for found in decode_nodes(nodes) {
+                    if found.id != self.table.id && !candidates.iter().any(|c| c.addr == found.addr)
+                    {
+                        candidates.push(found);
+                    }
+                }
+            }
+            if let Some(values) = reply.get("values").and_then(|v| v.as_list()) {
+                for peer in values
+                    .iter()
+                    .filter_map(|v| v.as_bytes().and_then(decode_addr))
+                {
+                    if !result.peers.contains(&peer) {
+                        result.peers.push(peer);
+                    }
+                }
+            }
+            let token = reply
+                .get("token")
+                .and_then(|t| t.as_bytes())
+                .map(|t| t.to_vec());
+            result.nodes.push((node, token));
+        }
+        result
+            .nodes
+            .sort_by_key(|(node, _)| distance(&node.id, target));
+        result
+    }
+
+    /// 发送请求,返回请求的transaction id
+    fn send_query(&mut self, addr: SocketAddrV4, method: &str, mut args: Dict) -> Vec<u8> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let t = self.transaction.to_be_bytes().to_vec();
+        args.insert(b"id".to_vec(), Value::Bytes(self.table.id.clone()));
+        let mut message = BTreeMap::new();
+        message.insert(b"t".to_vec(), Value::Bytes(t.clone()));
+        message.insert(b"y".to_vec(), Value::Bytes(b"q".to_vec()));
+        message.insert(b"q".to_vec(), Value::Bytes(method.as_bytes().to_vec()));
+        message.insert(b"a".to_vec(), Value::Dict(args));
+        self.send(addr, message);
+        t
+    }
+
+    fn send(&self, addr: SocketAddrV4, message: Dict) {
+        if let Err(e) = self.socket.send_to(&Value::Dict(message).encode(), addr) {
+            info!("Could not send dht message to {}: {}", addr, e);
+        }
+    }
+
+    /// 接收一个回复或者错误,期间收到的请求直接响应
+    /// 到达 deadline 时返回None
+    fn receive(&mut self, deadline: Instant) -> Option<(SocketAddrV4, Value)> {
+        let mut buf = [0; 2048];
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() || self.socket.set_read_timeout(Some(timeout)).is_err() {
+                return None;
+            }
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok((len, SocketAddr::V4(addr))) => (len, addr),
+                Ok(_) => continue,
+                Err(_) => return None,
+            };
+            let message = match decode(&buf[..len]) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            match message.get("y").and_then(|y| y.as_bytes()) {
+                Some(b"q") => self.handle_query(addr, &message),
+                Some(b"r") | Some(b"e") => return Some((addr, message)),
+                _ => {}
+            }
+        }
+    }
+
+    /// 响应其它节点的请求
+    fn handle_query(&mut self, addr: SocketAddrV4, message: &Value) {
+        let t = message
+            .get("t")
+            .and_then(|t| t.as_bytes())
+            .unwrap_or_default()
+            .to_vec();
+        let mut reply = BTreeMap::new();
+        reply.insert(b"t".to_vec(), Value::Bytes(t));
+        match self.answer(addr, message) {
+            Ok(answer) => {
+                reply.insert(b"y".to_vec(), Value::Bytes(b"r".to_vec()));
+                reply.insert(b"r".to_vec(), Value::Dict(answer));
+            }
+            Err((code, error)) => {
+                reply.insert(b"y".to_vec(), Value::Bytes(b"e".to_vec()));
+                reply.insert(
+                    b"e".to_vec(),
+                    Value::List(vec![
+                        Value::Int(code),
+                        Value::Bytes(error.as_bytes().to_vec()),
+                    ]),
+                );
+            }
+        }
+        self.send(addr, reply);
+    }
+
+    /// 请求的回复内容,失败时返回错误码和错误信息
+    fn answer(&mut self, addr: SocketAddrV4, message: &Value) -> Result<Dict, (i64, &'static str)> {
+        let method = message
+            .get("q")
+            .and_then(|q| q.as_bytes())
+            .unwrap_or_default();
+        let args = message
+            .get("a")
+            .ok_or((ERROR_PROTOCOL, "missing arguments"))?;
+        let id = args
+            .get("id")
+            .and_then(|id| id.as_bytes())
+            .filter(|id| id.len() == ID_SIZE)
+            .ok_or((ERROR_PROTOCOL, "invalid id"))?;
+        let hash = |key: &str| {
+            args.get(key)
+                .and_then(|v| v.as_bytes())
+                .filter(|v| v.len() == ID_SIZE)
+                .ok_or((ERROR_PROTOCOL, "invalid target"))
+        };
+
+        let mut answer = BTreeMap::new();
+        answer.insert(b"id".to_vec(), Value::Bytes(self.table.id.clone()));
+        match method {
+            b"ping" => {}
+            b"find_node" => {
+                let nodes = self.table.closest(hash("target")?, K);
+                answer.insert(b"nodes".to_vec(), Value::Bytes(encode_nodes(&nodes)));
+            }
+            b"get_peers" => {
+                let info_hash = hash("info_hash")?;
+                answer.insert(b"token".to_vec(), Value::Bytes(self.token(addr.ip())));
+                match self.peers.get(info_hash) {
+                    Some(peers) => {
+                        let values = peers.iter().map(|p| Value::Bytes(encode_addr(p))).collect();
+                        answer.insert(b"values".to_vec(), Value::List(values));
+                    }
+                    None => {
+                        let nodes = self.table.closest(info_hash, K);
+                        answer.insert(b"nodes".to_vec(), Value::Bytes(encode_nodes(&nodes)));
+                    }
+                }
+            }
+            b"announce_peer" => {
+                let info_hash = hash("info_hash")?.to_vec();
+                let token = args.get("token").and_then(|t| t.as_bytes());
+                if token != Some(&self.token(addr.ip())[..]) {
+                    return Err((ERROR_PROTOCOL, "invalid token"));
+                }
+                // implied_port 为1时使用发送请求的端口
+                let port = match args.get("implied_port").and_then(|p| p.as_int()) {
+                    Some(1) => addr.port(),
+                    _ => args
+                        .get("port")
+                        .and_then(|p| p.as_int())
+                        .filter(|p| *p > 0 && *p <= u16::MAX as i64)
+                        .ok_or((ERROR_PROTOCOL, "invalid port"))? as u16,
+                };
+                let peer = SocketAddrV4::new(*addr.ip(), port);
+                let peers = self.peers.entry(info_hash).or_default();
+                if !peers.contains(&peer) {
+                    if peers.len() >= PEERS_MAX {
+                        peers.remove(0);
+                    }
+                    peers.push(peer);
+                }
+            }
+            _ => return Err((ERROR_METHOD_UNKNOWN, "method unknown")),
+        }
+        self.table.insert(Node {
+            id: id.to_vec(),
+            addr,
+        });
+        Ok(answer)
+    }
+
+    /// announce_peer 需要的token,由对方的ip和本机的密钥生成
+    fn token(&self, ip: &Ipv4Addr) -> Vec<u8> {
+        let mut data = self.secret.clone();
+        data.extend_from_slice(&ip.octets());
+        sha1(&data)[..TOKEN_SIZE].to_vec()
+    }
+}
+
+/// 通过DHT查找正在下载 info_hash 的peer,同时声明本机在 port 上下载
+pub fn find_peers(config: &DhtConfig, info_hash: &[u8], port: u16) -> Result<Vec<SocketAddrV4>> {
+    let mut dht = Dht::bind(port, config.state_path.as_deref())?;
+    let nb_nodes = dht.bootstrap(&config.bootstrap)?;
+    info!("Joined dht with {} nodes", nb_nodes);
+    let peers = dht.announce_peer(info_hash, port);
+    if let Some(path) = &config.state_path {
+        if let Err(e) = dht.save(path) {
+            info!("{}", e);
+        }
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod dht_test {
+    use super::*;
+    use std::thread;
+
+    fn local(dht: &Dht) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, dht.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let mut table = RoutingTable::new(vec![0; 20]);
+        let node = |first: u8, port: u16| {
+            let mut id = vec![0; 20];
+            id[0] = first;
+            id[19] = port as u8;
+            Node {
+                id,
+                addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            }
+        };
+        assert_eq!(table.bucket_index(&node(0x80, 1).id), Some(0));
+        assert_eq!(table.bucket_index(&node(0x01, 1).id), Some(7));
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+        // 同一个桶最多K个节点
+        for port in 1..=10 {
+            table.insert(node(0x80, port));
+        }
+        assert!(table.insert(node(0x01, 20)));
+        assert_eq!(table.len(), K + 1);
+        assert_eq!(table.closest(&[0; 20], 2)[0], node(0x01, 20));
+        table.remove(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 20));
+        assert_eq!(table.len(), K);
+
+        let path = std::env::temp_dir().join("dht_test_table");
+        table.save(&path).unwrap();
+        let loaded = RoutingTable::load(&path).unwrap();
+        assert_eq!(loaded.id(), table.id());
+        assert_eq!(loaded.closest(&[0; 20], K), table.closest(&[0; 20], K));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_local_network() {
+        let mut router = Dht::bind(0, None).unwrap();
+        let router_addr = local(&router);
+        thread::spawn(move || router.serve(Duration::from_secs(20)));
+        let bootstrap = vec![router_addr.to_string()];
+
+        // 做种的节点声明自己的端口
+        let mut seeder = Dht::bind(0, None).unwrap();
+        assert_eq!(seeder.bootstrap(&bootstrap).unwrap(), 1);
+        assert_eq!(seeder.ping(router_addr).unwrap().len(), ID_SIZE);
+        let info_hash = vec![7; 20];
+        assert!(seeder.announce_peer(&info_hash, 7001).is_empty());
+        thread::spawn(move || seeder.serve(Duration::from_secs(20)));
+
+        // 其它节点可以通过DHT找到它
+        let mut leecher = Dht::bind(0, None).unwrap();
+        assert!(leecher.bootstrap(&bootstrap).unwrap() >= 1);
+        assert_eq!(
+            leecher.get_peers(&info_hash),
+            vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7001)]
+        );
+        assert_eq!(leecher.find_node(&[0; 20]).len(), 2);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::bittorrent::bencode::{decode, Dict, Value};
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+// 扩展握手的消息id
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// 扩展握手中的客户端名称
+const CLIENT_VERSION: &str = "rust-downloader 0.1";
+
+/// 扩展协议(BEP 10)上的扩展,例如 ut_metadata、ut_pex
+/// 返回的消息会使用对方为这个扩展分配的id发送
+pub trait Extension: Send {
+    /// 扩展名称,写在扩展握手的 m 字典中
+    fn name(&self) -> &'static str;
+
+    /// 在本机的扩展握手中添加字段,例如 metadata_size
+    fn handshake(&self, _handshake: &mut Dict) {}
+
+    /// 收到对方的扩展握手,只有对方也支持这个扩展时才会调用
+    fn on_handshake(&mut self, _handshake: &Value) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+
+    /// 收到发给这个扩展的消息
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+/// 一个连接上注册的扩展
+#[derive(Default)]
+pub struct Extensions {
+    // 本机的扩展,扩展的id为下标加1
+    extensions: Vec<Box<dyn Extension>>,
+    // 对方为每个扩展分配的id
+    peer_ids: BTreeMap<String, u8>,
+}
+
+impl Extensions {
+    /// 注册扩展,返回本机为它分配的id
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// 本机的扩展握手
+    pub fn handshake(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        let mut handshake = BTreeMap::new();
+        for (index, extension) in self.extensions.iter().enumerate() {
+            m.insert(
+                extension.name().as_bytes().to_vec(),
+                Value::Int(index as i64 + 1),
+            );
+            extension.handshake(&mut handshake);
+        }
+        handshake.insert(b"m".to_vec(), Value::Dict(m));
+        handshake.insert(
+            b"v".to_vec(),
+            Value::Bytes(CLIENT_VERSION.as_bytes().to_vec()),
+        );
+        Value::Dict(handshake).encode()
+    }
+
+    /// 对方为某个扩展分配的id,对方不支持时返回None
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer_ids.get(name).copied()
+    }
+
+    /// 处理扩展消息,返回需要发送给对方的 (id, 内容)
+    pub fn handle(&mut self, payload: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+        let (id, payload) = match payload.split_first() {
+            Some((id, payload)) => (*id, payload),
+            None => return Err(anyhow!("received empty MESSAGE_EXTENDED from peer")),
+        };
+        let mut replies = vec![];
+        if id == EXTENDED_HANDSHAKE_ID {
+            let handshake = decode(payload)?;
+            // id为0表示对方关闭了这个扩展
+            if let Some(m) = handshake.get("m").and_then(|m| m.as_dict()) {
+                for (name, peer_id) in m {
+                    let name = String::from_utf8_lossy(name).to_string();
+                    match peer_id.as_int() {
+                        Some(peer_id) if peer_id > 0 && peer_id < 256 => {
+                            self.peer_ids.insert(name, peer_id as u8);
+                        }
+                        _ => {
+                            self.peer_ids.remove(&name);
+                        }
+                    }
+                }
+            }
+            for extension in self.extensions.iter_mut() {
+                if let Some(peer_id) = self.peer_ids.get(extension.name()) {
+                    for reply in extension.on_handshake(&handshake)? {
+                        replies.push((*peer_id, reply));
+                    }
+                }
+            }
+        } else {
+            let extension = match self.extensions.get_mut(id as usize - 1) {
+                Some(extension) => extension,
+                None => return Err(anyhow!("received unknown extended message {:?}", id)),
+            };
+            let replies_payload = extension.on_message(payload)?;
+            if let Some(peer_id) = self.peer_ids.get(extension.name()) {
+                for reply in replies_payload {
+                    replies.push((*peer_id, reply));
+                }
+            }
+        }
+        Ok(replies)
+    }
+}
+
+#[cfg(test)]
+mod extension_test {
+    use super::*;
+
+    /// 原样返回收到的消息
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn handshake(&self, handshake: &mut Dict) {
+            handshake.insert(b"echo_size".to_vec(), Value::Int(3));
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut extensions = Extensions::default();
+        assert_eq!(extensions.register(Box::new(Echo)), 1);
+        assert_eq!(
+            extensions.handshake(),
+            b"d9:echo_sizei3e1:md4:echoi1ee1:v19:rust-downloader 0.1e".to_vec()
+        );
+
+        // 对方把 echo 分配为 7
+        let mut handshake = vec![EXTENDED_HANDSHAKE_ID];
+        handshake.extend_from_slice(b"d1:md4:echoi7e6:ut_pexi0eee");
+        assert!(extensions.handle(&handshake).unwrap().is_empty());
+        assert_eq!(extensions.peer_id("echo"), Some(7));
+        assert_eq!(extensions.peer_id("ut_pex"), None);
+        assert_eq!(
+            extensions.handle(&[1, b'h', b'i']).unwrap(),
+            vec![(7, b"hi".to_vec())]
+        );
+        assert!(extensions.handle(&[2]).is_err());
+        assert!(extensions.handle(&[]).is_err());
+    }
+}
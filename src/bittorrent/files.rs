@@ -0,0 +1,208 @@
+use anyhow::{anyhow, Result};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// torrent中的一个文件
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TorrentFile {
+    // 相对于保存目录的路径,已经过滤了不安全的部分
+    pub path: PathBuf,
+    // 文件长度
+    pub length: u64,
+    // 可选的md5校验值
+    pub md5sum: Option<String>,
+    // 文件在所有文件首尾相接得到的数据中的起始位置
+    pub offset: u64,
+}
+
+/// 一段数据落在某个文件中的部分
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSlice {
+    // 文件索引
+    pub file: usize,
+    // 在文件中的起始位置
+    pub file_offset: u64,
+    // 在这段数据中的起始位置
+    pub offset: u64,
+    // 长度
+    pub length: u64,
+}
+
+impl TorrentFile {
+    pub fn new(path: PathBuf, length: u64, md5sum: Option<String>) -> Self {
+        TorrentFile {
+            path,
+            length,
+            md5sum,
+            offset: 0,
+        }
+    }
+}
+
+/// 依次计算每个文件的起始位置,返回总长度
+pub fn layout(files: &mut [TorrentFile]) -> u64 {
+    let mut offset = 0;
+    for file in files.iter_mut() {
+        file.offset = offset;
+        offset += file.length;
+    }
+    offset
+}
+
+/// 过滤路径中的一段,防止写到保存目录之外
+/// 路径分隔符和控制字符替换为 _,"." 和 ".." 也替换为 _
+pub fn sanitize_component(component: &str) -> String {
+    let component: String = component
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match component.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => component,
+    }
+}
+
+/// 由torrent中的路径列表生成相对路径
+pub fn build_path(components: &[String]) -> Result<PathBuf> {
+    if components.is_empty() {
+        return Err(anyhow!("torrent contains a file with an empty path"));
+    }
+    Ok(components
+        .iter()
+        .map(|component| sanitize_component(component))
+        .collect())
+}
+
+/// 计算数据 [begin, begin+length) 落在哪些文件中,跳过长度为0的文件
+pub fn map_range(files: &[TorrentFile], begin: u64, length: u64) -> Vec<FileSlice> {
+    let end = begin + length;
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| file.length > 0)
+        .filter(|(_, file)| file.offset < end && file.offset + file.length > begin)
+        .map(|(index, file)| {
+            let start = begin.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            FileSlice {
+                file: index,
+                file_offset: start - file.offset,
+                offset: start - begin,
+                length: stop - start,
+            }
+        })
+        .collect()
+}
+
+/// 文件的数据所在的piece,长度为0的文件返回 None
+pub fn piece_range(file: &TorrentFile, piece_length: u32) -> Option<RangeInclusive<u32>> {
+    if file.length == 0 {
+        return None;
+    }
+    let first = file.offset / piece_length as u64;
+    let last = (file.offset + file.length - 1) / piece_length as u64;
+    Some(first as u32..=last as u32)
+}
+
+/// 生成文件在本地的完整路径
+/// 单文件时 output 就是保存的文件名,为空时使用torrent中的名称
+/// 多文件时保存在 output/名称 目录下
+pub fn local_paths(
+    files: &[TorrentFile],
+    name: &str,
+    multi_file: bool,
+    output: &str,
+) -> Vec<PathBuf> {
+    if !multi_file {
+        let path = if output.is_empty() {
+            PathBuf::from(sanitize_component(name))
+        } else {
+            PathBuf::from(output)
+        };
+        return vec![path];
+    }
+    let root = Path::new(output).join(sanitize_component(name));
+    files.iter().map(|file| root.join(&file.path)).collect()
+}
+
+#[cfg(test)]
+mod files_test {
+    use super::*;
+
+    fn files(lengths: &[u64]) -> Vec<TorrentFile> {
+        let mut files: Vec<TorrentFile> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, length)| TorrentFile::new(PathBuf::from(i.to_string()), *length, None))
+            .collect();
+        layout(&mut files);
+        files
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component("a/b\\c"), "a_b_c");
+        assert_eq!(sanitize_component(" song.mp3 "), "song.mp3");
+        let path =
+            build_path(&["..".to_string(), "etc".to_string(), "/passwd".to_string()]).unwrap();
+        assert_eq!(path, PathBuf::from("_/etc/_passwd"));
+        assert!(build_path(&[]).is_err());
+    }
+
+    #[test]
+    fn test_map_range() {
+        let files = files(&[10, 0, 5, 20]);
+        assert_eq!(files[3].offset, 15);
+        // 一个piece跨越三个文件,长度为0的文件被跳过
+        let slices = map_range(&files, 8, 10);
+        assert_eq!(
+            slices,
+            vec![
+                FileSlice {
+                    file: 0,
+                    file_offset: 8,
+                    offset: 0,
+                    length: 2
+                },
+                FileSlice {
+                    file: 2,
+                    file_offset: 0,
+                    offset: 2,
+                    length: 5
+                },
+                FileSlice {
+                    file: 3,
+                    file_offset: 0,
+                    offset: 7,
+                    length: 3
+                },
+            ]
+        );
+        assert_eq!(map_range(&files, 30, 5).len(), 1);
+        assert!(map_range(&files, 35, 5).is_empty());
+    }
+
+    #[test]
+    fn test_piece_range() {
+        let files = files(&[10, 0, 25]);
+        assert_eq!(piece_range(&files[0], 8), Some(0..=1));
+        assert_eq!(piece_range(&files[1], 8), None);
+        assert_eq!(piece_range(&files[2], 8), Some(1..=4));
+        assert_eq!(piece_range(&files[2], 5), Some(2..=6));
+    }
+
+    #[test]
+    fn test_local_paths() {
+        let files = files(&[1, 2]);
+        let paths = local_paths(&files, "..", true, "/tmp/out");
+        assert_eq!(paths[1], PathBuf::from("/tmp/out/_/1"));
+        let paths = local_paths(&files[..1], "a.iso", false, "");
+        assert_eq!(paths, vec![PathBuf::from("a.iso")]);
+    }
+}
@@ -1,5 +1,8 @@
 use anyhow::Result;
 const PROTOCOL_ID: &str = "BitTorrent protocol";
+// 保留字节中表示支持扩展协议(BEP 10)的位,从右数第20位
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
 
 /// 握手需要发送的内容
 #[derive(PartialEq, Debug)]
@@ -30,6 +33,17 @@ impl Handshake {
         }
     }
 
+    /// 在保留字节中声明支持扩展协议
+    pub fn set_extension_protocol(&mut self) {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    }
+
+    /// 对方是否支持扩展协议
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved.len() == 8
+            && self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     /// 反序列化发出的内容
     pub fn serialize(&self) -> Result<Vec<u8>> {
         let mut serialized: Vec<u8> = vec![];
@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use url::Url;
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// 磁力链接
+/// magnet:?xt=urn:btih:<info hash>&dn=<名称>&tr=<tracker>&xl=<长度>
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Magnet {
+    // info字典的SHA1值
+    pub info_hash: Vec<u8>,
+    // tracker列表
+    pub trackers: Vec<String>,
+    // 显示的名称
+    pub name: Option<String>,
+    // 文件长度
+    pub length: Option<u64>,
+}
+
+impl Magnet {
+    /// 判断字符串是否是磁力链接
+    pub fn is_magnet(input: &str) -> bool {
+        input.to_lowercase().starts_with("magnet:?")
+    }
+
+    /// 解析磁力链接,info hash 可以是40位十六进制或者32位base32编码
+    pub fn parse(input: &str) -> Result<Magnet> {
+        let url = Url::parse(input).map_err(|_| anyhow!("invalid magnet link"))?;
+        if url.scheme() != "magnet" {
+            return Err(anyhow!("invalid magnet link"));
+        }
+        let mut magnet = Magnet::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // 前缀不区分大小写,info hash 的 base32 编码需要保留原样
+                    let prefix = value.get(..BTIH_PREFIX.len());
+                    if prefix.is_some_and(|p| p.eq_ignore_ascii_case(BTIH_PREFIX)) {
+                        magnet.info_hash = decode_info_hash(&value[BTIH_PREFIX.len()..])?;
+                    }
+                }
+                "tr" if !magnet.trackers.contains(&value.to_string()) => {
+                    magnet.trackers.push(value.to_string());
+                }
+                "dn" => magnet.name = Some(value.to_string()),
+                "xl" => magnet.length = value.parse::<u64>().ok(),
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_empty() {
+            return Err(anyhow!("magnet link has no urn:btih info hash"));
+        }
+        Ok(magnet)
+    }
+}
+
+/// 解码 info hash
+fn decode_info_hash(hash: &str) -> Result<Vec<u8>> {
+    let decoded = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    decoded.ok_or_else(|| anyhow!("invalid info hash {:?} in magnet link", hash))
+}
+
+/// RFC 4648 base32 解码,不区分大小写,不需要填充
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = vec![];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in input.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod magnet_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:C9E15763F722F23E98A29DECDFAE341B98D53056\
+             &dn=Ubuntu%20ISO&tr=http%3A%2F%2Ftracker.example%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A6969&xl=1024",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&magnet.info_hash),
+            "c9e15763f722f23e98a29decdfae341b98d53056"
+        );
+        assert_eq!(magnet.name, Some("Ubuntu ISO".to_string()));
+        assert_eq!(magnet.trackers.len(), 2);
+        assert_eq!(magnet.trackers[0], "http://tracker.example/announce");
+        assert_eq!(magnet.length, Some(1024));
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(
+            hex::encode(&magnet.info_hash),
+            "c9e15763f722f23e98a29decdfae341b98d53056"
+        );
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(Magnet::is_magnet("MAGNET:?xt=urn:btih:x"));
+        let magnet = Magnet::parse("magnet:?xt=URN:BTIH:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(magnet.info_hash.len(), 20);
+        // 小写后长度变化的字符不会导致越界
+        assert!(Magnet::parse("magnet:?xt=urn:btih:%E2%84%AA%E2%84%AA").is_err());
+    }
+}
@@ -14,6 +14,7 @@ pub const MESSAGE_BITFIELD: MessageId = 5; // 对发送者已经下载的片段
 pub const MESSAGE_REQUEST: MessageId = 6; // 向消息接收者请求一个块
 pub const MESSAGE_PIECE: MessageId = 7; // 传送满足请求的数据块
 pub const MESSAGE_CANCEL: MessageId = 8; // 取消一个请求
+pub const MESSAGE_EXTENDED: MessageId = 20; // 扩展协议(BEP 10)的消息
 
 #[derive(Default, Debug, Clone, PartialOrd, PartialEq)]
 pub struct Message {
@@ -56,6 +57,17 @@ impl Message {
     }
 }
 
+/// REQUEST 和 CANCEL 消息中的块
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockRequest {
+    // piece的索引
+    pub index: u32,
+    // 在piece中的偏移
+    pub begin: u32,
+    // 块的长度
+    pub length: u32,
+}
+
 /// 反序列化得到的内容
 pub fn deserialize_message(message_buf: &Vec<u8>, message_len: usize) -> Result<Message> {
     // 消息类型
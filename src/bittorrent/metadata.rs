@@ -0,0 +1,313 @@
+use crate::bittorrent::bencode::{decode_prefix, Dict, Value};
+use crate::bittorrent::client::*;
+use crate::bittorrent::extension::Extension;
+use crate::bittorrent::message::*;
+use crate::bittorrent::peer::*;
+use crate::bittorrent::piece::sha1;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{unbounded, Sender};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+
+// 扩展名称
+const UT_METADATA: &str = "ut_metadata";
+// ut_metadata 消息类型
+const METADATA_REQUEST: i64 = 0;
+const METADATA_DATA: i64 = 1;
+const METADATA_REJECT: i64 = 2;
+// 每块元数据的大小
+const METADATA_PIECE_SIZE: usize = 16384;
+// 元数据的最大长度,防止对方发送过大的长度
+const METADATA_SIZE_MAX: usize = 16 * 1024 * 1024;
+// 同时连接的peer数量
+const NB_PEERS_MAX: usize = 30;
+// 获取元数据的超时时间
+const METADATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// ut_metadata 消息
+fn metadata_message(msg_type: i64, piece: usize, total_size: Option<usize>) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    dict.insert(b"msg_type".to_vec(), Value::Int(msg_type));
+    dict.insert(b"piece".to_vec(), Value::Int(piece as i64));
+    if let Some(total_size) = total_size {
+        dict.insert(b"total_size".to_vec(), Value::Int(total_size as i64));
+    }
+    Value::Dict(dict).encode()
+}
+
+/// 正在接收的元数据
+struct Metadata {
+    // 元数据长度
+    size: usize,
+    // 每一块的数据
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl Metadata {
+    /// 使用对方扩展握手中的 metadata_size 建立
+    fn from_handshake(handshake: &Value) -> Result<Metadata> {
+        let size = handshake
+            .get("metadata_size")
+            .and_then(|size| size.as_int())
+            .ok_or_else(|| anyhow!("peer did not send metadata_size"))?;
+        if size <= 0 || size as usize > METADATA_SIZE_MAX {
+            return Err(anyhow!("invalid metadata_size received from peer"));
+        }
+        let size = size as usize;
+        Ok(Metadata {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    /// 保存收到的一块元数据
+    fn write_piece(&mut self, piece: usize, data: &[u8]) -> Result<()> {
+        if piece >= self.pieces.len() {
+            return Err(anyhow!("invalid metadata piece received from peer"));
+        }
+        // 除了最后一块,每块的大小都是固定的
+        let expected = METADATA_PIECE_SIZE.min(self.size - piece * METADATA_PIECE_SIZE);
+        if data.len() != expected {
+            return Err(anyhow!("invalid metadata piece received from peer"));
+        }
+        self.pieces[piece] = Some(data.to_vec());
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|piece| piece.is_some())
+    }
+
+    fn data(self) -> Vec<u8> {
+        self.pieces.into_iter().flatten().flatten().collect()
+    }
+}
+
+/// ut_metadata 扩展(BEP 9)
+/// 拥有info字典时响应对方的请求,否则向对方请求info字典
+pub struct UtMetadata {
+    // info字典的SHA1值
+    info_hash: Vec<u8>,
+    // 本机拥有的info字典
+    metadata: Option<Vec<u8>>,
+    // 正在接收的info字典
+    receiving: Option<Metadata>,
+    // 接收完成并校验通过后发送到这里
+    done: Option<Sender<Vec<u8>>>,
+}
+
+impl UtMetadata {
+    /// 向对方请求info字典,校验通过后发送到 done
+    pub fn fetch(info_hash: Vec<u8>, done: Sender<Vec<u8>>) -> Self {
+        UtMetadata {
+            info_hash,
+            metadata: None,
+            receiving: None,
+            done: Some(done),
+        }
+    }
+
+    /// 向对方提供info字典
+    pub fn serve(metadata: Vec<u8>) -> Self {
+        UtMetadata {
+            info_hash: sha1(&metadata),
+            metadata: Some(metadata),
+            receiving: None,
+            done: None,
+        }
+    }
+
+    /// 响应对方的请求,没有这一块时拒绝
+    fn reply(&self, piece: usize) -> Vec<u8> {
+        // 块号过大时乘法会溢出,同样拒绝
+        let begin = piece.checked_mul(METADATA_PIECE_SIZE);
+        match (&self.metadata, begin) {
+            (Some(metadata), Some(begin)) if begin < metadata.len() => {
+                let end = metadata.len().min(begin + METADATA_PIECE_SIZE);
+                let mut reply = metadata_message(METADATA_DATA, piece, Some(metadata.len()));
+                reply.extend_from_slice(&metadata[begin..end]);
+                reply
+            }
+            _ => metadata_message(METADATA_REJECT, piece, None),
+        }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn handshake(&self, handshake: &mut Dict) {
+        if let Some(metadata) = &self.metadata {
+            handshake.insert(b"metadata_size".to_vec(), Value::Int(metadata.len() as i64));
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &Value) -> Result<Vec<Vec<u8>>> {
+        if self.metadata.is_some() || self.receiving.is_some() {
+            return Ok(vec![]);
+        }
+        // 一次请求所有的块
+        let receiving = Metadata::from_handshake(handshake)?;
+        let requests = (0..receiving.pieces.len())
+            .map(|piece| metadata_message(METADATA_REQUEST, piece, None))
+            .collect();
+        self.receiving = Some(receiving);
+        Ok(requests)
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (message, len) = decode_prefix(payload)?;
+        let msg_type = message.get("msg_type").and_then(|v| v.as_int());
+        let piece = message
+            .get("piece")
+            .and_then(|v| v.as_int())
+            .filter(|piece| *piece >= 0)
+            .ok_or_else(|| anyhow!("invalid ut_metadata message received from peer"))?
+            as usize;
+        match msg_type {
+            Some(METADATA_REQUEST) => Ok(vec![self.reply(piece)]),
+            Some(METADATA_DATA) => {
+                let receiving = match self.receiving.as_mut() {
+                    Some(receiving) => receiving,
+                    None => return Ok(vec![]),
+                };
+                receiving.write_piece(piece, &payload[len..])?;
+                if receiving.is_complete() {
+                    let data = self.receiving.take().map(|m| m.data()).unwrap_or_default();
+                    if sha1(&data) != self.info_hash {
+                        return Err(anyhow!(
+                            "metadata received from peer does not match info hash"
+                        ));
+                    }
+                    if let Some(done) = self.done.take() {
+                        let _ = done.send(data.clone());
+                    }
+                    self.metadata = Some(data);
+                }
+                Ok(vec![])
+            }
+            Some(METADATA_REJECT) => Err(anyhow!("peer rejected metadata request")),
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+/// 从一个peer获取info字典
+pub async fn fetch_metadata(peer: Peer, peer_id: Vec<u8>, info_hash: Vec<u8>) -> Result<Vec<u8>> {
+    let (done, received) = unbounded();
+    let mut client = Client::new(peer, peer_id, info_hash.clone()).await?;
+    client.set_connection_timeout(30);
+    client.register_extension(Box::new(UtMetadata::fetch(info_hash, done)));
+    client.handshake_with_peer().await?;
+    if !client.supports_extension_protocol() {
+        return Err(anyhow!("peer does not support the extension protocol"));
+    }
+
+    loop {
+        let message = client.read_message().await?;
+        if message.id != MESSAGE_EXTENDED {
+            continue;
+        }
+        client.read_extended(message).await?;
+        if let Ok(data) = received.try_recv() {
+            return Ok(data);
+        }
+    }
+}
+
+/// 同时向多个peer请求info字典,返回第一个校验通过的结果
+pub async fn fetch_metadata_from_peers(
+    peers: &[Peer],
+    peer_id: &[u8],
+    info_hash: &[u8],
+) -> Result<Vec<u8>> {
+    if peers.is_empty() {
+        return Err(anyhow!("no peers to fetch metadata from"));
+    }
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+    let mut tasks = vec![];
+    for peer in peers.iter().take(NB_PEERS_MAX) {
+        let peer = peer.clone();
+        let peer_id = peer_id.to_vec();
+        let info_hash = info_hash.to_vec();
+        let result = result_tx.clone();
+        tasks.push(tokio::spawn(async move {
+            let _ = result.send(fetch_metadata(peer, peer_id, info_hash).await);
+        }));
+    }
+    drop(result_tx);
+
+    // 获取到元数据后断开其它连接
+    let deadline = Instant::now() + METADATA_TIMEOUT;
+    let fetched = loop {
+        match timeout_at(deadline, result_rx.recv()).await {
+            Ok(Some(Ok(data))) => break Ok(data),
+            Ok(Some(Err(e))) => info!("Could not fetch metadata from peer: {}", e),
+            Ok(None) => break Err(anyhow!("could not fetch metadata from any peer")),
+            Err(_) => break Err(anyhow!("timed out fetching metadata from peers")),
+        }
+    };
+    for task in tasks {
+        task.abort();
+    }
+    fetched
+}
+
+#[cfg(test)]
+mod metadata_test {
+    use super::*;
+    use crate::bittorrent::bencode::decode;
+
+    #[test]
+    fn test_fetch_and_serve() {
+        let info: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let mut server = UtMetadata::serve(info.clone());
+        let (done, received) = unbounded();
+        let mut client = UtMetadata::fetch(sha1(&info), done);
+
+        let mut handshake = BTreeMap::new();
+        server.handshake(&mut handshake);
+        let requests = client.on_handshake(&Value::Dict(handshake)).unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let replies = server.on_message(&request).unwrap();
+            assert!(client.on_message(&replies[0]).unwrap().is_empty());
+        }
+        assert_eq!(received.try_recv().unwrap(), info);
+
+        // 不存在的块会被拒绝
+        let reject = server
+            .on_message(&metadata_message(METADATA_REQUEST, 5, None))
+            .unwrap();
+        let (message, _) = decode_prefix(&reject[0]).unwrap();
+        assert_eq!(message.get("msg_type").and_then(|v| v.as_int()), Some(2));
+        assert!(client.on_message(&reject[0]).is_err());
+        let reject = server
+            .on_message(&metadata_message(METADATA_REQUEST, i64::MAX as usize, None))
+            .unwrap();
+        let (message, _) = decode_prefix(&reject[0]).unwrap();
+        assert_eq!(message.get("msg_type").and_then(|v| v.as_int()), Some(2));
+    }
+
+    #[test]
+    fn test_metadata_mismatch() {
+        let (done, received) = unbounded();
+        let mut client = UtMetadata::fetch(vec![0; 20], done);
+        assert!(client
+            .on_handshake(&decode(b"d13:metadata_sizei0ee").unwrap())
+            .is_err());
+        let handshake = decode(b"d13:metadata_sizei6ee").unwrap();
+        assert_eq!(client.on_handshake(&handshake).unwrap().len(), 1);
+        let mut payload = metadata_message(METADATA_DATA, 0, Some(6));
+        payload.extend_from_slice(b"abc");
+        assert!(client.on_message(&payload).is_err());
+        payload.extend_from_slice(b"def");
+        assert!(client.on_message(&payload).is_err());
+        assert!(received.try_recv().is_err());
+    }
+}
@@ -1,46 +1,86 @@
+pub mod bencode;
+pub mod bitfield;
+pub mod choker;
 pub mod client;
+pub mod codec;
+pub mod connection;
+pub mod dht;
+pub mod extension;
+pub mod files;
 pub mod handshake;
+pub mod magnet;
 pub mod message;
+pub mod metadata;
 pub mod parser;
 pub mod peer;
+pub mod picker;
 pub mod piece;
+pub mod resume;
+pub mod seeder;
+pub mod storage;
 pub mod torrent;
+pub mod tracker;
+pub mod udp_tracker;
 pub mod worker;
 
+use crate::bittorrent::dht::{default_state_path, DhtConfig, DEFAULT_BOOTSTRAP_NODES};
 use crate::bittorrent::parser::CommandArgument;
+use crate::bittorrent::seeder::SeedLimit;
 use anyhow::{anyhow, Result};
-use std::fs::File;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use magnet::Magnet;
 use torrent::*;
 
-async fn run(torrent: &str, file: &str) -> Result<()> {
-    // 检查文件是否存在
-    if !Path::new(&torrent).exists() {
-        return Err(anyhow!("could not find torrent"));
-    } else {
-        let torrent_filepath = PathBuf::from(torrent);
-        let output_filepath = PathBuf::from(file);
+async fn run(command: &CommandArgument) -> Result<()> {
+    let torrent_path = command.get_torrent();
+    let file = command.get_target_path();
+    let mut torrent = Torrent::new();
+    torrent.set_recheck(command.is_recheck());
+    torrent.set_priority_files(command.get_priority_files().to_vec());
+    torrent.set_seed_limit(SeedLimit {
+        ratio: command.get_seed_ratio(),
+        time: command.get_seed_time().map(Duration::from_secs),
+    });
 
-        // 新建下载文件
-        let mut output_file = match File::create(output_filepath) {
-            Ok(file) => file,
-            Err(_) => return Err(anyhow!("could not create file")),
-        };
-
-        // 打开torrent文件并开始下载
-        let mut torrent = Torrent::new();
-        if torrent.open(torrent_filepath).await.is_err() {
-            return Err(anyhow!("could not open file"));
-        };
-        let data: Vec<u8> = torrent.download()?;
+    // 没有指定引导节点时使用公共的引导节点
+    let mut bootstrap = command.get_dht_nodes().to_vec();
+    if bootstrap.is_empty() {
+        bootstrap = DEFAULT_BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect();
+    }
+    torrent.set_dht(DhtConfig {
+        enabled: command.is_dht(),
+        bootstrap,
+        state_path: Some(
+            command
+                .get_dht_state()
+                .map(PathBuf::from)
+                .unwrap_or_else(default_state_path),
+        ),
+    });
 
-        // 保存文件
-        if output_file.write(&data).is_err() {
-            return Err(anyhow!("could not write data to file"));
+    if Magnet::is_magnet(torrent_path) {
+        // 磁力链接需要先从peer获取info字典
+        torrent.open_magnet(torrent_path).await?;
+        if let Some(path) = command.get_save_torrent() {
+            torrent.save_torrent(path)?;
+            println!("Saved torrent in {:?}.", path);
         }
+    } else if !Path::new(&torrent_path).exists() {
+        // 检查文件是否存在
+        return Err(anyhow!("could not find torrent"));
+    } else {
+        // 打开torrent文件
+        let torrent_filepath = PathBuf::from(torrent_path);
+        torrent.open(torrent_filepath).await?;
+    }
 
-        println!("Saved in {:?}.", file);
+    // 开始下载
+    let paths = torrent.download(file).await?;
+    if paths.len() == 1 {
+        println!("Saved in {:?}.", paths[0]);
+    } else {
+        println!("Saved {} files in {:?}.", paths.len(), file);
     }
 
     Ok(())
@@ -52,9 +92,7 @@ pub async fn execute() {
     // 解析参数
     let mut command = CommandArgument::new();
     command.parse();
-    let file = command.get_torrent();
-    let target_path = command.get_target_path();
-    if let Err(error) = run(file, target_path).await {
+    if let Err(error) = run(&command).await {
         eprintln!("Error: {}", error);
         std::process::exit(1);
     }
@@ -3,6 +3,14 @@ use clap::{App, Arg};
 pub struct CommandArgument {
     file_path: Option<String>,
     target_path: String,
+    recheck: bool,
+    save_torrent: Option<String>,
+    dht: bool,
+    dht_nodes: Vec<String>,
+    dht_state: Option<String>,
+    seed_ratio: Option<f64>,
+    seed_time: Option<u64>,
+    priority_files: Vec<String>,
 }
 
 impl CommandArgument {
@@ -10,6 +18,14 @@ impl CommandArgument {
         Self {
             file_path: None,
             target_path: "".to_string(),
+            recheck: false,
+            save_torrent: None,
+            dht: true,
+            dht_nodes: vec![],
+            dht_state: None,
+            seed_ratio: None,
+            seed_time: None,
+            priority_files: vec![],
         }
     }
     pub fn parse(&mut self) {
@@ -21,7 +37,7 @@ impl CommandArgument {
                 Arg::new("torrent")
                     .short('t')
                     .long("torrent")
-                    .help("The path to the torrent")
+                    .help("The path to the torrent, or a magnet link")
                     .number_of_values(1)
                     .required(true),
             )
@@ -29,14 +45,79 @@ impl CommandArgument {
                 Arg::new("file")
                     .short('o')
                     .long("output")
-                    .help("The path where to save the file")
+                    .help("The path where to save the file, or the directory for multi-file torrents")
                     .number_of_values(1),
             )
+            .arg(
+                Arg::new("recheck")
+                    .long("recheck")
+                    .help("Ignore the saved progress and verify existing data again"),
+            )
+            .arg(
+                Arg::new("save-torrent")
+                    .long("save-torrent")
+                    .help("Save the metadata fetched for a magnet link as a torrent file")
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::new("no-dht")
+                    .long("no-dht")
+                    .help("Only get peers from trackers"),
+            )
+            .arg(
+                Arg::new("dht-node")
+                    .long("dht-node")
+                    .help("A DHT bootstrap node as host:port, can be given several times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("dht-state")
+                    .long("dht-state")
+                    .help("The file where the DHT routing table is kept between runs")
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::new("seed-ratio")
+                    .long("seed-ratio")
+                    .help("Stop seeding once the uploaded data reaches this multiple of the torrent size")
+                    .number_of_values(1)
+                    .validator(|s| match s.parse::<f64>() {
+                        Ok(ratio) if ratio >= 0.0 => Ok(()),
+                        _ => Err("must be a non-negative number"),
+                    }),
+            )
+            .arg(
+                Arg::new("seed-time")
+                    .long("seed-time")
+                    .help("Stop seeding after this many seconds")
+                    .number_of_values(1)
+                    .validator(|s| s.parse::<u64>()),
+            )
+            .arg(
+                Arg::new("priority")
+                    .long("priority")
+                    .help("Download this file of the torrent first, can be given several times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
             .get_matches();
         self.file_path = Some(matcher.value_of("torrent").unwrap().to_string());
         if matcher.value_of("file").is_some() {
             self.target_path = matcher.value_of("file").unwrap().to_string();
         }
+        self.recheck = matcher.is_present("recheck");
+        self.save_torrent = matcher.value_of("save-torrent").map(|s| s.to_string());
+        self.dht = !matcher.is_present("no-dht");
+        if let Some(nodes) = matcher.values_of("dht-node") {
+            self.dht_nodes = nodes.map(|s| s.to_string()).collect();
+        }
+        self.dht_state = matcher.value_of("dht-state").map(|s| s.to_string());
+        self.seed_ratio = matcher.value_of_t("seed-ratio").ok();
+        self.seed_time = matcher.value_of_t("seed-time").ok();
+        if let Some(files) = matcher.values_of("priority") {
+            self.priority_files = files.map(|s| s.to_string()).collect();
+        }
     }
     pub fn get_torrent(&self) -> &str {
         self.file_path.as_ref().unwrap().as_str()
@@ -45,4 +126,36 @@ impl CommandArgument {
     pub fn get_target_path(&self) -> &str {
         self.target_path.as_str()
     }
+
+    pub fn is_recheck(&self) -> bool {
+        self.recheck
+    }
+
+    pub fn get_save_torrent(&self) -> Option<&str> {
+        self.save_torrent.as_deref()
+    }
+
+    pub fn is_dht(&self) -> bool {
+        self.dht
+    }
+
+    pub fn get_dht_nodes(&self) -> &[String] {
+        &self.dht_nodes
+    }
+
+    pub fn get_dht_state(&self) -> Option<&str> {
+        self.dht_state.as_deref()
+    }
+
+    pub fn get_seed_ratio(&self) -> Option<f64> {
+        self.seed_ratio
+    }
+
+    pub fn get_seed_time(&self) -> Option<u64> {
+        self.seed_time
+    }
+
+    pub fn get_priority_files(&self) -> &[String] {
+        &self.priority_files
+    }
 }
@@ -3,12 +3,11 @@ extern crate anyhow;
 use super::torrent::*;
 
 use anyhow::{anyhow, Result};
-use byteorder::{BigEndian, ReadBytesExt};
 
-use std::io::Cursor;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 const PEER_SIZE: usize = 6;
+const PEER6_SIZE: usize = 18;
 
 type PeerId = u32;
 
@@ -16,7 +15,7 @@ type PeerId = u32;
 #[derive(Clone)]
 pub struct Peer {
     pub id: PeerId,
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
 }
 
@@ -24,49 +23,61 @@ impl Peer {
     pub fn new() -> Peer {
         Peer {
             id: 0,
-            ip: Ipv4Addr::new(1, 1, 1, 1),
+            ip: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
             port: 0,
         }
     }
 }
 
+/// 合并新获取的peer,去掉重复的地址后重新编号
+/// 返回新加入的peer
+pub fn merge_peers(peers: &mut Vec<Peer>, received: Vec<Peer>) -> Vec<Peer> {
+    let known = peers.len();
+    for peer in received {
+        if !peers.iter().any(|p| p.ip == peer.ip && p.port == peer.port) {
+            peers.push(peer);
+        }
+    }
+    for (id, peer) in peers.iter_mut().enumerate() {
+        peer.id = id as u32;
+    }
+    peers[known..].to_vec()
+}
+
+/// 解析紧凑格式的peer
+/// IPv4 每个6字节,IPv6 每个18字节,最后两个字节为端口
+pub fn parse_compact_peers(buf: &[u8], ipv6: bool) -> Result<Vec<Peer>> {
+    let size = if ipv6 { PEER6_SIZE } else { PEER_SIZE };
+    if !buf.len().is_multiple_of(size) {
+        return Err(anyhow!("received invalid peers from tracker"));
+    }
+    let peers = buf
+        .chunks_exact(size)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let ip = if ipv6 {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&chunk[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+            };
+            Peer {
+                id: i as u32,
+                ip,
+                port: u16::from_be_bytes([chunk[size - 2], chunk[size - 1]]),
+            }
+        })
+        .collect();
+    Ok(peers)
+}
+
 impl Torrent {
     /// 构建所有的Peer的信息
     /// 总共6bytes
     /// 前4byte为ip地址，后两个字节为端口
     pub fn build_peers(&self, tracker_peers: Vec<u8>) -> Result<Vec<Peer>> {
-        // 检查返回数据的合法性
-        if tracker_peers.len() % PEER_SIZE != 0 {
-            return Err(anyhow!("received invalid peers from tracker"));
-        }
-
-        // 获取peers数量
-        let nb_peers = tracker_peers.len() / PEER_SIZE;
-
-        // 建立peer
-        let mut peers: Vec<Peer> = vec![Peer::new(); nb_peers];
-        let mut port = vec![];
-
-        for i in 0..nb_peers {
-            // 建立 peer ID
-            peers[i].id = i as u32;
-            let offset = i * PEER_SIZE;
-            // Add peer IP address
-            peers[i].ip = Ipv4Addr::new(
-                tracker_peers[offset],
-                tracker_peers[offset + 1],
-                tracker_peers[offset + 2],
-                tracker_peers[offset + 3],
-            );
-            port.push(tracker_peers[offset + 4]);
-            port.push(tracker_peers[offset + 5]);
-            let mut port_cursor = Cursor::new(port);
-            // Add peer port
-            peers[i].port = port_cursor.read_u16::<BigEndian>()?;
-            port = vec![];
-        }
-
-        Ok(peers)
+        parse_compact_peers(&tracker_peers, false)
     }
 }
 
@@ -87,4 +98,13 @@ mod peer_test {
         let peerinfo = vec![192, 165, 1, 21, 12];
         let _ = torrent.build_peers(peerinfo).unwrap();
     }
+    #[test]
+    fn test_parse_compact_peers_ipv6() {
+        let mut peerinfo = vec![0; 15];
+        peerinfo.extend_from_slice(&[1, 0x1a, 0xe1]);
+        let answer = super::parse_compact_peers(&peerinfo, true).unwrap();
+        assert_eq!(answer[0].ip, "::1".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(answer[0].port, 6881);
+        assert!(super::parse_compact_peers(&peerinfo[1..], true).is_err());
+    }
 }
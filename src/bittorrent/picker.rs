@@ -0,0 +1,591 @@
+use crate::bittorrent::message::BlockRequest;
+use crate::bittorrent::piece::PieceWork;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// 每次请求的块的大小
+pub const BLOCK_SIZE: u32 = 16384;
+// 开始时随机选择的piece数量,尽快拥有可以和其它peer交换的piece
+const RANDOM_FIRST_PIECES: usize = 4;
+// peer数量不超过这个值时,多个peer同时下载同一个piece的不同块
+const SMALL_SWARM_PEERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceState {
+    // 还没有下载
+    Missing,
+    // 正在下载,已经收到的块保存在 partial 中
+    Downloading,
+    // 已经下载并校验
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    // 还没有请求
+    Missing,
+    // 已经向几个peer请求,endgame 时可能多于一个
+    Requested(u32),
+    // 已经收到
+    Received,
+}
+
+/// 正在下载的piece
+struct PartialPiece {
+    // 收到的块直接写入 work.data
+    work: PieceWork,
+    blocks: Vec<BlockState>,
+    // 已经收到的块的数量
+    received: usize,
+}
+
+impl PartialPiece {
+    fn new(mut work: PieceWork) -> Self {
+        let nb_blocks = (work.length as usize).div_ceil(BLOCK_SIZE as usize);
+        work.data = vec![0; work.length as usize];
+        work.downloaded = 0;
+        PartialPiece {
+            work,
+            blocks: vec![BlockState::Missing; nb_blocks],
+            received: 0,
+        }
+    }
+
+    fn request(&self, block: usize) -> BlockRequest {
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockRequest {
+            index: self.work.index,
+            begin,
+            length: BLOCK_SIZE.min(self.work.length - begin),
+        }
+    }
+
+    /// 第一个还没有请求的块
+    fn missing_block(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|state| *state == BlockState::Missing)
+    }
+
+    /// 没有任何请求的块,下载这个piece的peer都已经断开
+    fn is_orphaned(&self) -> bool {
+        !self
+            .blocks
+            .iter()
+            .any(|state| matches!(state, BlockState::Requested(_)))
+    }
+}
+
+struct Pieces {
+    states: Vec<PieceState>,
+    // 需要下载的piece的下载任务
+    works: Vec<Option<PieceWork>>,
+    // 正在下载的piece,块的状态和已经收到的数据
+    partial: HashMap<u32, PartialPiece>,
+    // 拥有每个piece的peer数量
+    availability: Vec<u32>,
+    // 优先级,越大越先下载
+    priorities: Vec<u8>,
+    // 设置过的最高优先级,正在下载的piece已经是这个优先级时不需要再找新的piece
+    top_priority: u8,
+    // 连接的peer数量
+    peers: usize,
+    // 这次运行中下载完成的piece数量
+    completed: usize,
+    // 还没有完成的piece数量
+    remaining: usize,
+    // 还没有开始下载的piece数量
+    missing: usize,
+}
+
+impl Pieces {
+    /// 在正在下载的piece中选择一个还没有请求的块
+    /// 优先选择优先级高的piece,其次选择收到的块最多的,尽快完成
+    fn pick_partial(&self, filter: impl Fn(&PartialPiece) -> bool) -> Option<BlockRequest> {
+        let piece = self
+            .partial
+            .values()
+            .filter(|piece| piece.missing_block().is_some() && filter(piece))
+            .max_by_key(|piece| {
+                (
+                    self.priorities[piece.work.index as usize],
+                    piece.received,
+                    Reverse(piece.work.index),
+                )
+            })?;
+        Some(piece.request(piece.missing_block()?))
+    }
+
+    /// 选择一个还没有开始下载的piece
+    /// 开始时没有设置优先级的piece随机选择,之后选择最稀有的,同样稀有时随机选择
+    fn pick_piece(&self, has: impl Fn(u32) -> bool) -> Option<usize> {
+        let random_first = self.completed < RANDOM_FIRST_PIECES && self.top_priority == 0;
+        let mut rng = rand::thread_rng();
+        let mut best = None;
+        let mut ties = 0;
+        for index in 0..self.states.len() {
+            if self.states[index] != PieceState::Missing || !has(index as u32) {
+                continue;
+            }
+            let rarity = if random_first {
+                0
+            } else {
+                self.availability[index]
+            };
+            let key = (Reverse(self.priorities[index]), rarity);
+            match best {
+                Some((best_key, _)) if key > best_key => continue,
+                // 每个同样好的piece都以相同的概率被选中
+                Some((best_key, _)) if key == best_key => {
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        best = Some((key, index));
+                    }
+                }
+                _ => {
+                    best = Some((key, index));
+                    ties = 1;
+                }
+            }
+        }
+        best.map(|(_, index)| index)
+    }
+
+    /// endgame 时选择已经向其它peer请求但还没有收到的块,优先选择请求次数最少的
+    fn pick_endgame(
+        &self,
+        has: impl Fn(u32) -> bool,
+        pending: &[BlockRequest],
+    ) -> Option<BlockRequest> {
+        self.partial
+            .values()
+            .filter(|piece| has(piece.work.index))
+            .flat_map(|piece| {
+                piece
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(block, state)| match state {
+                        BlockState::Requested(count) => Some((*count, piece.request(block))),
+                        _ => None,
+                    })
+            })
+            .filter(|(_, request)| !pending.contains(request))
+            .min_by_key(|(count, request)| (*count, request.index, request.begin))
+            .map(|(_, request)| request)
+    }
+
+    /// 所有剩下的块都已经请求
+    fn is_endgame(&self) -> bool {
+        self.missing == 0
+            && self.remaining > 0
+            && self
+                .partial
+                .values()
+                .all(|piece| piece.missing_block().is_none())
+    }
+
+    fn block_mut(&mut self, request: &BlockRequest) -> Option<&mut BlockState> {
+        let piece = self.partial.get_mut(&request.index)?;
+        if !request.begin.is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+        piece.blocks.get_mut((request.begin / BLOCK_SIZE) as usize)
+    }
+}
+
+/// 为每个worker选择下载的块,记录每个piece中每个块的状态
+/// 优先选择优先级最高的piece,其次选择拥有的peer最少的piece(rarest first),
+/// 刚开始时随机选择,尽快拥有可以上传给其它peer的piece;
+/// 断开的peer已经收到的块会保留,由其它peer下载剩下的块;
+/// 所有块都请求后进入 endgame,同时从其它拥有的peer请求还没有收到的块
+#[derive(Clone)]
+pub struct PiecePicker {
+    pieces: Arc<Mutex<Pieces>>,
+}
+
+impl PiecePicker {
+    /// len 是piece的总数量,works 是需要下载的piece
+    pub fn new(len: usize, works: Vec<PieceWork>) -> Self {
+        let mut pieces = Pieces {
+            states: vec![PieceState::Done; len],
+            works: (0..len).map(|_| None).collect(),
+            partial: HashMap::new(),
+            availability: vec![0; len],
+            priorities: vec![0; len],
+            top_priority: 0,
+            peers: 0,
+            completed: 0,
+            remaining: 0,
+            missing: 0,
+        };
+        for work in works {
+            let index = work.index as usize;
+            if index < len && pieces.states[index] == PieceState::Done {
+                pieces.states[index] = PieceState::Missing;
+                pieces.works[index] = Some(work);
+                pieces.remaining += 1;
+                pieces.missing += 1;
+            }
+        }
+        PiecePicker {
+            pieces: Arc::new(Mutex::new(pieces)),
+        }
+    }
+
+    /// piece的总数量
+    pub fn nb_pieces(&self) -> usize {
+        self.pieces.lock().unwrap().states.len()
+    }
+
+    /// 加入一个peer,has 返回这个peer是否拥有某个piece
+    pub fn add_peer(&self, has: impl Fn(u32) -> bool) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.peers += 1;
+        for (index, availability) in pieces.availability.iter_mut().enumerate() {
+            if has(index as u32) {
+                *availability += 1;
+            }
+        }
+    }
+
+    /// 移除断开连接的peer
+    pub fn remove_peer(&self, has: impl Fn(u32) -> bool) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.peers = pieces.peers.saturating_sub(1);
+        for (index, availability) in pieces.availability.iter_mut().enumerate() {
+            if has(index as u32) {
+                *availability = availability.saturating_sub(1);
+            }
+        }
+    }
+
+    /// peer 发送了 HAVE
+    pub fn have(&self, index: u32) {
+        if let Some(availability) = self
+            .pieces
+            .lock()
+            .unwrap()
+            .availability
+            .get_mut(index as usize)
+        {
+            *availability += 1;
+        }
+    }
+
+    /// 设置piece的优先级,优先级高的piece不论稀有程度都先下载
+    pub fn set_priority(&self, index: u32, priority: u8) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if let Some(p) = pieces.priorities.get_mut(index as usize) {
+            *p = priority;
+            pieces.top_priority = pieces.top_priority.max(priority);
+        }
+    }
+
+    /// 选择peer拥有的下一个要请求的块,pending 是这个peer已经请求还没有收到的块
+    /// 没有可以请求的块时返回 None
+    pub fn pick_block(
+        &self,
+        has: impl Fn(u32) -> bool,
+        pending: &[BlockRequest],
+    ) -> Option<BlockRequest> {
+        let mut pieces = self.pieces.lock().unwrap();
+
+        // 先继续这个peer正在下载的piece,然后是断开的peer留下的piece
+        // peer较少时和其它peer一起下载同一个piece
+        let small_swarm = pieces.peers <= SMALL_SWARM_PEERS;
+        let mut request = pieces
+            .pick_partial(|piece| {
+                pending
+                    .iter()
+                    .any(|request| request.index == piece.work.index)
+            })
+            .or_else(|| {
+                pieces.pick_partial(|piece| {
+                    has(piece.work.index) && (small_swarm || piece.is_orphaned())
+                })
+            });
+
+        // 没有可以继续的piece,或者可能有优先级更高的新piece时才选择新的piece
+        let priority = |request: &BlockRequest| pieces.priorities[request.index as usize];
+        let lower = request.is_none_or(|partial| priority(&partial) < pieces.top_priority);
+        let new_piece = match lower {
+            true => pieces.pick_piece(&has),
+            false => None,
+        };
+        if let Some(index) = new_piece {
+            if request.is_none_or(|partial| pieces.priorities[index] > priority(&partial)) {
+                let work = pieces.works[index].clone()?;
+                let piece = PartialPiece::new(work);
+                request = Some(piece.request(0));
+                pieces.partial.insert(index as u32, piece);
+                pieces.states[index] = PieceState::Downloading;
+                pieces.missing -= 1;
+            }
+        }
+
+        // 没有新的piece时和其它peer一起下载
+        let request = request
+            .or_else(|| pieces.pick_partial(|piece| has(piece.work.index)))
+            .or_else(|| match pieces.is_endgame() {
+                true => pieces.pick_endgame(&has, pending),
+                false => None,
+            })?;
+        if let Some(state) = pieces.block_mut(&request) {
+            *state = match *state {
+                BlockState::Requested(count) => BlockState::Requested(count + 1),
+                _ => BlockState::Requested(1),
+            };
+        }
+        Some(request)
+    }
+
+    /// 不再等待请求的块,例如被阻塞或者连接断开,没有其它请求时可以再分配
+    pub fn cancel_block(&self, request: &BlockRequest) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if let Some(state) = pieces.block_mut(request) {
+            *state = match *state {
+                BlockState::Requested(count) if count > 1 => BlockState::Requested(count - 1),
+                BlockState::Requested(_) => BlockState::Missing,
+                state => state,
+            };
+        }
+    }
+
+    /// 块是否还需要下载,其它peer已经发送时返回 false
+    pub fn is_block_needed(&self, request: &BlockRequest) -> bool {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces
+            .block_mut(request)
+            .is_some_and(|state| *state != BlockState::Received)
+    }
+
+    /// 保存收到的块,piece的所有块都收到时返回完整的piece,由调用者校验
+    /// 已经收到过的块直接忽略
+    pub fn receive_block(&self, index: u32, begin: u32, block: &[u8]) -> Result<Option<PieceWork>> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let request = BlockRequest {
+            index,
+            begin,
+            length: block.len() as u32,
+        };
+        let piece = match pieces.partial.get_mut(&index) {
+            Some(piece) => piece,
+            None => return Ok(None),
+        };
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        if block_index >= piece.blocks.len() || piece.request(block_index) != request {
+            return Err(anyhow!("received invalid block from peer"));
+        }
+        if piece.blocks[block_index] == BlockState::Received {
+            return Ok(None);
+        }
+        let begin = begin as usize;
+        piece.work.data[begin..begin + block.len()].copy_from_slice(block);
+        piece.work.downloaded += block.len() as u32;
+        piece.blocks[block_index] = BlockState::Received;
+        piece.received += 1;
+        if piece.received < piece.blocks.len() {
+            return Ok(None);
+        }
+        // 校验期间不再分配这个piece的块
+        Ok(pieces.partial.remove(&index).map(|piece| piece.work))
+    }
+
+    /// 校验失败,丢弃收到的块重新下载
+    pub fn discard(&self, index: u32) {
+        let mut pieces = self.pieces.lock().unwrap();
+        let index = index as usize;
+        if pieces.states.get(index) == Some(&PieceState::Downloading) {
+            pieces.partial.remove(&(index as u32));
+            pieces.states[index] = PieceState::Missing;
+            pieces.missing += 1;
+        }
+    }
+
+    /// piece下载并校验完成,返回 false 表示这个piece已经完成
+    pub fn finish(&self, index: u32) -> bool {
+        let mut pieces = self.pieces.lock().unwrap();
+        let index = index as usize;
+        if pieces.states.get(index) != Some(&PieceState::Downloading) {
+            return false;
+        }
+        pieces.states[index] = PieceState::Done;
+        pieces.partial.remove(&(index as u32));
+        pieces.completed += 1;
+        pieces.remaining -= 1;
+        true
+    }
+
+    /// piece是否已经下载完成
+    pub fn is_done(&self, index: u32) -> bool {
+        self.pieces.lock().unwrap().states.get(index as usize) == Some(&PieceState::Done)
+    }
+
+    /// 所有piece都已经下载完成
+    pub fn is_complete(&self) -> bool {
+        self.pieces.lock().unwrap().remaining == 0
+    }
+}
+
+#[cfg(test)]
+mod picker_test {
+    use super::*;
+
+    fn works(indexes: &[u32], length: u32) -> Vec<PieceWork> {
+        indexes
+            .iter()
+            .map(|index| PieceWork::new(*index, vec![0; 20], length))
+            .collect()
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let picker = PiecePicker::new(6, works(&[0, 1, 2, 3, 4, 5], 16));
+        picker.pieces.lock().unwrap().completed = RANDOM_FIRST_PIECES;
+        picker.add_peer(|_| true);
+        picker.add_peer(|index| index != 3);
+        picker.add_peer(|index| index < 2);
+        picker.have(5);
+
+        // 第一个peer可以下载所有piece,piece 3 最稀有
+        let first = picker.pick_block(|_| true, &[]).unwrap();
+        assert_eq!(first.index, 3);
+        let second = picker.pick_block(|_| true, &[first]).unwrap();
+        assert!(second.index == 2 || second.index == 4);
+
+        // 优先级高的piece先下载
+        picker.set_priority(0, 1);
+        assert_eq!(picker.pick_block(|_| true, &[]).unwrap().index, 0);
+
+        // 校验失败的piece重新下载
+        assert!(picker.receive_block(3, 0, &[1; 16]).unwrap().is_some());
+        picker.discard(3);
+        assert!(!picker.is_done(3));
+        picker.remove_peer(|_| true);
+        assert_eq!(picker.pick_block(|index| index == 3, &[]).unwrap().index, 3);
+        let work = picker.receive_block(3, 0, &[2; 16]).unwrap().unwrap();
+        assert_eq!(work.data, vec![2; 16]);
+        assert!(picker.finish(3));
+        assert!(!picker.finish(3));
+        assert!(picker.is_done(3));
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn test_keep_blocks_of_failed_peer() {
+        let picker = PiecePicker::new(2, works(&[0, 1], BLOCK_SIZE * 3));
+        for _ in 0..SMALL_SWARM_PEERS + 1 {
+            picker.add_peer(|_| true);
+        }
+        let first = picker.pick_block(|index| index == 0, &[]).unwrap();
+        let second = picker.pick_block(|index| index == 0, &[first]).unwrap();
+        assert_eq!((first.index, first.begin), (0, 0));
+        assert_eq!((second.index, second.begin), (0, BLOCK_SIZE));
+        assert!(picker
+            .receive_block(0, 0, &[1; BLOCK_SIZE as usize])
+            .unwrap()
+            .is_none());
+        // 错误的块
+        assert!(picker.receive_block(0, BLOCK_SIZE, &[0; 1]).is_err());
+
+        // 对方断开后,已经收到的块保留,其它peer只下载剩下的块
+        picker.cancel_block(&second);
+        let block = picker.pick_block(|_| true, &[]).unwrap();
+        assert_eq!((block.index, block.begin), (0, BLOCK_SIZE));
+        let last = picker.pick_block(|_| true, &[block]).unwrap();
+        assert_eq!((last.index, last.begin), (0, BLOCK_SIZE * 2));
+        picker
+            .receive_block(0, BLOCK_SIZE, &[2; BLOCK_SIZE as usize])
+            .unwrap();
+        let work = picker
+            .receive_block(0, BLOCK_SIZE * 2, &[3; BLOCK_SIZE as usize])
+            .unwrap()
+            .unwrap();
+        assert_eq!(work.data[0], 1);
+        assert_eq!(work.data[BLOCK_SIZE as usize], 2);
+        assert_eq!(work.data[BLOCK_SIZE as usize * 2], 3);
+    }
+
+    #[test]
+    fn test_share_pieces_and_endgame() {
+        let picker = PiecePicker::new(2, works(&[0, 1], BLOCK_SIZE * 2));
+        picker.add_peer(|_| true);
+        picker.add_peer(|index| index == 0);
+
+        // peer较少时多个peer下载同一个piece的不同块
+        let first = picker.pick_block(|index| index == 0, &[]).unwrap();
+        let second = picker.pick_block(|_| true, &[]).unwrap();
+        assert_eq!(first.index, 0);
+        assert_eq!((second.index, second.begin), (0, BLOCK_SIZE));
+        let third = picker.pick_block(|_| true, &[second]).unwrap();
+        assert_eq!(third.index, 1);
+        let fourth = picker.pick_block(|_| true, &[second, third]).unwrap();
+        assert_eq!((fourth.index, fourth.begin), (1, BLOCK_SIZE));
+
+        // 所有块都已经请求,进入 endgame,同一个peer不重复请求
+        assert!(picker.pieces.lock().unwrap().is_endgame());
+        let duplicate = picker.pick_block(|index| index == 0, &[first]).unwrap();
+        assert_eq!(duplicate, second);
+        assert!(picker
+            .pick_block(|index| index == 0, &[first, second])
+            .is_none());
+
+        // 收到后其它peer的请求不再需要
+        picker
+            .receive_block(0, BLOCK_SIZE, &[0; BLOCK_SIZE as usize])
+            .unwrap();
+        assert!(!picker.is_block_needed(&second));
+        assert!(picker.is_block_needed(&first));
+        picker
+            .receive_block(0, BLOCK_SIZE, &[0; BLOCK_SIZE as usize])
+            .unwrap();
+        assert!(picker
+            .receive_block(0, 0, &[0; BLOCK_SIZE as usize])
+            .unwrap()
+            .is_some());
+        assert!(!picker.is_block_needed(&first));
+        assert!(picker.finish(0));
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn test_no_endgame_while_missing() {
+        let picker = PiecePicker::new(2, works(&[0, 1], BLOCK_SIZE));
+        picker.add_peer(|index| index == 0);
+        picker.add_peer(|index| index == 0);
+
+        // 还有piece没有开始下载时,不重复请求其它peer已经请求的块
+        let first = picker.pick_block(|index| index == 0, &[]).unwrap();
+        assert_eq!(first.index, 0);
+        assert!(picker.pick_block(|index| index == 0, &[]).is_none());
+        assert!(!picker.pieces.lock().unwrap().is_endgame());
+    }
+
+    #[test]
+    fn test_pick_among_rarest() {
+        let picker = PiecePicker::new(4, works(&[0, 1, 2, 3], 16));
+        picker.add_peer(|_| true);
+        picker.add_peer(|index| index != 1 && index != 3);
+        let mut pieces = picker.pieces.lock().unwrap();
+        // 开始时随机选择所有的piece
+        let mut picked = [false; 4];
+        for _ in 0..200 {
+            picked[pieces.pick_piece(|_| true).unwrap()] = true;
+        }
+        assert_eq!(picked, [true; 4]);
+        // 之后只在最稀有的piece中随机选择
+        pieces.completed = RANDOM_FIRST_PIECES;
+        let mut picked = [false; 4];
+        for _ in 0..200 {
+            picked[pieces.pick_piece(|_| true).unwrap()] = true;
+        }
+        assert_eq!(picked, [false, true, false, true]);
+        assert_eq!(pieces.pick_piece(|index| index != 3), Some(1));
+        assert_eq!(pieces.pick_piece(|_| false), None);
+    }
+}
@@ -1,3 +1,6 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
 /// PieceWork structure.
 #[derive(Default, Debug, Clone)]
 pub struct PieceWork {
@@ -33,7 +36,8 @@ impl PieceWork {
             index,
             hash,
             length,
-            data: vec![0; length as usize],
+            // 开始下载时才分配内存
+            data: vec![],
             requests: 0,
             requested: 0,
             downloaded: 0,
@@ -50,3 +54,12 @@ impl PieceResult {
         }
     }
 }
+
+/// 计算数据的SHA1值
+pub fn sha1(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    let mut hash = vec![0; hasher.output_bytes()];
+    hasher.result(&mut hash);
+    hash
+}
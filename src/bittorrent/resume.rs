@@ -0,0 +1,160 @@
+use crate::bittorrent::bencode::{decode, Value};
+use crate::bittorrent::bitfield::Bitfield;
+use crate::bittorrent::files::TorrentFile;
+use crate::bittorrent::piece::*;
+use crate::bittorrent::storage::Storage;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::unbounded;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+/// 保存已经校验过的piece的文件
+/// 内容为 bencode 字典 {info_hash, bitfield}
+pub struct Resume {
+    path: PathBuf,
+    info_hash: Vec<u8>,
+}
+
+impl Resume {
+    pub fn new(path: PathBuf, info_hash: Vec<u8>) -> Self {
+        Resume { path, info_hash }
+    }
+
+    /// 读取保存的位图
+    /// 文件不存在、属于其它torrent或者下载的文件不完整时返回None,需要重新校验
+    pub fn load(
+        &self,
+        paths: &[PathBuf],
+        files: &[TorrentFile],
+        nb_pieces: usize,
+    ) -> Option<Bitfield> {
+        let buf = fs::read(&self.path).ok()?;
+        let value = decode(&buf).ok()?;
+        if value.get("info_hash")?.as_bytes()? != self.info_hash.as_slice() {
+            return None;
+        }
+        // 文件被删除或者长度被修改过
+        let intact = files.iter().zip(paths.iter()).all(|(file, path)| {
+            fs::metadata(path)
+                .map(|m| m.len() == file.length)
+                .unwrap_or(false)
+        });
+        if !intact {
+            return None;
+        }
+        let bits = value.get("bitfield")?.as_bytes()?;
+        if bits.len() != nb_pieces.div_ceil(8) {
+            return None;
+        }
+        Some(Bitfield::from_bytes(bits, nb_pieces))
+    }
+
+    /// 保存位图,先写临时文件再重命名,避免中断时留下不完整的内容
+    pub fn save(&self, bitfield: &Bitfield) -> Result<()> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"info_hash".to_vec(), Value::Bytes(self.info_hash.clone()));
+        dict.insert(
+            b"bitfield".to_vec(),
+            Value::Bytes(bitfield.as_bytes().to_vec()),
+        );
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        if fs::write(&tmp, Value::Dict(dict).encode()).is_err()
+            || fs::rename(&tmp, &self.path).is_err()
+        {
+            return Err(anyhow!("could not save resume file {:?}", self.path));
+        }
+        Ok(())
+    }
+}
+
+/// 多线程校验已有的数据,返回校验通过的piece
+pub fn recheck(storage: Arc<Storage>, pieces: Vec<PieceWork>) -> Bitfield {
+    let mut bitfield = Bitfield::new(pieces.len());
+    let total: u64 = pieces.iter().map(|piece| piece.length as u64).sum();
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("checking {bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}%")
+            .progress_chars("#>-"),
+    );
+
+    let work_chan = unbounded::<PieceWork>();
+    let result_chan = unbounded::<(u32, u32, bool)>();
+    for piece in pieces {
+        let _ = work_chan.0.send(piece);
+    }
+    drop(work_chan.0);
+
+    let nb_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    for _ in 0..nb_threads {
+        let storage = storage.clone();
+        let work = work_chan.1.clone();
+        let result = result_chan.0.clone();
+        thread::spawn(move || {
+            for piece in work.iter() {
+                let ok = match storage.read_piece(piece.index, piece.length) {
+                    Ok(data) => sha1(&data) == piece.hash,
+                    Err(_) => false,
+                };
+                if result.send((piece.index, piece.length, ok)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(result_chan.0);
+
+    for (index, length, ok) in result_chan.1.iter() {
+        if ok {
+            bitfield.set(index as usize);
+        }
+        pb.inc(length as u64);
+    }
+    pb.finish_and_clear();
+    bitfield
+}
+
+#[cfg(test)]
+mod resume_test {
+    use super::*;
+    use crate::bittorrent::files::layout;
+
+    #[test]
+    fn test_recheck_and_resume() {
+        let dir = std::env::temp_dir().join("resume_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut files = vec![
+            TorrentFile::new(PathBuf::from("a"), 6, None),
+            TorrentFile::new(PathBuf::from("b"), 4, None),
+        ];
+        layout(&mut files);
+        let paths: Vec<PathBuf> = files.iter().map(|f| dir.join(&f.path)).collect();
+        let storage = Arc::new(Storage::open(&files, paths.clone(), 4).unwrap());
+        storage.write(0, b"0123456789").unwrap();
+        // 第二个piece的hash是错误的
+        let pieces = vec![
+            PieceWork::new(0, sha1(b"0123"), 4),
+            PieceWork::new(1, sha1(b"xxxx"), 4),
+            PieceWork::new(2, sha1(b"89"), 2),
+        ];
+        let bitfield = recheck(storage, pieces);
+        assert!(bitfield.has(0) && !bitfield.has(1) && bitfield.has(2));
+
+        let resume = Resume::new(dir.join("a.resume"), vec![7; 20]);
+        resume.save(&bitfield).unwrap();
+        assert_eq!(resume.load(&paths, &files, 3), Some(bitfield));
+        assert_eq!(
+            Resume::new(dir.join("a.resume"), vec![8; 20]).load(&paths, &files, 3),
+            None
+        );
+        fs::write(&paths[1], b"89").unwrap();
+        assert_eq!(resume.load(&paths, &files, 3), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
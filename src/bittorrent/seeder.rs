@@ -0,0 +1,452 @@
+use crate::bittorrent::bitfield::Bitfield;
+use crate::bittorrent::choker::{Choker, PeerState, CHOKE_INTERVAL};
+use crate::bittorrent::client::*;
+use crate::bittorrent::connection::ConnectionManager;
+use crate::bittorrent::message::*;
+use crate::bittorrent::metadata::UtMetadata;
+use crate::bittorrent::peer::Peer;
+use crate::bittorrent::storage::Storage;
+use crate::bittorrent::tracker::TransferStats;
+
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+// 每个peer排队等待发送的请求数量
+const NB_QUEUED_MAX: usize = 256;
+// 一次请求的最大长度,超过时断开连接
+const REQUEST_LENGTH_MAX: u32 = 131072;
+// 没有请求需要发送时等待消息的时间
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// 检查是否停止的间隔
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// 接受连接失败后等待的时间,文件描述符用完时不会一直重试
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// 对方长时间没有消息时断开连接
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 做种的停止条件,都没有设置时一直做种直到退出
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SeedLimit {
+    // 上传量达到文件大小的倍数
+    pub ratio: Option<f64>,
+    // 做种的时间
+    pub time: Option<Duration>,
+}
+
+impl SeedLimit {
+    /// 是否达到了任意一个停止条件
+    pub fn is_reached(&self, uploaded: u64, length: u64, elapsed: Duration) -> bool {
+        let ratio = self
+            .ratio
+            .is_some_and(|ratio| uploaded as f64 >= ratio * length as f64);
+        let time = self.time.is_some_and(|time| elapsed >= time);
+        ratio || time
+    }
+}
+
+/// 接受其它peer发起的连接,提供已经下载的piece
+#[derive(Clone)]
+pub struct Seeder {
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    // info字典的原始数据,提供给通过磁力链接下载的peer
+    info_bytes: Vec<u8>,
+    storage: Arc<Storage>,
+    // 已经写入磁盘的piece
+    have: Arc<RwLock<Bitfield>>,
+    // 每个piece的大小
+    piece_length: u32,
+    // 文件总大小
+    length: u64,
+    stats: Arc<TransferStats>,
+    // 决定向哪些peer上传
+    choker: Choker,
+    // 和下载的连接共用连接数量的上限
+    connections: ConnectionManager,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Seeder {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        info_bytes: Vec<u8>,
+        storage: Arc<Storage>,
+        have: Arc<RwLock<Bitfield>>,
+        piece_length: u32,
+        length: u64,
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        Seeder {
+            info_hash,
+            peer_id,
+            info_bytes,
+            storage,
+            have,
+            piece_length,
+            length,
+            stats,
+            choker: Choker::new(),
+            connections: ConnectionManager::default(),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 设置和下载共用的连接管理
+    pub fn set_connections(&mut self, connections: ConnectionManager) {
+        self.connections = connections;
+    }
+
+    /// 在 port 上监听,每个连接是单独的任务
+    pub async fn listen(&self, port: u16) -> Result<JoinHandle<()>> {
+        // 同时接受IPv4和IPv6的连接,不支持IPv6时只监听IPv4
+        let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => listener,
+            Err(_) => match TcpListener::bind(("0.0.0.0", port)).await {
+                Ok(listener) => listener,
+                Err(_) => return Err(anyhow!("could not listen on port {}", port)),
+            },
+        };
+        info!("Listening for peers on port {}", port);
+        let seeder = self.clone();
+        Ok(tokio::spawn(async move { seeder.accept(listener).await }))
+    }
+
+    /// 停止接受连接、断开所有peer并停止 choker
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    async fn accept(&self, listener: TcpListener) {
+        let mut accepted: u32 = 0;
+        while !self.stopped.load(Ordering::Relaxed) {
+            // 定期检查是否停止
+            let (conn, addr) = match timeout(STOP_POLL_INTERVAL, listener.accept()).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    warn!("Could not accept connection: {}", e);
+                    sleep(ACCEPT_RETRY_INTERVAL).await;
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            // 连接数量已满时直接关闭
+            let permit = match self.connections.try_accept() {
+                Some(permit) => permit,
+                None => continue,
+            };
+            // 使用和主动连接的peer不同的编号,便于区分日志
+            accepted += 1;
+            let peer = Peer {
+                id: u32::MAX - accepted,
+                ip: to_canonical(addr).ip(),
+                port: addr.port(),
+            };
+            let seeder = self.clone();
+            tokio::spawn(async move {
+                let mut client = Client::from_stream(
+                    peer,
+                    conn,
+                    seeder.peer_id.clone(),
+                    seeder.info_hash.clone(),
+                );
+                if let Err(e) = seeder.serve(&mut client).await {
+                    info!("Stopped serving peer: {}", e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// 完成握手后发送bitfield,然后响应对方的请求
+    async fn serve(&self, client: &mut Client) -> Result<()> {
+        client.set_connection_timeout(30);
+        if !self.info_bytes.is_empty() {
+            client.register_extension(Box::new(UtMetadata::serve(self.info_bytes.clone())));
+        }
+        client.handshake_from_peer().await?;
+        let mut sent = self.have();
+        client.send_bitfield(sent.as_bytes()).await?;
+
+        // 由 choker 决定是否响应对方的请求
+        let state = self.choker.register();
+        let mut requests: VecDeque<BlockRequest> = VecDeque::new();
+        let mut active = Instant::now();
+        while !self.stopped.load(Ordering::Relaxed) {
+            self.send_haves(client, &mut sent).await?;
+            if self.update_choking(client, &state).await? {
+                requests.clear();
+            }
+
+            // 有请求需要发送时不等待
+            let wait = match requests.is_empty() {
+                true => POLL_INTERVAL,
+                false => Duration::from_millis(1),
+            };
+            let message = match timeout(wait, client.read_next_message()).await {
+                Ok(message) => message?,
+                Err(_) => {
+                    match requests.pop_front() {
+                        Some(request) => self.send_block(client, &state, request).await?,
+                        None if active.elapsed() >= IDLE_TIMEOUT => {
+                            return Err(anyhow!("peer is idle"))
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+            };
+
+            active = Instant::now();
+            let message = match message {
+                Some(message) => message,
+                None => continue,
+            };
+            match message.id {
+                MESSAGE_INTERESTED => {
+                    client.read_interested();
+                    self.choker.interested(&state);
+                }
+                MESSAGE_NOTINTERSETED => {
+                    client.read_not_interested();
+                    self.choker.not_interested(&state);
+                }
+                MESSAGE_REQUEST => {
+                    let request = client.read_block_request(message)?;
+                    self.check_request(&request)?;
+                    // 阻塞对方时丢弃请求
+                    if !client.am_choking() && requests.len() < NB_QUEUED_MAX {
+                        requests.push_back(request);
+                    }
+                }
+                MESSAGE_CANCEL => {
+                    let cancel = client.read_block_request(message)?;
+                    requests.retain(|request| *request != cancel);
+                }
+                MESSAGE_HAVE => {
+                    client.read_have(message)?;
+                }
+                MESSAGE_EXTENDED => client.read_extended(message).await?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// 已经写入磁盘的piece
+    pub fn have(&self) -> Bitfield {
+        self.have.read().unwrap().clone()
+    }
+
+    /// 加入 choker,返回的状态用来记录这个连接上的数据量
+    pub fn register(&self) -> Arc<PeerState> {
+        self.choker.register()
+    }
+
+    pub fn choker(&self) -> &Choker {
+        &self.choker
+    }
+
+    /// 在单独的任务中定期重新选择解除阻塞的peer,下载完成后按照上传速度选择
+    pub fn start_choking(&self) -> JoinHandle<()> {
+        let seeder = self.clone();
+        tokio::spawn(async move {
+            let mut rechoked = Instant::now();
+            while !seeder.stopped.load(Ordering::Relaxed) {
+                sleep(STOP_POLL_INTERVAL).await;
+                if rechoked.elapsed() >= CHOKE_INTERVAL {
+                    let seeding = seeder.have.read().unwrap().is_complete();
+                    seeder.choker.rechoke(seeding);
+                    rechoked = Instant::now();
+                }
+            }
+        })
+    }
+
+    /// 通知对方 sent 之后新下载的piece
+    pub async fn send_haves(&self, client: &mut Client, sent: &mut Bitfield) -> Result<()> {
+        let have = self.have();
+        if have != *sent {
+            for index in (0..have.len()).filter(|i| have.has(*i) && !sent.has(*i)) {
+                client.send_have(index as u32).await?;
+            }
+            *sent = have;
+        }
+        Ok(())
+    }
+
+    /// 按照 choker 的决定阻塞或者解除阻塞对方,返回是否新阻塞了对方
+    pub async fn update_choking(&self, client: &mut Client, state: &PeerState) -> Result<bool> {
+        match (state.is_unchoked(), client.am_choking()) {
+            (true, true) => client.send_unchoke().await?,
+            (false, false) => {
+                client.send_choke().await?;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// 立即响应对方的请求,阻塞对方时忽略
+    pub async fn serve_request(
+        &self,
+        client: &mut Client,
+        state: &PeerState,
+        request: BlockRequest,
+    ) -> Result<()> {
+        self.check_request(&request)?;
+        if client.am_choking() {
+            return Ok(());
+        }
+        self.send_block(client, state, request).await
+    }
+
+    /// 检查请求的块是否在piece的范围内
+    fn check_request(&self, request: &BlockRequest) -> Result<()> {
+        let pieces = self.have.read().unwrap().len() as u64;
+        let index = request.index as u64;
+        let begin = index * self.piece_length as u64;
+        let piece_length = (self.length - begin.min(self.length)).min(self.piece_length as u64);
+        if index >= pieces
+            || request.length == 0
+            || request.length > REQUEST_LENGTH_MAX
+            || request.begin as u64 + request.length as u64 > piece_length
+        {
+            return Err(anyhow!("received invalid MESSAGE_REQUEST from peer"));
+        }
+        Ok(())
+    }
+
+    /// 从磁盘读取请求的块并发送,没有这个piece时忽略
+    async fn send_block(
+        &self,
+        client: &mut Client,
+        state: &PeerState,
+        request: BlockRequest,
+    ) -> Result<()> {
+        if !self.have.read().unwrap().has(request.index as usize) {
+            return Ok(());
+        }
+        let begin = request.index as u64 * self.piece_length as u64 + request.begin as u64;
+        let block = self.storage.read(begin, request.length as u64)?;
+        client
+            .send_piece(request.index, request.begin, &block)
+            .await?;
+        self.stats.add_uploaded(request.length as u64);
+        state.add_uploaded(request.length as u64);
+        Ok(())
+    }
+}
+
+/// 双栈监听时IPv4的地址会映射为IPv6地址,转换回IPv4
+fn to_canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod seeder_test {
+    use super::*;
+    use crate::bittorrent::files::TorrentFile;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_seed_limit() {
+        let limit = SeedLimit::default();
+        assert!(!limit.is_reached(u64::MAX, 10, Duration::MAX));
+        let limit = SeedLimit {
+            ratio: Some(1.5),
+            time: Some(Duration::from_secs(60)),
+        };
+        assert!(!limit.is_reached(14, 10, Duration::from_secs(59)));
+        assert!(limit.is_reached(15, 10, Duration::from_secs(0)));
+        assert!(limit.is_reached(0, 10, Duration::from_secs(60)));
+        let limit = SeedLimit {
+            ratio: Some(0.0),
+            time: None,
+        };
+        assert!(limit.is_reached(0, 10, Duration::from_secs(0)));
+    }
+
+    /// 通过回环地址连接seeder下载块,取消的请求不会被发送
+    #[tokio::test]
+    async fn test_serve_blocks() {
+        let dir = std::env::temp_dir().join(format!("seeder_test_{}", std::process::id()));
+        let data: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let files = vec![TorrentFile {
+            path: PathBuf::from("data"),
+            length: data.len() as u64,
+            md5sum: None,
+            offset: 0,
+        }];
+        let storage = Arc::new(Storage::open(&files, vec![dir.join("data")], 32768).unwrap());
+        storage.write(0, &data).unwrap();
+        let mut have = Bitfield::new(2);
+        have.set(0);
+        have.set(1);
+        let info_hash = vec![7; 20];
+        let stats = Arc::new(TransferStats::new(0));
+        let seeder = Seeder::new(
+            info_hash.clone(),
+            vec![1; 20],
+            vec![],
+            storage,
+            Arc::new(RwLock::new(have)),
+            32768,
+            data.len() as u64,
+            stats.clone(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = seeder.clone();
+        let accepting = tokio::spawn(async move { server.accept(listener).await });
+
+        let peer = Peer {
+            id: 0,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        };
+        let mut client = Client::new(peer, vec![2; 20], info_hash).await.unwrap();
+        client.handshake_with_peer().await.unwrap();
+        assert!(client.read_bitfield(2).await.unwrap().is_none());
+        assert!(client.has_piece(0) && client.has_piece(1));
+        // 有空闲位置时表示感兴趣后立即解除阻塞
+        client.send_interested().await.unwrap();
+        while client.read_message().await.unwrap().id != MESSAGE_UNCHOKE {}
+
+        client.send_request(0, 16384, 16384).await.unwrap();
+        client.send_request(1, 0, 4096).await.unwrap();
+        client.send_cancel(1, 0, 4096).await.unwrap();
+        client.send_request(1, 4096, 3136).await.unwrap();
+        let mut blocks = vec![];
+        while blocks.len() < 2 {
+            let message = client.read_message().await.unwrap();
+            if message.id == MESSAGE_PIECE {
+                blocks.push(client.read_block(message).unwrap());
+            }
+        }
+        assert_eq!(blocks[0], (0, 16384, data[16384..32768].to_vec()));
+        assert_eq!(blocks[1], (1, 4096, data[36864..].to_vec()));
+        // 取消的块不会再发送
+        let next = timeout(Duration::from_millis(200), client.read_next_message()).await;
+        assert!(next.is_err());
+        assert_eq!(stats.uploaded.load(Ordering::Relaxed), 16384 + 3136);
+
+        seeder.stop();
+        accepting.await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
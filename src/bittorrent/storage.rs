@@ -0,0 +1,149 @@
+use crate::bittorrent::files::*;
+
+use anyhow::{anyhow, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// 下载文件在磁盘上的存储
+/// 所有文件首尾相接看作一段连续的数据,piece按照偏移写入对应的文件
+pub struct Storage {
+    // 文件列表
+    files: Vec<TorrentFile>,
+    // 文件在本地的路径
+    paths: Vec<PathBuf>,
+    // 打开的文件,读写时需要先移动位置
+    handles: Vec<Mutex<File>>,
+    // 每个piece的大小
+    piece_length: u64,
+}
+
+impl Storage {
+    /// 打开或者新建所有文件并分配空间,已有的数据会保留
+    pub fn open(files: &[TorrentFile], paths: Vec<PathBuf>, piece_length: u32) -> Result<Storage> {
+        let mut handles = vec![];
+        for (file, path) in files.iter().zip(paths.iter()) {
+            if let Some(parent) = path.parent() {
+                if fs::create_dir_all(parent).is_err() {
+                    return Err(anyhow!("could not create directory {:?}", parent));
+                }
+            }
+            let handle = match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+            {
+                Ok(handle) => handle,
+                Err(_) => return Err(anyhow!("could not create file {:?}", path)),
+            };
+            // 预先分配文件大小
+            let allocated = handle.metadata().map(|m| m.len() == file.length);
+            if !allocated.unwrap_or(false) && handle.set_len(file.length).is_err() {
+                return Err(anyhow!("could not allocate file {:?}", path));
+            }
+            handles.push(Mutex::new(handle));
+        }
+        Ok(Storage {
+            files: files.to_vec(),
+            paths,
+            handles,
+            piece_length: piece_length as u64,
+        })
+    }
+
+    /// 本地文件的路径
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// 将校验过的piece写入对应的文件
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        self.write(index as u64 * self.piece_length, data)
+    }
+
+    /// 读取一个piece
+    pub fn read_piece(&self, index: u32, length: u32) -> Result<Vec<u8>> {
+        self.read(index as u64 * self.piece_length, length as u64)
+    }
+
+    /// 从所有文件拼接成的数据中 begin 的位置开始写入
+    pub fn write(&self, begin: u64, data: &[u8]) -> Result<()> {
+        for slice in map_range(&self.files, begin, data.len() as u64) {
+            let start = slice.offset as usize;
+            let end = start + slice.length as usize;
+            let mut handle = self.handles[slice.file].lock().unwrap();
+            if handle.seek(SeekFrom::Start(slice.file_offset)).is_err()
+                || handle.write_all(&data[start..end]).is_err()
+            {
+                return Err(anyhow!(
+                    "could not write data to file {:?}",
+                    self.paths[slice.file]
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 从所有文件拼接成的数据中 begin 的位置开始读取 length 字节
+    pub fn read(&self, begin: u64, length: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; length as usize];
+        for slice in map_range(&self.files, begin, length) {
+            let start = slice.offset as usize;
+            let end = start + slice.length as usize;
+            let mut handle = self.handles[slice.file].lock().unwrap();
+            if handle.seek(SeekFrom::Start(slice.file_offset)).is_err()
+                || handle.read_exact(&mut data[start..end]).is_err()
+            {
+                return Err(anyhow!(
+                    "could not read data from file {:?}",
+                    self.paths[slice.file]
+                ));
+            }
+        }
+        Ok(data)
+    }
+
+    /// 将缓冲的数据写入磁盘
+    pub fn flush(&self) -> Result<()> {
+        for (handle, path) in self.handles.iter().zip(self.paths.iter()) {
+            if handle.lock().unwrap().sync_all().is_err() {
+                return Err(anyhow!("could not flush file {:?}", path));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod storage_test {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_across_files() {
+        let dir = std::env::temp_dir().join("storage_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut files = vec![
+            TorrentFile::new(PathBuf::from("a"), 5, None),
+            TorrentFile::new(PathBuf::from("b"), 0, None),
+            TorrentFile::new(PathBuf::from("c"), 7, None),
+        ];
+        layout(&mut files);
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .map(|f| dir.join("sub").join(&f.path))
+            .collect();
+        let storage = Storage::open(&files, paths.clone(), 4).unwrap();
+        // 第二个piece跨越了第一个和第三个文件
+        storage.write_piece(1, b"4567").unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(2, b"89ab").unwrap();
+        assert_eq!(storage.read_piece(1, 4).unwrap(), b"4567");
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"01234");
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"");
+        assert_eq!(fs::read(&paths[2]).unwrap(), b"56789ab");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
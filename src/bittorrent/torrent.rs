@@ -1,96 +1,164 @@
+use crate::bittorrent::bencode::{decode, decode_with_span, Value};
+use crate::bittorrent::bitfield::Bitfield;
+use crate::bittorrent::connection::ConnectionManager;
+use crate::bittorrent::dht::{find_peers, DhtConfig};
+use crate::bittorrent::files::*;
+use crate::bittorrent::magnet::Magnet;
+use crate::bittorrent::metadata::fetch_metadata_from_peers;
 use crate::bittorrent::peer::*;
+use crate::bittorrent::picker::PiecePicker;
 use crate::bittorrent::piece::*;
+use crate::bittorrent::resume::{recheck, Resume};
+use crate::bittorrent::seeder::{SeedLimit, Seeder};
+use crate::bittorrent::storage::Storage;
+use crate::bittorrent::tracker::{Event, TrackerList, TrackerSession, TransferStats};
 use crate::bittorrent::worker::*;
 
 use anyhow::{anyhow, Result};
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
+use crossbeam_channel::unbounded;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::Rng;
-use serde::{Deserialize, Serialize};
-use serde_bencode::{de, ser};
-use serde_bytes::ByteBuf;
-use std::borrow::Cow;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::str;
-use std::thread;
-use std::time::Duration;
-use url::Url;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
 
 const PORT: u16 = 6881; //监听端口
 const SHA1_HASH_SIZE: usize = 20; //SHA1长度
+const UNKNOWN_LEFT: u64 = 16384; //磁力链接不知道文件大小时发给tracker的left
+const RESULT_QUEUE_SIZE: usize = 16; //等待写入磁盘的piece数量
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(5); //保存进度的间隔
+const SEED_POLL_INTERVAL: Duration = Duration::from_millis(500); //做种时检查停止条件的间隔
 
 /// Torrent structure.
 #[derive(Default, Clone)]
 pub struct Torrent {
-    // 主服务器的url
-    announce: String,
+    // tracker 会话,包含分层的tracker列表
+    session: TrackerSession,
     //整个文件的hash校验值
     info_hash: Vec<u8>,
+    // info字典的原始数据
+    info_bytes: Vec<u8>,
     // SHA-1 哈希值列表
     pieces_hashes: Vec<Vec<u8>>,
     // 每个piece的大小
     piece_length: u32,
     // 文件总大小
     length: u64,
-    // 文件名称
+    // 文件名称,多文件时为目录名称
     name: String,
+    // 文件列表,单文件时只有一个
+    files: Vec<TorrentFile>,
+    // 是否是多文件torrent
+    multi_file: bool,
+    // 是否是私有torrent
+    private: bool,
+    // 发布来源
+    source: Option<String>,
+    // 是否忽略保存的进度重新校验
+    recheck: bool,
+    // 优先下载的文件,匹配文件路径的结尾
+    priority_files: Vec<String>,
+    // DHT 配置
+    dht: DhtConfig,
+    // 做种的停止条件
+    seed_limit: SeedLimit,
     // 标识本机的id
     peer_id: Vec<u8>,
     // Peers
     peers: Vec<Peer>,
 }
 
-/// BencodeInfo structure.
-#[derive(Deserialize, Serialize)]
+/// info 字典中的内容
 struct BencodeInfo {
     // 所有piece的hash
-    #[serde(rename = "pieces")]
-    pieces: ByteBuf,
-    #[serde(rename = "piece length")]
+    pieces: Vec<u8>,
+    // 每个piece的大小
     piece_length: u32,
-    #[serde(rename = "length")]
-    length: u64,
+    // 单文件时的文件长度
+    length: Option<u64>,
+    // 单文件时的md5校验值
+    md5sum: Option<String>,
+    // 多文件时的文件列表
+    files: Option<Vec<BencodeFile>>,
     /// 文件名称
-    #[serde(rename = "name")]
     name: String,
+    // 私有torrent只能从tracker获取peer
+    private: bool,
+    // 发布来源
+    source: Option<String>,
 }
 
-/// BencodeTorrent structure.
-#[derive(Deserialize, Serialize)]
-struct BencodeTorrent {
-    #[serde(default)]
-    // URL of the tracker
-    announce: String,
-    info: BencodeInfo,
-}
-
-/// tracker服务器发回的消息
-/// 包含interval和peers
-#[derive(Debug, Deserialize, Serialize)]
-struct BencodeTracker {
-    //告诉客户端间隔多久再向服务端发一次请求
-    interval: u32,
-    // peers 字段包含同伴的 peer_id、ip、port 等信息
-    peers: ByteBuf,
+/// 多文件torrent中的一个文件
+struct BencodeFile {
+    length: u64,
+    // 路径的每一段,最后一段是文件名
+    path: Vec<String>,
+    md5sum: Option<String>,
 }
 
 impl BencodeInfo {
-    /// HASH编码信息得到文件的唯一标识
-    fn hash(&self) -> Result<Vec<u8>> {
-        // 序列化编码信息
-        let buf: Vec<u8> = ser::to_bytes::<BencodeInfo>(self)?;
-        // SHA1加密
-        let mut hasher = Sha1::new();
-        hasher.input(&buf);
-        // 得到加密字符串
-        let hex = hasher.result_str();
-        // 将字符串解码为二进制
-        let decoded: Vec<u8> = hex::decode(hex)?;
-        Ok(decoded)
+    /// 从解码后的 info 字典中读取需要的字段
+    fn from_value(info: &Value) -> Result<BencodeInfo> {
+        let int = |value: &Value, key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_int())
+                .ok_or_else(|| anyhow!("torrent info is missing {:?}", key))
+        };
+        let files = match info.get("files") {
+            Some(files) => {
+                let files = files
+                    .as_list()
+                    .ok_or_else(|| anyhow!("torrent info has an invalid file list"))?;
+                let files = files
+                    .iter()
+                    .map(|file| {
+                        let path = file
+                            .get("path")
+                            .and_then(|path| path.as_list())
+                            .ok_or_else(|| anyhow!("torrent file is missing \"path\""))?
+                            .iter()
+                            .map(|component| component.as_str().unwrap_or_default())
+                            .collect();
+                        Ok(BencodeFile {
+                            length: int(file, "length")? as u64,
+                            path,
+                            md5sum: file.get("md5sum").and_then(|v| v.as_str()),
+                        })
+                    })
+                    .collect::<Result<Vec<BencodeFile>>>()?;
+                Some(files)
+            }
+            None => None,
+        };
+        Ok(BencodeInfo {
+            pieces: info
+                .get("pieces")
+                .and_then(|v| v.as_bytes())
+                .ok_or_else(|| anyhow!("torrent info is missing \"pieces\""))?
+                .to_vec(),
+            piece_length: int(info, "piece length")? as u32,
+            length: info
+                .get("length")
+                .and_then(|v| v.as_int())
+                .map(|v| v as u64),
+            md5sum: info.get("md5sum").and_then(|v| v.as_str()),
+            files,
+            name: info
+                .get("name")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("torrent info is missing \"name\""))?,
+            private: info.get("private").and_then(|v| v.as_int()) == Some(1),
+            source: info.get("source").and_then(|v| v.as_str()),
+        })
     }
 
     /// 将所有piece的hash校验值切分
@@ -112,6 +180,36 @@ impl BencodeInfo {
 
         Ok(hashes)
     }
+
+    /// 生成文件列表,返回是否是多文件torrent
+    fn build_files(&self) -> Result<(Vec<TorrentFile>, bool)> {
+        let (mut files, multi_file) = match (&self.files, self.length) {
+            (Some(files), _) => {
+                let files = files
+                    .iter()
+                    .map(|file| {
+                        Ok(TorrentFile::new(
+                            build_path(&file.path)?,
+                            file.length,
+                            file.md5sum.clone(),
+                        ))
+                    })
+                    .collect::<Result<Vec<TorrentFile>>>()?;
+                (files, true)
+            }
+            (None, Some(length)) => (
+                vec![TorrentFile::new(
+                    PathBuf::from(sanitize_component(&self.name)),
+                    length,
+                    self.md5sum.clone(),
+                )],
+                false,
+            ),
+            (None, None) => return Err(anyhow!("torrent has neither length nor files")),
+        };
+        layout(&mut files);
+        Ok((files, multi_file))
+    }
 }
 
 impl Torrent {
@@ -130,10 +228,10 @@ impl Torrent {
         if file.read_to_end(&mut buf).is_err() {
             return Err(anyhow!("could not read torrent"));
         }
-        // 从缓冲区内容序列化
-        let bencode = match de::from_bytes::<BencodeTorrent>(&buf) {
-            Ok(bencode) => bencode,
-            Err(_) => return Err(anyhow!("could not decode torrent")),
+        // 解码torrent文件,同时记录info字典的原始数据
+        let (bencode, span) = match decode_with_span(&buf, "info") {
+            Ok((bencode, Some(span))) => (bencode, span),
+            _ => return Err(anyhow!("could not decode torrent")),
         };
 
         // 生成一个随机peer
@@ -144,156 +242,326 @@ impl Torrent {
         }
 
         // 设置所有的信息
-        self.announce = bencode.announce.to_owned();
-        self.info_hash = bencode.info.hash()?;
-        self.pieces_hashes = bencode.info.split_pieces_hashes()?;
-        self.piece_length = bencode.info.piece_length;
-        self.length = bencode.info.length;
-        self.name = bencode.info.name.to_owned();
+        let announce = bencode
+            .get("announce")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let announce_list: Vec<Vec<String>> = bencode
+            .get("announce-list")
+            .and_then(|v| v.as_list())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|tier| tier.as_list())
+            .map(|tier| tier.iter().filter_map(|url| url.as_str()).collect())
+            .collect();
+        self.set_info(&buf[span])?;
         self.peer_id = peer_id.clone();
-        self.peers = self.request_peers(peer_id, PORT).await.unwrap();
+        self.session = TrackerSession::new(
+            TrackerList::from_torrent(&announce, announce_list),
+            self.info_hash.clone(),
+            peer_id,
+            PORT,
+        );
+
+        // tracker失效或者没有tracker时仍然可以通过DHT获取peer
+        let mut peers = self.announce_started().await;
+        merge_peers(&mut peers, self.request_dht_peers().await);
+        if peers.is_empty() {
+            return Err(anyhow!("could not get peers from tracker or dht"));
+        }
+        self.peers = peers;
         Ok(self.length)
     }
 
-    /// 向track服务器发送请求获取所有peer的信息
-    async fn request_peers(&self, peer_id: Vec<u8>, port: u16) -> Result<Vec<Peer>> {
-        // 建立请求url
-        let tracker_url = match self.build_tracker_url(peer_id, port) {
-            Ok(url) => url,
-            Err(_) => return Err(anyhow!("could not build tracker url")),
-        };
+    /// 打开磁力链接,从tracker获取peer后向peer请求info字典
+    pub async fn open_magnet(&mut self, uri: &str) -> Result<u64> {
+        let magnet = Magnet::parse(uri)?;
+        if magnet.trackers.is_empty() && !self.dht.enabled {
+            return Err(anyhow!("magnet link has no trackers"));
+        }
 
-        // 建立http客户端
-        let client = match reqwest::Client::builder()
-            .timeout(Duration::from_secs(15))
-            .build()
-        {
-            Ok(client) => client,
-            Err(_) => return Err(anyhow!("could not connect to tracker")),
-        };
+        // 生成一个随机peer
+        let mut peer_id: Vec<u8> = vec![0; 20];
+        let mut rng = rand::thread_rng();
+        for x in peer_id.iter_mut() {
+            *x = rng.gen();
+        }
+        self.peer_id = peer_id.clone();
+        self.info_hash = magnet.info_hash.clone();
+        // 还不知道文件大小,left 不能为0,否则tracker会认为我们是做种者
+        self.length = magnet.length.unwrap_or(UNKNOWN_LEFT);
+
+        // 磁力链接中的tracker相互独立,每个tracker作为一层
+        let trackers = TrackerList::new(
+            magnet
+                .trackers
+                .iter()
+                .map(|tracker| vec![tracker.clone()])
+                .collect(),
+        );
+        self.session = TrackerSession::new(trackers, self.info_hash.clone(), peer_id.clone(), PORT);
+        let mut peers = self.announce_started().await;
+        merge_peers(&mut peers, self.request_dht_peers().await);
+        if peers.is_empty() {
+            return Err(anyhow!("could not get peers from any tracker or dht"));
+        }
 
-        // 发送请求
-        let response = match client.get(&tracker_url).send().await {
-            Ok(response) => match response.bytes().await {
-                Ok(bytes) => bytes,
-                Err(_) => return Err(anyhow!("could not read response from tracker")),
-            },
-            Err(_) => return Err(anyhow!("could not send request to tracker")),
-        };
+        // 从peer获取info字典
+        println!(
+            "Fetching metadata of {:?} from {} peers",
+            magnet.name.as_deref().unwrap_or("magnet"),
+            peers.len()
+        );
+        let raw_info = fetch_metadata_from_peers(&peers, &peer_id, &magnet.info_hash).await?;
+        self.set_info(&raw_info)?;
+        if self.info_hash != magnet.info_hash {
+            return Err(anyhow!("metadata does not match the magnet info hash"));
+        }
+        self.peers = peers;
+        Ok(self.length)
+    }
 
-        // 反序列化返回的信息
-        // println!("{:?}",response);
-        let tracker_bencode = match de::from_bytes::<BencodeTracker>(&response) {
-            Ok(bencode) => bencode,
-            Err(_) => return Err(anyhow!("could not decode tracker response")),
-        };
+    /// 将info字典保存为torrent文件
+    pub fn save_torrent(&self, path: &str) -> Result<()> {
+        // info字典使用原始数据,保证info_hash不变
+        let mut buf: Vec<u8> = b"d".to_vec();
+        if let Some(announce) = self.session.trackers().first() {
+            buf.extend(b"8:announce");
+            buf.extend(Value::Bytes(announce.as_bytes().to_vec()).encode());
+        }
+        let tiers = self.session.trackers().tiers();
+        if tiers.iter().map(|tier| tier.len()).sum::<usize>() > 1 {
+            let announce_list = tiers
+                .iter()
+                .map(|tier| {
+                    let urls = tier.iter().map(|url| Value::Bytes(url.as_bytes().to_vec()));
+                    Value::List(urls.collect())
+                })
+                .collect();
+            buf.extend(b"13:announce-list");
+            buf.extend(Value::List(announce_list).encode());
+        }
+        buf.extend(b"4:info");
+        buf.extend(&self.info_bytes);
+        buf.push(b'e');
+        if std::fs::write(path, buf).is_err() {
+            return Err(anyhow!("could not save torrent to {:?}", path));
+        }
+        Ok(())
+    }
 
-        // 建立peers信息
-        let peers: Vec<Peer> = match self.build_peers(tracker_bencode.peers.to_vec()) {
-            Ok(peers) => peers,
-            Err(_) => return Err(anyhow!("could not build peers")),
-        };
-        Ok(peers)
+    /// 使用info字典的原始数据设置文件信息
+    /// info_hash 是原始数据的SHA1值,重新编码可能会改变内容
+    fn set_info(&mut self, raw_info: &[u8]) -> Result<()> {
+        let info = BencodeInfo::from_value(&decode(raw_info)?)?;
+        let (mut files, multi_file) = info.build_files()?;
+        let pieces_hashes = info.split_pieces_hashes()?;
+        let length = layout(&mut files);
+        // piece的数量必须和总长度一致,否则计算piece长度时会越界
+        if info.piece_length == 0 {
+            return Err(anyhow!("torrent has an invalid piece length"));
+        }
+        if pieces_hashes.len() as u64 != length.div_ceil(info.piece_length as u64) {
+            return Err(anyhow!("torrent piece count does not match its length"));
+        }
+        self.info_hash = sha1(raw_info);
+        self.info_bytes = raw_info.to_vec();
+        self.pieces_hashes = pieces_hashes;
+        self.piece_length = info.piece_length;
+        self.length = length;
+        self.files = files;
+        self.multi_file = multi_file;
+        self.name = info.name;
+        self.private = info.private;
+        self.source = info.source;
+        Ok(())
     }
 
-    /// 构建 tracker URL.
-    fn build_tracker_url(&self, peer_id: Vec<u8>, port: u16) -> Result<String> {
-        // 解析文件中的tracker url
-        let mut base_url = match Url::parse(&self.announce) {
-            Ok(url) => url,
-            Err(_) => return Err(anyhow!("could not parse tracker url")),
-        };
+    /// 向tracker发送 started,返回所有回复中的peer
+    async fn announce_started(&mut self) -> Vec<Peer> {
+        let mut session = self.session.clone();
+        let stats = TransferStats::new(self.length);
+        let announced = tokio::task::spawn_blocking(move || {
+            let peers = session.announce(Event::Started, &stats);
+            (session, peers)
+        })
+        .await;
+        match announced {
+            Ok((session, peers)) => {
+                self.session = session;
+                peers
+            }
+            Err(_) => vec![],
+        }
+    }
 
-        // 添加参数
-        base_url
-            // Add info hash
-            .query_pairs_mut()
-            .encoding_override(Some(&|input| {
-                if input != "!" {
-                    Cow::Borrowed(input.as_bytes())
-                } else {
-                    Cow::Owned(self.info_hash.clone())
-                }
-            }))
-            .append_pair("info_hash", "!");
-        base_url
-            // Add peer id
-            .query_pairs_mut()
-            .encoding_override(Some(&|input| {
-                if input != "!" {
-                    Cow::Borrowed(input.as_bytes())
-                } else {
-                    Cow::Owned(peer_id.clone())
-                }
-            }))
-            .append_pair("peer_id", "!");
-        base_url
-            .query_pairs_mut()
-            // 添加监听的端口
-            .append_pair("port", &port.to_string())
-            // 添加上传的大小
-            .append_pair("uploaded", "0")
-            // 添加下载的大小
-            .append_pair("downloaded", "0")
-            // 添加compact
-            .append_pair("compact", "1")
-            // 添加仍然需要下载的数量
-            .append_pair("left", &self.length.to_string());
-        Ok(base_url.to_string())
+    /// 通过DHT获取peer,私有torrent只能从tracker获取peer
+    /// 只支持IPv4的DHT(BEP 5),IPv6 的DHT(BEP 32)不在支持范围内
+    async fn request_dht_peers(&self) -> Vec<Peer> {
+        if !self.dht.enabled || self.private {
+            return vec![];
+        }
+        let config = self.dht.clone();
+        let info_hash = self.info_hash.clone();
+        let found =
+            tokio::task::spawn_blocking(move || find_peers(&config, &info_hash, PORT)).await;
+        match found {
+            Ok(Ok(addrs)) => {
+                info!("Found {} peers in dht", addrs.len());
+                addrs
+                    .into_iter()
+                    .map(|addr| Peer {
+                        id: 0,
+                        ip: IpAddr::V4(*addr.ip()),
+                        port: addr.port(),
+                    })
+                    .collect()
+            }
+            Ok(Err(e)) => {
+                info!("Could not get peers from dht: {}", e);
+                vec![]
+            }
+            Err(_) => vec![],
+        }
     }
 
-    /// 下载文件
-    pub fn download(&self) -> Result<Vec<u8>> {
+    /// 下载文件,校验过的piece直接写入 output 下对应的文件
+    /// 返回保存的文件路径
+    pub async fn download(&self, output: &str) -> Result<Vec<PathBuf>> {
         println!(
-            "Downloading {:?} ({:?} pieces)",
+            "Downloading {:?} ({:?} files, {:?} pieces)",
             self.name,
+            self.files.len(),
             self.pieces_hashes.len(),
         );
 
-        // 创建无限容量的channel-->工作channel
-        let work_chan: (Sender<PieceWork>, Receiver<PieceWork>) = unbounded();
-
         // 下载结果channel
-        let result_chan: (Sender<PieceResult>, Receiver<PieceResult>) = unbounded();
+        // 容量有限,写磁盘跟不上时worker会等待,内存中只保留正在下载的piece
+        let (result_tx, mut result_rx): (Sender<PieceResult>, Receiver<PieceResult>) =
+            mpsc::channel(RESULT_QUEUE_SIZE);
+
+        // 读取上次保存的进度,没有时校验已有的文件
+        let paths = local_paths(&self.files, &self.name, self.multi_file, output);
+        let resume = Resume::new(self.resume_path(&paths, output), self.info_hash.clone());
+        let existed = paths.iter().any(|path| path.exists());
+        let saved = match self.recheck {
+            true => None,
+            false => resume.load(&paths, &self.files, self.pieces_hashes.len()),
+        };
 
-        // 新建piece生产者并发送到channel中
-        // 每个piece对应一个生产者
-        for index in 0..self.pieces_hashes.len() {
-            // 创建piece
-            let piece_index = index as u32;
-            let piece_hash = self.pieces_hashes[index].clone();
-            let piece_length = self.get_piece_length(piece_index)?;
-            let piece_work = PieceWork::new(piece_index, piece_hash, piece_length);
-            // 将生产者送入channel中
-            if work_chan.0.send(piece_work).is_err() {
-                return Err(anyhow!("Error: could not send piece to channel"));
+        // 预先分配所有文件
+        let storage = Arc::new(Storage::open(&self.files, paths, self.piece_length)?);
+        let mut bitfield = match saved {
+            Some(bitfield) => bitfield,
+            None if existed => {
+                println!("Checking existing data...");
+                recheck(storage.clone(), self.piece_works()?)
             }
-        }
+            None => Bitfield::new(self.pieces_hashes.len()),
+        };
 
-        // 初始化生产者
-        let peers = self.peers.to_owned();
-        for peer in peers {
-            let peer_copy = peer.clone();
-            let peer_id_copy = self.peer_id.clone(); //本机id
-            let info_hash_copy = self.info_hash.clone();
-            let work_chan_copy = work_chan.clone();
-            let result_chan_copy = result_chan.clone();
-
-            // 对于每个可用的peer，我们都建一个工作者，将其放入工作线程中运行
-            // 创建工作者
-            let worker = Worker::new(
-                peer_copy,
-                peer_id_copy,
-                info_hash_copy,
-                work_chan_copy,
-                result_chan_copy,
-            )?;
+        // 只下载缺少的piece,由picker分配给每个worker
+        let mut downloaded: u64 = 0;
+        let mut missing = vec![];
+        for piece_work in self.piece_works()? {
+            if bitfield.has(piece_work.index as usize) {
+                downloaded += piece_work.length as u64;
+                continue;
+            }
+            missing.push(piece_work);
+        }
+        let picker = PiecePicker::new(self.pieces_hashes.len(), missing);
+        // 指定的文件所在的piece优先下载
+        for name in &self.priority_files {
+            let matched: Vec<&TorrentFile> = self
+                .files
+                .iter()
+                .filter(|file| file.path.ends_with(name))
+                .collect();
+            if matched.is_empty() {
+                println!("No file matches {:?}", name);
+            }
+            for range in matched
+                .into_iter()
+                .filter_map(|file| piece_range(file, self.piece_length))
+            {
+                for index in range {
+                    picker.set_priority(index, 1);
+                }
+            }
+        }
+        if bitfield.count() > 0 {
+            println!(
+                "Resuming with {}/{} pieces verified",
+                bitfield.count(),
+                bitfield.len()
+            );
+        }
+        resume.save(&bitfield)?;
+
+        // 在单独的线程中定期向tracker announce
+        let stats = Arc::new(TransferStats::new(self.length - downloaded));
+        let (event_tx, event_rx) = unbounded();
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let session = self.session.clone();
+        let session_stats = stats.clone();
+        let announcer =
+            tokio::task::spawn_blocking(move || session.run(session_stats, event_rx, peers_tx));
+
+        // 下载和上传的连接共用连接数量的上限
+        let connections = ConnectionManager::default();
+
+        // 在监听端口上向其它peer提供已经下载的piece
+        let have = Arc::new(RwLock::new(bitfield.clone()));
+        let mut seeder = Seeder::new(
+            self.info_hash.clone(),
+            self.peer_id.clone(),
+            if self.private {
+                vec![]
+            } else {
+                self.info_bytes.clone()
+            },
+            storage.clone(),
+            have.clone(),
+            self.piece_length,
+            self.length,
+            stats.clone(),
+        );
+        seeder.set_connections(connections.clone());
+        let choking = seeder.start_choking();
+        let listener = match seeder.listen(PORT).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                warn!("Could not accept connections from peers: {}", e);
+                None
+            }
+        };
+        if bitfield.is_complete() {
+            self.seed(&stats).await;
+            seeder.stop();
+            connections.close();
+            let _ = choking.await;
+            if let Some(listener) = listener {
+                let _ = listener.await;
+            }
+            let _ = event_tx.send(Event::Stopped);
+            let _ = announcer.await;
+            return Ok(storage.paths().to_vec());
+        }
 
-            // 在新的线程工作
-            thread::spawn(move || {
-                worker.start_download();
-            });
+        // 初始化生产者,peers 中只保留还在运行的worker对应的peer
+        // worker结束后从中移除,之后announce再次得到这个peer时可以重新连接
+        let mut peers = self.peers.to_owned();
+        let mut running = FuturesUnordered::new();
+        for (peer, handle) in
+            self.start_workers(peers.clone(), &picker, &result_tx, &seeder, &connections)?
+        {
+            running.push(worker_ended(peer, handle));
         }
+        // 所有worker都结束时立即重新announce,等待announce的结果
+        let mut waiting = false;
+        // 重新announce之后还没有收到任何piece
+        let mut reannounced = false;
 
         // 创建进度条
         let pb = ProgressBar::new(self.length as u64);
@@ -302,32 +570,177 @@ impl Torrent {
                 .template("{spinner:.green} {bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}%")
                 .progress_chars("#>-"),
         );
-
-        // 接收文件内容
-        let mut data: Vec<u8> = vec![0; self.length as usize];
-        let mut nb_pieces_downloaded = 0;
-        while nb_pieces_downloaded < self.pieces_hashes.len() {
-            let piece_result: PieceResult = match result_chan.1.recv() {
-                Ok(piece_result) => piece_result,
-                Err(_) => return Err(anyhow!("Error: could not receive piece from channel")),
+        pb.inc(downloaded);
+
+        // 接收piece并写入文件,定期保存进度
+        let mut saved_at = Instant::now();
+        while !bitfield.is_complete() {
+            if running.is_empty() && !waiting {
+                // 重新announce也没有带来任何进展时放弃下载
+                if reannounced {
+                    return Err(anyhow!("no peers left to download from"));
+                }
+                info!("All peers disconnected, announcing again");
+                let _ = event_tx.send(Event::None);
+                waiting = true;
+                reannounced = true;
+            }
+            let piece_result: PieceResult = tokio::select! {
+                piece_result = result_rx.recv() => match piece_result {
+                    Some(piece_result) => piece_result,
+                    None => return Err(anyhow!("Error: could not receive piece from channel")),
+                },
+                // announce 线程退出后不再有新的peer
+                Some(received) = peers_rx.recv() => {
+                    // 只为新的peer创建工作者
+                    waiting = false;
+                    let added = merge_peers(&mut peers, received);
+                    for (peer, handle) in
+                        self.start_workers(added, &picker, &result_tx, &seeder, &connections)?
+                    {
+                        running.push(worker_ended(peer, handle));
+                    }
+                    continue;
+                }
+                Some(ended) = running.next() => {
+                    peers.retain(|peer| peer.ip != ended.ip || peer.port != ended.port);
+                    continue;
+                }
             };
+            reannounced = false;
 
-            let begin: u32 = piece_result.index * self.piece_length;
-            for i in 0..piece_result.length as usize {
-                data[begin as usize + i] = piece_result.data[i];
-            }
+            storage.write_piece(piece_result.index, &piece_result.data)?;
+            bitfield.set(piece_result.index as usize);
+            have.write().unwrap().set(piece_result.index as usize);
+            stats.add_downloaded(piece_result.length as u64);
             // 更新进度条
             pb.inc(piece_result.length as u64);
-            // 更新下载的内容
-            nb_pieces_downloaded += 1;
+            if saved_at.elapsed() >= RESUME_SAVE_INTERVAL {
+                // 保存的进度中只能包含已经写入磁盘的piece
+                storage.flush()?;
+                resume.save(&bitfield)?;
+                saved_at = Instant::now();
+            }
+        }
+        storage.flush()?;
+        resume.save(&bitfield)?;
+        pb.finish();
+
+        // 通知tracker下载完成,做种结束后退出
+        let _ = event_tx.send(Event::Completed);
+        self.seed(&stats).await;
+        seeder.stop();
+        connections.close();
+        let _ = choking.await;
+        if let Some(listener) = listener {
+            let _ = listener.await;
+        }
+        let _ = event_tx.send(Event::Stopped);
+        let _ = announcer.await;
+        Ok(storage.paths().to_vec())
+    }
+
+    /// 下载完成后继续做种,直到达到停止条件或者按下 Ctrl-C
+    async fn seed(&self, stats: &TransferStats) {
+        let started = Instant::now();
+        let uploaded = || stats.uploaded.load(Ordering::Relaxed);
+        if self
+            .seed_limit
+            .is_reached(uploaded(), self.length, Duration::ZERO)
+        {
+            return;
+        }
+        println!("Seeding {:?}, press Ctrl-C to stop", self.name);
+
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+        while !self
+            .seed_limit
+            .is_reached(uploaded(), self.length, started.elapsed())
+        {
+            tokio::select! {
+                _ = &mut ctrl_c => break,
+                _ = tokio::time::sleep(SEED_POLL_INTERVAL) => {}
+            }
+        }
+        println!("Uploaded {} bytes", uploaded());
+    }
+
+    /// 对于每个可用的peer，我们都建一个工作者，交给连接管理排队连接
+    /// 返回每个peer和对应的任务,任务结束表示和这个peer的连接已经断开
+    fn start_workers(
+        &self,
+        peers: Vec<Peer>,
+        picker: &PiecePicker,
+        result_chan: &Sender<PieceResult>,
+        seeder: &Seeder,
+        connections: &ConnectionManager,
+    ) -> Result<Vec<(Peer, JoinHandle<()>)>> {
+        let mut handles = vec![];
+        for peer in peers {
+            // 创建工作者,同时向对方上传已经下载的piece
+            let mut worker = Worker::new(
+                peer.clone(),
+                self.peer_id.clone(),
+                self.info_hash.clone(),
+                picker.clone(),
+                result_chan.clone(),
+            )?;
+            worker.set_seeder(seeder.clone());
+
+            // 连接数量达到上限时等待其它连接关闭
+            handles.push((peer, connections.connect(worker)));
         }
-        Ok(data)
+        Ok(handles)
+    }
+
+    /// 所有piece的下载任务
+    fn piece_works(&self) -> Result<Vec<PieceWork>> {
+        let mut works = vec![];
+        for index in 0..self.pieces_hashes.len() {
+            let piece_index = index as u32;
+            let piece_hash = self.pieces_hashes[index].clone();
+            let piece_length = self.get_piece_length(piece_index)?;
+            works.push(PieceWork::new(piece_index, piece_hash, piece_length));
+        }
+        Ok(works)
+    }
+
+    /// 保存进度的文件
+    /// 单文件时为 文件名.resume,多文件时为 output/名称.resume
+    fn resume_path(&self, paths: &[PathBuf], output: &str) -> PathBuf {
+        if self.multi_file {
+            Path::new(output).join(format!("{}.resume", sanitize_component(&self.name)))
+        } else {
+            let mut path = paths[0].clone().into_os_string();
+            path.push(".resume");
+            PathBuf::from(path)
+        }
+    }
+
+    /// 设置DHT的引导节点和路由表文件,私有torrent不会使用DHT
+    pub fn set_dht(&mut self, dht: DhtConfig) {
+        self.dht = dht;
+    }
+
+    pub fn set_seed_limit(&mut self, seed_limit: SeedLimit) {
+        self.seed_limit = seed_limit;
+    }
+
+    /// 设置是否忽略保存的进度,重新校验所有已有的数据
+    pub fn set_recheck(&mut self, recheck: bool) {
+        self.recheck = recheck;
+    }
+
+    /// 设置优先下载的文件,这些文件所在的piece不论稀有程度都先下载
+    pub fn set_priority_files(&mut self, priority_files: Vec<String>) {
+        self.priority_files = priority_files;
     }
 
     /// 获取piece长度
     /// 主要是为了防止最后一个piece长度与文件中的不一样
     fn get_piece_length(&self, index: u32) -> Result<u32> {
-        let begin: u64 = (index * self.piece_length) as u64;
+        let begin: u64 = index as u64 * self.piece_length as u64;
         let mut end: u64 = begin + self.piece_length as u64;
         if end > self.length {
             end = self.length;
@@ -335,4 +748,59 @@ impl Torrent {
 
         Ok((end - begin) as u32)
     }
+
+    /// 文件列表
+    pub fn files(&self) -> &[TorrentFile] {
+        &self.files
+    }
+
+    /// 是否是私有torrent,私有torrent不使用DHT等方式获取peer
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// 发布来源
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+}
+
+/// 等待worker的任务结束,返回对应的peer
+async fn worker_ended(peer: Peer, handle: JoinHandle<()>) -> Peer {
+    let _ = handle.await;
+    peer
+}
+
+#[cfg(test)]
+mod torrent_test {
+    use super::Torrent;
+
+    /// 生成单文件的info字典
+    fn info(length: u64, piece_length: u32, nb_pieces: usize) -> Vec<u8> {
+        let mut info = format!(
+            "d6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces{}:",
+            length,
+            piece_length,
+            nb_pieces * 20
+        )
+        .into_bytes();
+        info.extend(vec![0; nb_pieces * 20]);
+        info.push(b'e');
+        info
+    }
+
+    #[test]
+    fn test_set_info() {
+        let mut torrent = Torrent::new();
+        torrent.set_info(&info(40000, 16384, 3)).unwrap();
+        assert_eq!(torrent.get_piece_length(2).unwrap(), 40000 - 2 * 16384);
+    }
+
+    #[test]
+    fn test_set_info_invalid_pieces() {
+        let mut torrent = Torrent::new();
+        assert!(torrent.set_info(&info(40000, 0, 3)).is_err());
+        assert!(torrent.set_info(&info(40000, 16384, 2)).is_err());
+        assert!(torrent.set_info(&info(40000, 16384, 4)).is_err());
+    }
 }
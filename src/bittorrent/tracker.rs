@@ -0,0 +1,618 @@
+use crate::bittorrent::bencode::{decode, Value};
+use crate::bittorrent::peer::*;
+use crate::bittorrent::udp_tracker::UdpTracker;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use rand::seq::SliceRandom;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+use url::Url;
+
+// tracker没有返回间隔时使用的announce间隔,单位为秒
+const DEFAULT_INTERVAL: u32 = 1800;
+// announce间隔的下限,防止tracker返回过小的值
+const INTERVAL_MIN: u32 = 5;
+// HTTP tracker 的超时时间
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+// UDP tracker 的重传次数,避免在失效的tracker上等待太久
+const UDP_RETRIES: u32 = 2;
+
+/// announce 时通知tracker的事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    // 定期的announce
+    None,
+    // 下载完成
+    Completed,
+    // 开始下载
+    Started,
+    // 停止下载
+    Stopped,
+}
+
+/// 向tracker发送的announce请求
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    // 已经下载的字节数
+    pub downloaded: u64,
+    // 仍然需要下载的字节数
+    pub left: u64,
+    // 已经上传的字节数
+    pub uploaded: u64,
+    pub event: Event,
+    // 监听的端口
+    pub port: u16,
+}
+
+/// tracker返回的announce结果
+#[derive(Clone)]
+pub struct AnnounceResponse {
+    // 间隔多久再announce一次,单位为秒
+    pub interval: u32,
+    // 正在下载的peer数量
+    pub leechers: u32,
+    // 做种的peer数量
+    pub seeders: u32,
+    pub peers: Vec<Peer>,
+    // 两次announce之间的最短间隔
+    pub min_interval: Option<u32>,
+    // tracker返回的警告
+    pub warning: Option<String>,
+    // 之后的announce需要带上的 tracker id
+    pub tracker_id: Option<String>,
+}
+
+/// tracker返回的scrape结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeResponse {
+    pub seeders: u32,
+    // 已经完成下载的次数
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// 分层的tracker列表(BEP 12)
+/// 每一层中的tracker顺序随机,有回复的tracker会被移到所在层的最前面
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerList {
+    /// 使用 announce-list 建立,去掉重复的tracker和空的层
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut seen: Vec<String> = vec![];
+        let mut rng = rand::thread_rng();
+        let mut list = vec![];
+        for tier in tiers {
+            let mut tier: Vec<String> = tier
+                .into_iter()
+                .filter(|url| {
+                    let new = !url.is_empty() && !seen.contains(url);
+                    if new {
+                        seen.push(url.clone());
+                    }
+                    new
+                })
+                .collect();
+            if !tier.is_empty() {
+                tier.shuffle(&mut rng);
+                list.push(tier);
+            }
+        }
+        TrackerList { tiers: list }
+    }
+
+    /// torrent中有 announce-list 时忽略 announce
+    pub fn from_torrent(announce: &str, announce_list: Vec<Vec<String>>) -> Self {
+        let list = TrackerList::new(announce_list);
+        if list.is_empty() {
+            TrackerList::new(vec![vec![announce.to_string()]])
+        } else {
+            list
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// 第一个tracker,用作 announce
+    pub fn first(&self) -> Option<&str> {
+        self.tiers
+            .first()
+            .and_then(|tier| tier.first())
+            .map(|url| url.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// 将有回复的tracker移到所在层的最前面
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        if let Some(tier) = self.tiers.get_mut(tier) {
+            if index < tier.len() {
+                let url = tier.remove(index);
+                tier.insert(0, url);
+            }
+        }
+    }
+}
+
+/// 下载过程中的统计,由下载和上传的线程更新
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        TransferStats {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    /// 收到一个校验通过的piece
+    pub fn add_downloaded(&self, length: u64) {
+        self.downloaded.fetch_add(length, Ordering::Relaxed);
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(length))
+            });
+    }
+
+    pub fn add_uploaded(&self, length: u64) {
+        self.uploaded.fetch_add(length, Ordering::Relaxed);
+    }
+}
+
+/// tracker 会话
+/// 第一次announce发送 started,之后按照tracker返回的间隔重新announce,
+/// 下载完成时发送 completed,结束时发送 stopped
+#[derive(Debug, Clone, Default)]
+pub struct TrackerSession {
+    trackers: TrackerList,
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    // 监听的端口
+    port: u16,
+    // 每个tracker返回的 tracker id
+    tracker_ids: HashMap<String, String>,
+    // 每个tracker下一次可以announce的时间
+    next_announce: HashMap<String, Instant>,
+    // 是否已经有tracker收到 started
+    started: bool,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: TrackerList, info_hash: Vec<u8>, peer_id: Vec<u8>, port: u16) -> Self {
+        TrackerSession {
+            trackers,
+            info_hash,
+            peer_id,
+            port,
+            tracker_ids: HashMap::new(),
+            next_announce: HashMap::new(),
+            started: false,
+        }
+    }
+
+    pub fn trackers(&self) -> &TrackerList {
+        &self.trackers
+    }
+
+    /// 距离最早的一个tracker可以announce的时间
+    fn until_next(&self) -> Duration {
+        let now = Instant::now();
+        self.trackers
+            .tiers()
+            .iter()
+            .filter_map(|urls| urls.first())
+            .map(|url| match self.next_announce.get(url) {
+                Some(next) => next.saturating_duration_since(now),
+                None => Duration::ZERO,
+            })
+            .min()
+            .unwrap_or(Duration::from_secs(DEFAULT_INTERVAL as u64))
+    }
+
+    /// 向每一层的tracker announce,返回所有回复中的peer
+    /// 一层中的tracker依次尝试,直到有一个回复,回复的tracker移到这一层的最前面
+    pub fn announce(&mut self, event: Event, stats: &TransferStats) -> Vec<Peer> {
+        self.announce_tiers(event, stats, None)
+    }
+
+    /// due 不为空时只向这个时间已经可以announce的层announce
+    fn announce_tiers(
+        &mut self,
+        event: Event,
+        stats: &TransferStats,
+        due: Option<Instant>,
+    ) -> Vec<Peer> {
+        // 没有tracker收到过 started 时不需要 stopped,定期的announce改为 started
+        let event = match (self.started, event) {
+            (false, Event::Stopped) => return vec![],
+            (false, _) => Event::Started,
+            (true, event) => event,
+        };
+        let request = AnnounceRequest {
+            info_hash: self.info_hash.clone(),
+            peer_id: self.peer_id.clone(),
+            downloaded: stats.downloaded.load(Ordering::Relaxed),
+            left: stats.left.load(Ordering::Relaxed),
+            uploaded: stats.uploaded.load(Ordering::Relaxed),
+            event,
+            port: self.port,
+        };
+        // 退出时不等待没有回复的UDP tracker
+        let retries = if event == Event::Stopped {
+            0
+        } else {
+            UDP_RETRIES
+        };
+
+        let mut peers: Vec<Peer> = vec![];
+        let tiers = self.trackers.tiers().to_vec();
+        for (tier, urls) in tiers.iter().enumerate() {
+            // 每一层只向最前面的tracker定期announce
+            if let (Some(due), Some(first)) = (due, urls.first()) {
+                if self
+                    .next_announce
+                    .get(first)
+                    .is_some_and(|next| *next > due)
+                {
+                    continue;
+                }
+            }
+            for (index, url) in urls.iter().enumerate() {
+                let tracker_id = self.tracker_ids.get(url).map(|id| id.as_str());
+                let response = match announce(url, &request, tracker_id, retries) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        info!("Could not announce to {:?}: {}", url, e);
+                        let next = Instant::now() + Duration::from_secs(DEFAULT_INTERVAL as u64);
+                        self.next_announce.insert(url.clone(), next);
+                        continue;
+                    }
+                };
+                info!("Got {} peers from {:?}", response.peers.len(), url);
+                if let Some(warning) = &response.warning {
+                    warn!("Tracker {:?} returned warning: {}", url, warning);
+                }
+                if let Some(tracker_id) = response.tracker_id {
+                    self.tracker_ids.insert(url.clone(), tracker_id);
+                }
+                // 不能比 min interval 更频繁
+                let next = response
+                    .interval
+                    .max(response.min_interval.unwrap_or(0))
+                    .max(INTERVAL_MIN);
+                let next = Instant::now() + Duration::from_secs(next as u64);
+                self.next_announce.insert(url.clone(), next);
+                merge_peers(&mut peers, response.peers);
+                self.trackers.promote(tier, index);
+                self.started = true;
+                break;
+            }
+        }
+        peers
+    }
+
+    /// 在单独的线程中运行,定期announce并把得到的peer发送到 peers
+    /// peers 的接收端在 tokio 运行时中读取,没有得到peer时也发送空的列表表示announce已经完成
+    /// 收到 None 时立即announce,收到 Completed 时发送 completed
+    /// 收到 Stopped 或者channel关闭时发送 stopped 后退出
+    pub fn run(
+        mut self,
+        stats: Arc<TransferStats>,
+        events: Receiver<Event>,
+        peers: UnboundedSender<Vec<Peer>>,
+    ) {
+        loop {
+            // 定期的announce只发给时间已到的tracker,收到的事件发给所有tracker
+            let (event, due) = match events.recv_timeout(self.until_next()) {
+                Ok(event) => (event, None),
+                Err(RecvTimeoutError::Timeout) => (Event::None, Some(Instant::now())),
+                Err(RecvTimeoutError::Disconnected) => (Event::Stopped, None),
+            };
+            let received = self.announce_tiers(event, &stats, due);
+            if event == Event::Stopped {
+                return;
+            }
+            let _ = peers.send(received);
+        }
+    }
+}
+
+/// 根据url的协议向 HTTP 或者 UDP tracker announce
+pub fn announce(
+    url: &str,
+    request: &AnnounceRequest,
+    tracker_id: Option<&str>,
+    udp_retries: u32,
+) -> Result<AnnounceResponse> {
+    let scheme = match Url::parse(url) {
+        Ok(url) => url.scheme().to_string(),
+        Err(_) => return Err(anyhow!("could not parse tracker url")),
+    };
+    match scheme.as_str() {
+        "http" | "https" => http_announce(url, request, tracker_id),
+        "udp" => {
+            let mut tracker = UdpTracker::new(url)?;
+            tracker.set_max_retries(udp_retries);
+            tracker.announce(request)
+        }
+        _ => Err(anyhow!("unsupported tracker {:?}", url)),
+    }
+}
+
+/// 向HTTP tracker announce
+fn http_announce(
+    url: &str,
+    request: &AnnounceRequest,
+    tracker_id: Option<&str>,
+) -> Result<AnnounceResponse> {
+    // 建立请求url
+    let tracker_url = match build_tracker_url(url, request, tracker_id) {
+        Ok(url) => url,
+        Err(_) => return Err(anyhow!("could not build tracker url")),
+    };
+
+    // 建立http客户端
+    let client = match reqwest::blocking::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(_) => return Err(anyhow!("could not connect to tracker")),
+    };
+
+    // 发送请求
+    let response = match client.get(&tracker_url).send() {
+        Ok(response) => match response.bytes() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("could not read response from tracker")),
+        },
+        Err(_) => return Err(anyhow!("could not send request to tracker")),
+    };
+    parse_http_response(&response)
+}
+
+/// 构建 tracker URL.
+fn build_tracker_url(
+    url: &str,
+    request: &AnnounceRequest,
+    tracker_id: Option<&str>,
+) -> Result<String> {
+    // 解析文件中的tracker url
+    let mut base_url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return Err(anyhow!("could not parse tracker url")),
+    };
+
+    // info_hash 和 peer_id 是二进制数据,需要按字节编码
+    for (key, value) in [
+        ("info_hash", &request.info_hash),
+        ("peer_id", &request.peer_id),
+    ] {
+        base_url
+            .query_pairs_mut()
+            .encoding_override(Some(&|input| {
+                if input != "!" {
+                    Cow::Borrowed(input.as_bytes())
+                } else {
+                    Cow::Owned(value.clone())
+                }
+            }))
+            .append_pair(key, "!");
+    }
+    base_url
+        .query_pairs_mut()
+        // 添加监听的端口
+        .append_pair("port", &request.port.to_string())
+        // 添加上传的大小
+        .append_pair("uploaded", &request.uploaded.to_string())
+        // 添加下载的大小
+        .append_pair("downloaded", &request.downloaded.to_string())
+        // 添加compact
+        .append_pair("compact", "1")
+        // 添加仍然需要下载的数量
+        .append_pair("left", &request.left.to_string());
+    let event = match request.event {
+        Event::None => None,
+        Event::Completed => Some("completed"),
+        Event::Started => Some("started"),
+        Event::Stopped => Some("stopped"),
+    };
+    if let Some(event) = event {
+        base_url.query_pairs_mut().append_pair("event", event);
+    }
+    if let Some(tracker_id) = tracker_id {
+        base_url
+            .query_pairs_mut()
+            .append_pair("trackerid", tracker_id);
+    }
+    Ok(base_url.to_string())
+}
+
+/// 解析HTTP tracker的回复
+/// peers 可以是紧凑格式,也可以是包含 ip 和 port 的字典列表
+fn parse_http_response(buf: &[u8]) -> Result<AnnounceResponse> {
+    let response = match decode(buf) {
+        Ok(response) => response,
+        Err(_) => return Err(anyhow!("could not decode tracker response")),
+    };
+    if let Some(reason) = response.get("failure reason").and_then(|v| v.as_str()) {
+        return Err(anyhow!("tracker returned failure: {}", reason));
+    }
+    let int = |key: &str| {
+        response
+            .get(key)
+            .and_then(|v| v.as_int())
+            .filter(|v| *v >= 0)
+            .map(|v| v.min(u32::MAX as i64) as u32)
+    };
+
+    let mut peers = match response.get("peers") {
+        Some(Value::Bytes(peers)) => parse_compact_peers(peers, false)?,
+        Some(Value::List(peers)) => peers
+            .iter()
+            .filter_map(|peer| {
+                let ip = peer.get("ip")?.as_str()?.parse::<IpAddr>().ok()?;
+                let port = peer.get("port")?.as_int()?;
+                Some(Peer {
+                    id: 0,
+                    ip,
+                    port: u16::try_from(port).ok()?,
+                })
+            })
+            .collect(),
+        _ => vec![],
+    };
+    // peers6 中是IPv6的peer
+    if let Some(peers6) = response.get("peers6").and_then(|v| v.as_bytes()) {
+        merge_peers(&mut peers, parse_compact_peers(peers6, true)?);
+    }
+    Ok(AnnounceResponse {
+        interval: int("interval").unwrap_or(DEFAULT_INTERVAL),
+        leechers: int("incomplete").unwrap_or(0),
+        seeders: int("complete").unwrap_or(0),
+        peers,
+        min_interval: int("min interval"),
+        warning: response.get("warning message").and_then(|v| v.as_str()),
+        tracker_id: response.get("tracker id").and_then(|v| v.as_str()),
+    })
+}
+
+#[cfg(test)]
+mod tracker_test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tracker_list() {
+        let mut list = TrackerList::new(vec![
+            urls(&["http://a", "udp://b", "http://c"]),
+            vec![],
+            urls(&["http://a", "http://d"]),
+        ]);
+        assert_eq!(list.tiers().len(), 2);
+        let mut first = list.tiers()[0].clone();
+        first.sort();
+        assert_eq!(first, urls(&["http://a", "http://c", "udp://b"]));
+        assert_eq!(list.tiers()[1], urls(&["http://d"]));
+
+        let answered = list.tiers()[0][2].clone();
+        list.promote(0, 2);
+        assert_eq!(list.first(), Some(answered.as_str()));
+        assert_eq!(list.tiers()[0].len(), 3);
+
+        // 没有 announce-list 时使用 announce
+        let list = TrackerList::from_torrent("http://a", vec![vec![]]);
+        assert_eq!(list.tiers(), &[urls(&["http://a"])]);
+        assert!(TrackerList::from_torrent("", vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_http_response() {
+        let response = parse_http_response(
+            b"d8:completei3e10:incompletei1e8:intervali900e12:min intervali60e\
+              5:peersld2:ip9:127.0.0.14:porti6881eee10:tracker id3:abc\
+              15:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!((response.interval, response.min_interval), (900, Some(60)));
+        assert_eq!((response.seeders, response.leechers), (3, 1));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].port, 6881);
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        let failure = parse_http_response(b"d14:failure reason7:refusede");
+        assert_eq!(
+            failure.err().unwrap().to_string(),
+            "tracker returned failure: refused"
+        );
+    }
+
+    #[test]
+    fn test_tracker_url() {
+        let request = AnnounceRequest {
+            info_hash: vec![0xff; 20],
+            peer_id: b"-RD0001-000000000000".to_vec(),
+            downloaded: 10,
+            left: 20,
+            uploaded: 30,
+            event: Event::Completed,
+            port: 6881,
+        };
+        let url = build_tracker_url("http://t.example/announce?k=v", &request, Some("id")).unwrap();
+        assert!(url.starts_with("http://t.example/announce?k=v&info_hash=%FF%FF"));
+        assert!(url.contains("&uploaded=30&downloaded=10&compact=1&left=20"));
+        assert!(url.ends_with("&event=completed&trackerid=id"));
+    }
+
+    /// 返回固定间隔的HTTP tracker,记录收到的请求数
+    fn serve_tracker(interval: u32) -> (String, Arc<AtomicU64>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicU64::new(0));
+        let counter = count.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                counter.fetch_add(1, Ordering::Relaxed);
+                let body = format!("d8:intervali{}e5:peers0:e", interval);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, count)
+    }
+
+    #[test]
+    fn test_announce_when_due() {
+        let (fast, fast_count) = serve_tracker(60);
+        let (slow, slow_count) = serve_tracker(900);
+        let trackers = TrackerList::new(vec![vec![fast], vec![slow]]);
+        let mut session = TrackerSession::new(trackers, vec![0; 20], vec![0; 20], 6881);
+        let stats = TransferStats::new(100);
+        session.announce(Event::Started, &stats);
+        // 等待间隔较短的tracker
+        let wait = session.until_next();
+        assert!(wait > Duration::from_secs(50) && wait <= Duration::from_secs(60));
+
+        // 定期announce时间隔较长的tracker还没有到时间
+        let due = Instant::now() + Duration::from_secs(120);
+        session.announce_tiers(Event::None, &stats, Some(due));
+        assert_eq!(fast_count.load(Ordering::Relaxed), 2);
+        assert_eq!(slow_count.load(Ordering::Relaxed), 1);
+
+        // 收到的事件发给所有tracker
+        session.announce(Event::Completed, &stats);
+        assert_eq!(fast_count.load(Ordering::Relaxed), 3);
+        assert_eq!(slow_count.load(Ordering::Relaxed), 2);
+    }
+}
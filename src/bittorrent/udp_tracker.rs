@@ -0,0 +1,337 @@
+use crate::bittorrent::peer::parse_compact_peers;
+use crate::bittorrent::tracker::*;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use url::Url;
+
+// connect 请求使用的固定协议id
+const PROTOCOL_ID: u64 = 0x41727101980;
+// 请求类型
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+// connection id 的有效时间
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// 第n次重传的超时时间为 15 * 2 ^ n 秒
+const TIMEOUT_BASE: Duration = Duration::from_secs(15);
+// 最多重传次数
+const MAX_RETRIES: u32 = 8;
+// 一次scrape最多的info hash数量
+const SCRAPE_MAX: usize = 74;
+
+/// UDP tracker 客户端(BEP 15)
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    // 缓存的 connection id 和获取的时间
+    connection: Option<(u64, Instant)>,
+    // 标识本机的key,tracker用它识别更换了ip的客户端
+    key: u32,
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /// 连接 udp://host:port 形式的tracker
+    pub fn new(announce: &str) -> Result<UdpTracker> {
+        let url = match Url::parse(announce) {
+            Ok(url) if url.scheme() == "udp" => url,
+            _ => return Err(anyhow!("could not parse tracker url")),
+        };
+        let addr = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => (host.trim_matches(|c| c == '[' || c == ']'), port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next()),
+            _ => None,
+        };
+        let addr = addr.ok_or_else(|| anyhow!("could not resolve tracker {:?}", announce))?;
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+        let socket = match UdpSocket::bind(bind).and_then(|s| s.connect(addr).map(|_| s)) {
+            Ok(socket) => socket,
+            Err(_) => return Err(anyhow!("could not connect to tracker")),
+        };
+        Ok(UdpTracker {
+            socket,
+            addr,
+            connection: None,
+            key: rand::thread_rng().gen(),
+            timeout: TIMEOUT_BASE,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    /// 第一次请求的超时时间,之后每次重传加倍
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// 放弃之前最多重传的次数
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// 发送announce,返回tracker分配的peer
+    pub fn announce(&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let event = match request.event {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        };
+        let key = self.key;
+        let response = self.request(ACTION_ANNOUNCE, |buf| {
+            buf.extend_from_slice(&request.info_hash);
+            buf.extend_from_slice(&request.peer_id);
+            buf.extend_from_slice(&request.downloaded.to_be_bytes());
+            buf.extend_from_slice(&request.left.to_be_bytes());
+            buf.extend_from_slice(&request.uploaded.to_be_bytes());
+            buf.extend_from_slice(&(event as u32).to_be_bytes());
+            // ip 为0表示使用发送请求的地址
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            buf.extend_from_slice(&key.to_be_bytes());
+            // num_want 为-1表示使用tracker的默认值
+            buf.extend_from_slice(&(-1i32).to_be_bytes());
+            buf.extend_from_slice(&request.port.to_be_bytes());
+        })?;
+        if response.len() < 12 {
+            return Err(anyhow!("received invalid announce response from tracker"));
+        }
+        // tracker使用IPv6时返回IPv6地址
+        let peers = parse_compact_peers(&response[12..], self.addr.is_ipv6())?;
+        Ok(AnnounceResponse {
+            interval: read_u32(&response, 0),
+            leechers: read_u32(&response, 4),
+            seeders: read_u32(&response, 8),
+            peers,
+            min_interval: None,
+            warning: None,
+            tracker_id: None,
+        })
+    }
+
+    /// 查询每个torrent的做种、下载和完成数量
+    pub fn scrape(&mut self, info_hashes: &[Vec<u8>]) -> Result<Vec<ScrapeResponse>> {
+        if info_hashes.is_empty() || info_hashes.len() > SCRAPE_MAX {
+            return Err(anyhow!("can scrape 1 to {} torrents at once", SCRAPE_MAX));
+        }
+        let response = self.request(ACTION_SCRAPE, |buf| {
+            for info_hash in info_hashes {
+                buf.extend_from_slice(info_hash);
+            }
+        })?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(anyhow!("received invalid scrape response from tracker"));
+        }
+        let stats = response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|chunk| ScrapeResponse {
+                seeders: read_u32(chunk, 0),
+                completed: read_u32(chunk, 4),
+                leechers: read_u32(chunk, 8),
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    /// 获取 connection id,一分钟内重复使用
+    fn connect(&mut self) -> Result<u64> {
+        if let Some((connection_id, time)) = self.connection {
+            if time.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+        for n in 0..=self.max_retries {
+            let transaction_id = rand::thread_rng().gen();
+            let buf = header(PROTOCOL_ID, ACTION_CONNECT, transaction_id);
+            if let Some(response) = self.send(&buf, ACTION_CONNECT, transaction_id, n)? {
+                if response.len() < 8 {
+                    return Err(anyhow!("received invalid connect response from tracker"));
+                }
+                let connection_id = u64::from_be_bytes(response[..8].try_into()?);
+                self.connection = Some((connection_id, Instant::now()));
+                return Ok(connection_id);
+            }
+        }
+        Err(anyhow!("tracker {} did not respond", self.addr))
+    }
+
+    /// 发送请求并等待回复,超时后重传
+    /// 返回去掉 action 和 transaction id 之后的内容
+    fn request<F>(&mut self, action: u32, body: F) -> Result<Vec<u8>>
+    where
+        F: Fn(&mut Vec<u8>),
+    {
+        for n in 0..=self.max_retries {
+            // connection id 可能在重传期间过期
+            let connection_id = self.connect()?;
+            let transaction_id = rand::thread_rng().gen();
+            let mut buf = header(connection_id, action, transaction_id);
+            body(&mut buf);
+            if let Some(response) = self.send(&buf, action, transaction_id, n)? {
+                return Ok(response);
+            }
+        }
+        Err(anyhow!("tracker {} did not respond", self.addr))
+    }
+
+    /// 发送一次请求,等待 timeout * 2 ^ n,超时返回None
+    fn send(
+        &mut self,
+        buf: &[u8],
+        action: u32,
+        transaction_id: u32,
+        n: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.socket.send(buf).is_err() {
+            return Err(anyhow!("could not send request to tracker"));
+        }
+        let deadline = Instant::now() + self.timeout * 2u32.pow(n);
+        let mut response = [0; 65536];
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() || self.socket.set_read_timeout(Some(timeout)).is_err() {
+                return Ok(None);
+            }
+            let len = match self.socket.recv(&mut response) {
+                Ok(len) => len,
+                Err(_) => return Ok(None),
+            };
+            // 忽略其它请求的回复
+            if len < 8 || read_u32(&response, 4) != transaction_id {
+                continue;
+            }
+            match read_u32(&response, 0) {
+                ACTION_ERROR => {
+                    let message = String::from_utf8_lossy(&response[8..len]);
+                    return Err(anyhow!("tracker returned error: {}", message));
+                }
+                received if received == action => return Ok(Some(response[8..len].to_vec())),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// 请求的头部: connection id, action, transaction id
+fn header(connection_id: u64, action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut buf = connection_id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&action.to_be_bytes());
+    buf.extend_from_slice(&transaction_id.to_be_bytes());
+    buf
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod udp_tracker_test {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// 丢弃第一个connect请求的tracker,返回收到的connect请求数量
+    fn mock_tracker() -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+                let action = read_u32(&buf, 8);
+                let mut reply = action.to_be_bytes().to_vec();
+                reply.extend_from_slice(&buf[12..16]);
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(&buf[..8], &PROTOCOL_ID.to_be_bytes());
+                        if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                            continue;
+                        }
+                        reply.extend_from_slice(&0x1234u64.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(len, 98);
+                        assert_eq!(&buf[..8], &0x1234u64.to_be_bytes());
+                        // interval, leechers, seeders, 一个peer
+                        for value in [1800u32, 1, 2] {
+                            reply.extend_from_slice(&value.to_be_bytes());
+                        }
+                        reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                    }
+                    ACTION_SCRAPE => {
+                        for value in [5u32, 10, 3] {
+                            reply.extend_from_slice(&value.to_be_bytes());
+                        }
+                    }
+                    _ => {
+                        reply = ACTION_ERROR.to_be_bytes().to_vec();
+                        reply.extend_from_slice(&buf[12..16]);
+                        reply.extend_from_slice(b"unknown action");
+                    }
+                }
+                socket.send_to(&reply, addr).unwrap();
+            }
+        });
+        (url, connects)
+    }
+
+    #[test]
+    fn test_announce_and_scrape() {
+        let (url, connects) = mock_tracker();
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        tracker.set_timeout(Duration::from_millis(100));
+        let request = AnnounceRequest {
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            downloaded: 0,
+            left: 100,
+            uploaded: 0,
+            event: Event::Started,
+            port: 6881,
+        };
+        let response = tracker.announce(&request).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.seeders, 2);
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(response.peers[0].port, 6881);
+
+        // connection id 被缓存,不需要再次connect
+        let stats = tracker.scrape(&[vec![1; 20]]).unwrap();
+        assert_eq!(stats[0].completed, 10);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        assert!(tracker.request(7, |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_no_response() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}", silent.local_addr().unwrap());
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        tracker.set_timeout(Duration::from_millis(10));
+        tracker.set_max_retries(2);
+        let start = Instant::now();
+        assert!(tracker.scrape(&[vec![1; 20]]).is_err());
+        // 10 + 20 + 40 毫秒
+        assert!(start.elapsed() >= Duration::from_millis(70));
+        assert!(UdpTracker::new("http://tracker.example/announce").is_err());
+    }
+}
@@ -1,26 +1,59 @@
+use crate::bittorrent::bitfield::Bitfield;
+use crate::bittorrent::choker::PeerState;
 use crate::bittorrent::client::*;
 use crate::bittorrent::message::*;
 use crate::bittorrent::peer::*;
+use crate::bittorrent::picker::PiecePicker;
 use crate::bittorrent::piece::*;
+use crate::bittorrent::seeder::Seeder;
 
 use anyhow::{anyhow, Result};
-use crossbeam_channel::{Receiver, Sender};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
 
 // 最大请求数量
 const NB_REQUESTS_MAX: u32 = 5;
 
-//每次请求的大小
-const BLOCK_SIZE_MAX: u32 = 16384;
+// 等待消息的时间,定期检查其它worker的进度
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// 等待对方发送请求的块的最长时间,超时后断开,请求的块交给其它peer
+const PIECE_TIMEOUT: Duration = Duration::from_secs(120);
 
 /// 生产者定义
 pub struct Worker {
     peer: Peer,
     peer_id: Vec<u8>,
     info_hash: Vec<u8>,
-    work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
-    result_chan: (Sender<PieceResult>, Receiver<PieceResult>),
+    // 选择下载的piece
+    picker: PiecePicker,
+    result_chan: Sender<PieceResult>,
+    // 设置后同时向对方上传已经下载的piece
+    seeder: Option<Seeder>,
+    // 等待请求的块的最长时间
+    piece_timeout: Duration,
+}
+
+/// 向对方上传时的状态
+struct Upload {
+    seeder: Seeder,
+    // 在 choker 中的状态
+    state: Arc<PeerState>,
+    // 已经通知对方的piece
+    sent: Bitfield,
+}
+
+impl Upload {
+    /// 通知对方新下载的piece,并按照 choker 的决定阻塞或者解除阻塞对方
+    async fn update(&mut self, client: &mut Client) -> Result<()> {
+        self.seeder.send_haves(client, &mut self.sent).await?;
+        self.seeder.update_choking(client, &self.state).await?;
+        Ok(())
+    }
 }
 
 impl Worker {
@@ -28,42 +61,67 @@ impl Worker {
         peer: Peer,
         peer_id: Vec<u8>,
         info_hash: Vec<u8>,
-        work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
-        result_chan: (Sender<PieceResult>, Receiver<PieceResult>),
+        picker: PiecePicker,
+        result_chan: Sender<PieceResult>,
     ) -> Result<Worker> {
         let worker = Worker {
             peer,
             peer_id,
             info_hash,
-            work_chan,
+            picker,
             result_chan,
+            seeder: None,
+            piece_timeout: PIECE_TIMEOUT,
         };
 
         Ok(worker)
     }
 
-    /// 启动worker.
-    pub fn start_download(&self) {
+    pub fn set_seeder(&mut self, seeder: Seeder) {
+        self.seeder = Some(seeder);
+    }
+
+    /// 设置等待请求的块的最长时间
+    pub fn set_piece_timeout(&mut self, piece_timeout: Duration) {
+        self.piece_timeout = piece_timeout;
+    }
+
+    /// 启动worker,在连接关闭前不会返回
+    pub async fn start_download(&self) {
         let peer_copy = self.peer.clone();
         let peer_id_copy = self.peer_id.clone();
         let info_hash_copy = self.info_hash.clone();
 
         // 建立客户端
-        let mut client = match Client::new(peer_copy, peer_id_copy, info_hash_copy) {
+        let mut client = match Client::new(peer_copy, peer_id_copy, info_hash_copy).await {
             Ok(client) => client,
             Err(_) => return,
         };
 
         // 设置连接超时时间
-        if client.set_connection_timeout(10).is_err() {
-            return;
-        }
+        client.set_connection_timeout(10);
 
         // 第一握手确认
-        if client.handshake_with_peer().is_err() {
+        if client.handshake_with_peer().await.is_err() {
             return;
         }
 
+        // 可以上传时先发送本机已经拥有的piece
+        let mut upload = match &self.seeder {
+            Some(seeder) => {
+                let sent = seeder.have();
+                if sent.count() > 0 && client.send_bitfield(sent.as_bytes()).await.is_err() {
+                    return;
+                }
+                Some(Upload {
+                    seeder: seeder.clone(),
+                    state: seeder.register(),
+                    sent,
+                })
+            }
+            None => None,
+        };
+
         // 握手完成后，双方开始传输内容
         // 此时消息的格式发生了改变
         //消息以长度指示符开头，该指示符告诉我们该消息将有多少字节长，它是一个32位整数，
@@ -71,124 +129,246 @@ impl Worker {
         // 下一个字节，即ID，告诉我们正在接收的消息类型（Message Type）。
         // 最后，可选的有效 payload 将填充消息的剩余长度.
         // 第二次读取peer发送的消息
-        if client.read_bitfield().is_err() {
+        let first = match client.read_bitfield(self.picker.nb_pieces()).await {
+            Ok(first) => first,
+            Err(_) => return,
+        };
+
+        // 发送 Interested 消息，代表自己要开始下载文件了
+        // 是否解除对对方的阻塞由 choker 决定
+        if client.send_interested().await.is_err() {
             return;
         }
 
-        // 本机发送unchoke消息代表自己准备好了，可以进行消息传输了
-        // 握手完成后的刚开始，我们被其他 Peer
-        // 认为状态是阻塞的（chocked）,我们需要发送一条解锁消息
-        if client.send_unchoke().is_err() {
-            return;
+        // 断开连接时从picker中移除这个peer拥有的piece
+        self.picker.add_peer(|index| client.has_piece(index));
+        // 已经请求还没有收到的块,断开时交给其它peer下载
+        let mut pending: Vec<BlockRequest> = vec![];
+        if let Err(e) = self
+            .download(&mut client, &mut upload, &mut pending, first)
+            .await
+        {
+            info!("Stopped downloading from peer {:?}: {}", self.peer.id, e);
         }
+        self.release(&mut pending);
+        self.picker.remove_peer(|index| client.has_piece(index));
+    }
 
-        // 发送 Interested 消息，代表自己要开始下载文件了
-        if client.send_interested().is_err() {
-            return;
+    /// 从对方下载picker分配的块,直到所有piece都下载完成
+    /// 同一个piece的块可能来自多个peer,由picker保存收到的块
+    async fn download(
+        &self,
+        client: &mut Client,
+        upload: &mut Option<Upload>,
+        pending: &mut Vec<BlockRequest>,
+        first: Option<Message>,
+    ) -> Result<()> {
+        // 设置连接超时时间
+        // 对于下载资源来说要较长
+        client.set_connection_timeout(120);
+        // 对方没有发送bitfield时,加入picker之后再处理收到的第一条消息
+        if let Some(message) = first {
+            self.handle_message(client, message, upload).await?;
         }
+        // 最后一次收到请求的块的时间,keep-alive 和 HAVE 等消息不算
+        let mut last_block = Instant::now();
 
         loop {
-            // 从work channel读取piece
-            let mut piece_work: PieceWork = match self.work_chan.1.recv() {
-                Ok(piece_work) => piece_work,
-                Err(_) => {
-                    error!("Error: could not receive piece from channel");
-                    return;
-                }
-            };
-
-            if !client.has_piece(piece_work.index) {
-                // 如果本地没有这个piece就再发送到channel中
-                if self.work_chan.0.send(piece_work).is_err() {
-                    error!("Error: could not send piece to channel");
-                    return;
-                }
-                continue;
+            if self.picker.is_complete() {
+                self.release(pending);
+                return self.seed(client, upload).await;
             }
 
-            // 下载piece
-            if self.download_piece(&mut client, &mut piece_work).is_err() {
-                // Resend piece to work channel
-                if self.work_chan.0.send(piece_work).is_err() {
-                    error!("Error: could not send piece to channel");
-                    return;
-                }
-                return;
+            // 取消其它peer已经发送的块
+            let (needed, received): (Vec<BlockRequest>, Vec<BlockRequest>) = pending
+                .iter()
+                .partition(|request| self.picker.is_block_needed(request));
+            *pending = needed;
+            for request in received {
+                self.picker.cancel_block(&request);
+                client
+                    .send_cancel(request.index, request.begin, request.length)
+                    .await?;
             }
 
-            // 校验下载的piece
-            if self.verify_piece_integrity(&mut piece_work).is_err() {
-                // 校验错误重新请求
-                if self.work_chan.0.send(piece_work).is_err() {
-                    error!("Error: could not send piece to channel");
-                    return;
+            // 如果客户端没有被阻塞,请求picker分配的块
+            if !client.peer_choking() {
+                while pending.len() < NB_REQUESTS_MAX as usize {
+                    let request = match self
+                        .picker
+                        .pick_block(|index| client.has_piece(index), pending)
+                    {
+                        Some(request) => request,
+                        None => break,
+                    };
+                    pending.push(request);
+                    client
+                        .send_request(request.index, request.begin, request.length)
+                        .await?;
                 }
-                continue;
+            }
+            if let Some(upload) = upload.as_mut() {
+                upload.update(client).await?;
             }
 
-            // 通知已经下载了这个piece
-            if client.send_have(piece_work.index).is_err() {
-                error!("Error: could not notify peer that piece was downloaded");
+            // 对方一直不发送请求的块时断开,由其它peer下载
+            if pending.is_empty() {
+                last_block = Instant::now();
+            } else if last_block.elapsed() > self.piece_timeout {
+                return Err(anyhow!("timed out waiting for piece from peer"));
             }
+            // 监听是否又消息来,定期检查其它worker的进度
+            let message = match timeout(POLL_INTERVAL, client.read_next_message()).await {
+                Ok(message) => match message? {
+                    Some(message) => message,
+                    None => continue,
+                },
+                Err(_) => continue,
+            };
 
-            // 将下载的piece发送到result channel中
-            let piece_result =
-                PieceResult::new(piece_work.index, piece_work.length, piece_work.data);
-            if self.result_chan.0.send(piece_result).is_err() {
-                error!("Error: could not send piece to channel");
-                return;
+            // 解析消息
+            if message.id == MESSAGE_PIECE {
+                //下载一个资源快
+                let (index, begin, block) = client.read_block(message)?;
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length: block.len() as u32,
+                };
+                // 忽略已经取消的请求
+                if !pending.contains(&request) {
+                    continue;
+                }
+                pending.retain(|r| *r != request);
+                last_block = Instant::now();
+                if let Some(upload) = upload.as_ref() {
+                    upload.state.add_downloaded(block.len() as u64);
+                }
+                if let Some(piece_work) = self.picker.receive_block(index, begin, &block)? {
+                    self.complete_piece(client, piece_work, upload).await?;
+                }
+            } else {
+                self.handle_message(client, message, upload).await?;
+                // 被阻塞时对方会丢弃所有请求
+                if client.peer_choking() {
+                    self.release(pending);
+                }
             }
         }
     }
 
-    fn download_piece(&self, client: &mut Client, piece_work: &mut PieceWork) -> Result<()> {
-        // 设置连接超时时间
-        // 对于下载资源来说要较长
-        client.set_connection_timeout(120)?;
-
-        // 重置
-        piece_work.requests = 0;
-        piece_work.requested = 0;
-        piece_work.downloaded = 0;
-
-        // Download torrent piece
-        while piece_work.downloaded < piece_work.length {
-            // 如果客户端被阻塞
-            if !client.is_choked() {
-                while piece_work.requests < NB_REQUESTS_MAX
-                    && piece_work.requested < piece_work.length
-                {
-                    // 读取内容
-                    let mut block_size = BLOCK_SIZE_MAX;
-                    let remaining = piece_work.length - piece_work.requested;
-                    if remaining < BLOCK_SIZE_MAX {
-                        block_size = remaining;
-                    }
+    /// 校验收到所有块的piece,发送到result channel中
+    async fn complete_piece(
+        &self,
+        client: &mut Client,
+        mut piece_work: PieceWork,
+        upload: &Option<Upload>,
+    ) -> Result<()> {
+        info!("Successfully downloaded piece {:?}", piece_work.index);
+        // 校验下载的piece
+        if self.verify_piece_integrity(&mut piece_work).is_err() {
+            // 校验错误重新请求
+            self.picker.discard(piece_work.index);
+            return Ok(());
+        }
+        if !self.picker.finish(piece_work.index) {
+            return Ok(());
+        }
 
-                    // 请求内容
-                    client.send_request(piece_work.index, piece_work.requested, block_size)?;
+        // 通知已经下载了这个piece,上传时写入磁盘后再通知
+        if upload.is_none() && client.send_have(piece_work.index).await.is_err() {
+            error!("Error: could not notify peer that piece was downloaded");
+        }
 
-                    // 更新发送的请求数目
-                    piece_work.requests += 1;
+        // 将下载的piece发送到result channel中
+        let piece_result = PieceResult::new(piece_work.index, piece_work.length, piece_work.data);
+        if self.result_chan.send(piece_result).await.is_err() {
+            return Err(anyhow!("could not send piece to channel"));
+        }
+        Ok(())
+    }
 
-                    // 更新请求的数据
-                    piece_work.requested += block_size;
-                }
-            }
+    /// 放弃所有还没有收到的请求,由其它peer下载
+    fn release(&self, pending: &mut Vec<BlockRequest>) {
+        for request in pending.drain(..) {
+            self.picker.cancel_block(&request);
+        }
+    }
 
-            // 监听是否又消息来
-            let message: Message = client.read_message()?;
+    /// 下载完成后不再感兴趣,可以上传时继续响应对方直到停止做种
+    async fn seed(&self, client: &mut Client, upload: &mut Option<Upload>) -> Result<()> {
+        if client.am_interested() {
+            client.send_not_interested().await?;
+        }
+        while upload
+            .as_ref()
+            .is_some_and(|upload| !upload.seeder.is_stopped())
+        {
+            self.poll(client, upload, POLL_INTERVAL).await?;
+        }
+        Ok(())
+    }
 
-            // 解析消息
-            match message.id {
-                MESSAGE_CHOKE => client.read_choke(),       //阻塞客户端
-                MESSAGE_UNCHOKE => client.read_unchoke(),   //解除阻塞
-                MESSAGE_HAVE => client.read_have(message)?, //本地已经下载
-                MESSAGE_PIECE => client.read_piece(message, piece_work)?, //下载一个资源快
-                _ => info!("received unknown message from peer"),
+    /// 最多等待 timeout,处理对方发送的消息
+    async fn poll(
+        &self,
+        client: &mut Client,
+        upload: &mut Option<Upload>,
+        wait: Duration,
+    ) -> Result<()> {
+        if let Some(upload) = upload.as_mut() {
+            upload.update(client).await?;
+        }
+        let mut wait = wait;
+        while let Ok(message) = timeout(wait, client.read_next_message()).await {
+            wait = Duration::from_millis(1);
+            if let Some(message) = message? {
+                self.handle_message(client, message, upload).await?;
             }
         }
-        info!("Successfully downloaded piece {:?}", piece_work.index);
+        Ok(())
+    }
+
+    /// 处理除了 PIECE 之外的消息
+    async fn handle_message(
+        &self,
+        client: &mut Client,
+        message: Message,
+        upload: &mut Option<Upload>,
+    ) -> Result<()> {
+        match message.id {
+            MESSAGE_CHOKE => client.read_choke(),     //阻塞客户端
+            MESSAGE_UNCHOKE => client.read_unchoke(), //解除阻塞
+            MESSAGE_HAVE => {
+                // 对方新下载了一个piece
+                if let Some(index) = client.read_have(message)? {
+                    self.picker.have(index);
+                }
+            }
+            MESSAGE_EXTENDED => client.read_extended(message).await?, //扩展协议的消息
+            MESSAGE_INTERESTED => {
+                client.read_interested();
+                if let Some(upload) = upload.as_ref() {
+                    upload.seeder.choker().interested(&upload.state);
+                }
+            }
+            MESSAGE_NOTINTERSETED => {
+                client.read_not_interested();
+                if let Some(upload) = upload.as_ref() {
+                    upload.seeder.choker().not_interested(&upload.state);
+                }
+            }
+            MESSAGE_REQUEST => {
+                let request = client.read_block_request(message)?;
+                if let Some(upload) = upload.as_ref() {
+                    upload
+                        .seeder
+                        .serve_request(client, &upload.state, request)
+                        .await?;
+                }
+            }
+            _ => info!("received unknown message from peer"),
+        }
         Ok(())
     }
 
@@ -212,3 +392,155 @@ impl Worker {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod worker_test {
+    use super::*;
+    use crate::bittorrent::picker::BLOCK_SIZE;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const PIECE_LENGTH: u32 = BLOCK_SIZE * 2;
+
+    /// 模拟拥有两个piece的peer,stall 为true时只定期发送HAVE,不响应请求
+    /// haves 为true时不发送bitfield,改为在解除阻塞前后逐个发送HAVE
+    async fn mock_peer(
+        listener: TcpListener,
+        info_hash: Vec<u8>,
+        data: Vec<u8>,
+        stall: bool,
+        haves: bool,
+    ) {
+        let (conn, addr) = listener.accept().await.unwrap();
+        let peer = Peer {
+            id: 0,
+            ip: addr.ip(),
+            port: addr.port(),
+        };
+        let mut client = Client::from_stream(peer, conn, vec![9; 20], info_hash);
+        client.handshake_from_peer().await.unwrap();
+        if haves {
+            client.send_have(0).await.unwrap();
+            client.send_unchoke().await.unwrap();
+            client.send_have(1).await.unwrap();
+        } else {
+            client.send_bitfield(&[0b1100_0000]).await.unwrap();
+            client.send_unchoke().await.unwrap();
+        }
+        loop {
+            let wait = Duration::from_millis(200);
+            let message = match timeout(wait, client.read_next_message()).await {
+                Ok(Ok(Some(message))) => message,
+                Ok(Ok(None)) => continue,
+                // 对方已经断开
+                Ok(Err(_)) => return,
+                Err(_) => match client.send_have(0).await {
+                    Ok(_) => continue,
+                    Err(_) => return,
+                },
+            };
+            if message.id == MESSAGE_REQUEST && !stall {
+                let request = client.read_block_request(message).unwrap();
+                let begin = (request.index * PIECE_LENGTH + request.begin) as usize;
+                let block = &data[begin..begin + request.length as usize];
+                if client
+                    .send_piece(request.index, request.begin, block)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn start_peer(info_hash: &[u8], data: &[u8], stall: bool, haves: bool) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(mock_peer(
+            listener,
+            info_hash.to_vec(),
+            data.to_vec(),
+            stall,
+            haves,
+        ));
+        Peer {
+            id: 0,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        }
+    }
+
+    /// 对方只发送HAVE不发送请求的块时超时断开,另一个peer下载这些块
+    #[tokio::test]
+    async fn test_take_over_stalled_blocks() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2).map(|i| (i % 253) as u8).collect();
+        let works = data
+            .chunks(PIECE_LENGTH as usize)
+            .enumerate()
+            .map(|(index, piece)| PieceWork::new(index as u32, sha1(piece), PIECE_LENGTH))
+            .collect();
+        let picker = PiecePicker::new(2, works);
+        let info_hash = vec![3; 20];
+        let (result_tx, mut result_rx) = mpsc::channel(2);
+
+        // 不发送块的peer拿到所有块的请求后,在超时前一直发送HAVE
+        let peer = start_peer(&info_hash, &data, true, false).await;
+        let mut stalled = Worker::new(
+            peer,
+            vec![1; 20],
+            info_hash.clone(),
+            picker.clone(),
+            result_tx.clone(),
+        )
+        .unwrap();
+        stalled.set_piece_timeout(Duration::from_secs(1));
+        let stalled = tokio::spawn(async move { stalled.start_download().await });
+        timeout(Duration::from_secs(10), stalled)
+            .await
+            .expect("stalled peer was not dropped")
+            .unwrap();
+
+        let peer = start_peer(&info_hash, &data, false, false).await;
+        let worker = Worker::new(peer, vec![1; 20], info_hash, picker.clone(), result_tx).unwrap();
+        tokio::spawn(async move { worker.start_download().await });
+        let mut received = vec![];
+        for _ in 0..2 {
+            let result = timeout(Duration::from_secs(10), result_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push((result.index, result.data));
+        }
+        received.sort();
+        assert_eq!(received[0], (0, data[..PIECE_LENGTH as usize].to_vec()));
+        assert_eq!(received[1], (1, data[PIECE_LENGTH as usize..].to_vec()));
+        assert!(picker.is_complete());
+    }
+
+    /// 对方不发送bitfield,只发送HAVE时仍然可以下载
+    #[tokio::test]
+    async fn test_download_without_bitfield() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2).map(|i| (i % 251) as u8).collect();
+        let works = data
+            .chunks(PIECE_LENGTH as usize)
+            .enumerate()
+            .map(|(index, piece)| PieceWork::new(index as u32, sha1(piece), PIECE_LENGTH))
+            .collect();
+        let picker = PiecePicker::new(2, works);
+        let info_hash = vec![4; 20];
+        let (result_tx, mut result_rx) = mpsc::channel(2);
+
+        let peer = start_peer(&info_hash, &data, false, true).await;
+        let worker = Worker::new(peer, vec![1; 20], info_hash, picker.clone(), result_tx).unwrap();
+        tokio::spawn(async move { worker.start_download().await });
+        for _ in 0..2 {
+            timeout(Duration::from_secs(10), result_rx.recv())
+                .await
+                .expect("no piece downloaded from peer without bitfield")
+                .unwrap();
+        }
+        assert!(picker.is_complete());
+    }
+}
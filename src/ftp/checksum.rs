@@ -0,0 +1,284 @@
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// 校验使用的摘要算法,按照优先级从高到低排列
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Crc32,
+}
+
+const ALGORITHMS: [Algorithm; 4] = [
+    Algorithm::Sha256,
+    Algorithm::Sha1,
+    Algorithm::Md5,
+    Algorithm::Crc32,
+];
+
+impl Algorithm {
+    /// HASH 命令中使用的名称
+    pub fn hash_name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha1 => "SHA-1",
+            Algorithm::Md5 => "MD5",
+            Algorithm::Crc32 => "CRC32",
+        }
+    }
+
+    /// 对应的扩展命令
+    pub fn command(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "XSHA256",
+            Algorithm::Sha1 => "XSHA1",
+            Algorithm::Md5 => "XMD5",
+            Algorithm::Crc32 => "XCRC",
+        }
+    }
+
+    /// 十六进制摘要的长度
+    fn digest_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha1 => 40,
+            Algorithm::Md5 => 32,
+            Algorithm::Crc32 => 8,
+        }
+    }
+}
+
+/// 获取服务器摘要的方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    /// HASH 命令,select 为true时需要先用 OPTS HASH 选择算法
+    Hash { algorithm: Algorithm, select: bool },
+    /// XMD5 XSHA1 XSHA256 XCRC 扩展命令
+    Command(Algorithm),
+}
+
+impl Method {
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Method::Hash { algorithm, .. } => *algorithm,
+            Method::Command(algorithm) => *algorithm,
+        }
+    }
+}
+
+/// 按照 FEAT 返回的特性选择最合适的方式,优先使用 HASH 命令和更强的算法
+/// HASH 的格式为 "HASH SHA-1;SHA-256*;MD5",* 表示当前选择的算法
+pub fn choose(features: &[String]) -> Option<Method> {
+    let features: Vec<String> = features.iter().map(|f| f.trim().to_uppercase()).collect();
+    if let Some(list) = features.iter().find_map(|f| f.strip_prefix("HASH ")) {
+        let names: Vec<&str> = list.split(';').map(|name| name.trim()).collect();
+        for algorithm in ALGORITHMS {
+            let name = algorithm.hash_name();
+            if names.contains(&name) {
+                return Some(Method::Hash {
+                    algorithm,
+                    select: true,
+                });
+            }
+            if names.contains(&format!("{}*", name).as_str()) {
+                return Some(Method::Hash {
+                    algorithm,
+                    select: false,
+                });
+            }
+        }
+    }
+    ALGORITHMS
+        .into_iter()
+        .find(|algorithm| features.iter().any(|f| f == algorithm.command()))
+        .map(Method::Command)
+}
+
+/// 按位置从响应中取出摘要,文件名本身可能像十六进制数
+/// HASH: "SHA-256 0-49 169cd222...e9dd filename",摘要在范围之后
+/// 扩展命令: "169cd222...e9dd" 或者 "filename 169cd222...e9dd",摘要在最后
+pub fn extract_digest(message: &str, method: Method) -> Option<String> {
+    let mut tokens = message.split_whitespace();
+    let token = match method {
+        Method::Hash { .. } => tokens.nth(2)?,
+        Method::Command(_) => tokens.last()?,
+    };
+    let algorithm = method.algorithm();
+    let len_ok = match algorithm {
+        // CRC 可能省略前导的0
+        Algorithm::Crc32 => !token.is_empty() && token.len() <= algorithm.digest_len(),
+        _ => token.len() == algorithm.digest_len(),
+    };
+    if len_ok && token.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(token.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// 比较服务器和本地的摘要
+pub fn same_digest(algorithm: Algorithm, remote: &str, local: &str) -> bool {
+    match algorithm {
+        Algorithm::Crc32 => {
+            u32::from_str_radix(remote, 16).ok() == u32::from_str_radix(local, 16).ok()
+        }
+        _ => remote.eq_ignore_ascii_case(local),
+    }
+}
+
+/// 计算本地文件的十六进制摘要
+pub fn file_digest(path: &Path, algorithm: Algorithm) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hasher: Box<dyn Digest> = match algorithm {
+        Algorithm::Sha256 => Box::new(Sha256::new()),
+        Algorithm::Sha1 => Box::new(Sha1::new()),
+        Algorithm::Md5 => Box::new(Md5::new()),
+        Algorithm::Crc32 => {
+            let mut crc = Crc32::new();
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    return Ok(format!("{:08x}", crc.finish()));
+                }
+                crc.update(&buf[..n]);
+            }
+        }
+    };
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(hasher.result_str())
+}
+
+/// CRC-32 (IEEE 802.3),与 zlib 的结果相同
+struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 {
+            table,
+            value: 0xffff_ffff,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            let index = ((self.value ^ *byte as u32) & 0xff) as usize;
+            self.value = self.table[index] ^ (self.value >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.value ^ 0xffff_ffff
+    }
+}
+
+#[cfg(test)]
+mod checksum_test {
+    use super::*;
+
+    fn features(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_choose() {
+        let method = choose(&features(&[" SIZE", " HASH SHA-1;SHA-256*;MD5", " XMD5"]));
+        assert_eq!(
+            method,
+            Some(Method::Hash {
+                algorithm: Algorithm::Sha256,
+                select: false
+            })
+        );
+        let method = choose(&features(&[" HASH SHA-1*;MD5"]));
+        assert_eq!(
+            method,
+            Some(Method::Hash {
+                algorithm: Algorithm::Sha1,
+                select: false
+            })
+        );
+        let method = choose(&features(&[" HASH MD5*;SHA-1"]));
+        assert_eq!(
+            method,
+            Some(Method::Hash {
+                algorithm: Algorithm::Sha1,
+                select: true
+            })
+        );
+        let method = choose(&features(&[" XCRC", " XMD5"]));
+        assert_eq!(method, Some(Method::Command(Algorithm::Md5)));
+        assert_eq!(choose(&features(&[" SIZE", " MDTM"])), None);
+    }
+
+    #[test]
+    fn test_extract_digest() {
+        let hash = Method::Hash {
+            algorithm: Algorithm::Sha1,
+            select: false,
+        };
+        let message = "SHA-1 0-10 0A4D55A8D778E5022FAB701977C5D840BBC486D0 hello.txt";
+        assert_eq!(
+            extract_digest(message, hash),
+            Some("0a4d55a8d778e5022fab701977c5d840bbc486d0".to_string())
+        );
+        let md5 = Method::Command(Algorithm::Md5);
+        assert_eq!(
+            extract_digest("8d777f385d3dfec8815d20f7496026dc", md5),
+            Some("8d777f385d3dfec8815d20f7496026dc".to_string())
+        );
+        assert_eq!(extract_digest("not supported", md5), None);
+        // 文件名像十六进制数时不能当作摘要
+        let crc = Method::Command(Algorithm::Crc32);
+        assert_eq!(
+            extract_digest("cafe CBF43926", crc),
+            Some("cbf43926".to_string())
+        );
+        assert_eq!(
+            extract_digest("2026 1c291ca3", crc),
+            Some("1c291ca3".to_string())
+        );
+        assert_eq!(extract_digest("cafe.txt", crc), None);
+        assert!(same_digest(Algorithm::Crc32, "CBF43926", "cbf43926"));
+        assert!(same_digest(Algorithm::Crc32, "1c291ca3", "1C291CA3"));
+    }
+
+    #[test]
+    fn test_file_digest() {
+        let path = std::env::temp_dir().join("checksum_test.txt");
+        std::fs::write(&path, "123456789").unwrap();
+        assert_eq!(file_digest(&path, Algorithm::Crc32).unwrap(), "cbf43926");
+        assert_eq!(
+            file_digest(&path, Algorithm::Md5).unwrap(),
+            "25f9e794323b453885f5181f1b624d0b"
+        );
+        assert_eq!(
+            file_digest(&path, Algorithm::Sha1).unwrap(),
+            "f7c3bc1d808e04732adf679965ccc34ca7ae3441"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
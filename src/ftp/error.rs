@@ -0,0 +1,164 @@
+use crate::ftp::stream::Reply;
+use std::fmt;
+use std::io;
+
+// 与错误分类相关的响应码
+pub const SERVICE_NOT_AVAILABLE: u32 = 421;
+pub const NOT_LOGGED_IN: u32 = 530;
+pub const FILE_UNAVAILABLE: u32 = 550;
+pub const FILE_NAME_NOT_ALLOWED: u32 = 553;
+
+pub type Result<T> = std::result::Result<T, FtpError>;
+
+/// ftp操作中的错误
+#[derive(Debug)]
+pub enum FtpError {
+    /// 用户名或密码错误,或者未登录
+    Auth(Reply),
+    /// 文件或目录不存在(550)
+    NotFound(Reply),
+    /// 没有权限访问文件或目录
+    PermissionDenied(Reply),
+    /// 连接失败、超时或者连接被服务器关闭
+    Network(io::Error),
+    /// 本地文件读写失败
+    Io(io::Error),
+    /// TLS握手或证书校验失败
+    Tls(openssl::error::ErrorStack),
+    /// 服务器返回了无法解析的内容
+    Protocol(String),
+    /// 服务器返回了其它不符合预期的响应
+    UnexpectedReply(Reply),
+    /// 下载的文件与服务器上的摘要不一致
+    ChecksumMismatch {
+        filename: String,
+        algorithm: &'static str,
+        remote: String,
+        local: String,
+    },
+}
+
+impl FtpError {
+    /// 按照响应码将服务器的错误响应分类
+    pub fn from_reply(reply: Reply) -> Self {
+        match reply.code {
+            NOT_LOGGED_IN => FtpError::Auth(reply),
+            FILE_NAME_NOT_ALLOWED => FtpError::PermissionDenied(reply),
+            // 550 同时用于文件不存在和没有权限,只能通过响应内容区分
+            FILE_UNAVAILABLE => {
+                let message = reply.message.to_lowercase();
+                if ["permission", "denied", "access", "not allowed"]
+                    .iter()
+                    .any(|word| message.contains(word))
+                {
+                    FtpError::PermissionDenied(reply)
+                } else {
+                    FtpError::NotFound(reply)
+                }
+            }
+            SERVICE_NOT_AVAILABLE => FtpError::Network(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("service not available: {}", reply.message),
+            )),
+            _ => FtpError::UnexpectedReply(reply),
+        }
+    }
+
+    /// 超时错误
+    pub fn timeout(message: &str) -> Self {
+        FtpError::Network(io::Error::new(io::ErrorKind::TimedOut, message))
+    }
+
+    /// 连接已经不可用,需要重新连接
+    pub fn is_network(&self) -> bool {
+        matches!(self, FtpError::Network(_))
+    }
+}
+
+impl fmt::Display for FtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtpError::Auth(reply) => {
+                write!(f, "authentication failed: {} {}", reply.code, reply.message)
+            }
+            FtpError::NotFound(reply) => write!(f, "file not found: {}", reply.message),
+            FtpError::PermissionDenied(reply) => write!(f, "permission denied: {}", reply.message),
+            FtpError::Network(e) => write!(f, "network error: {}", e),
+            FtpError::Io(e) => write!(f, "local io error: {}", e),
+            FtpError::Tls(e) => write!(f, "tls error: {}", e),
+            FtpError::Protocol(message) => write!(f, "protocol error: {}", message),
+            FtpError::UnexpectedReply(reply) => {
+                write!(f, "unexpected reply: {} {}", reply.code, reply.message)
+            }
+            FtpError::ChecksumMismatch {
+                filename,
+                algorithm,
+                remote,
+                local,
+            } => write!(
+                f,
+                "{} checksum of {} does not match: server {}, local {}",
+                algorithm, filename, remote, local
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FtpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FtpError::Network(e) | FtpError::Io(e) => Some(e),
+            FtpError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// 控制连接和数据连接上的io错误都属于网络错误,本地文件的错误需要转换为 FtpError::Io
+impl From<io::Error> for FtpError {
+    fn from(e: io::Error) -> Self {
+        FtpError::Network(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for FtpError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        FtpError::Tls(e)
+    }
+}
+
+/// 握手时的io错误属于网络错误,其它的是证书校验等TLS错误
+impl From<openssl::ssl::Error> for FtpError {
+    fn from(e: openssl::ssl::Error) -> Self {
+        match e.ssl_error() {
+            Some(stack) => FtpError::Tls(stack.clone()),
+            None => FtpError::Network(e.into_io_error().unwrap_or_else(io::Error::other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+
+    fn reply(code: u32, message: &str) -> Reply {
+        Reply {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_from_reply() {
+        let error = FtpError::from_reply(reply(530, "Login incorrect"));
+        assert!(matches!(error, FtpError::Auth(_)));
+        let error = FtpError::from_reply(reply(550, "No such file or directory"));
+        assert!(matches!(error, FtpError::NotFound(_)));
+        let error = FtpError::from_reply(reply(550, "Permission denied"));
+        assert!(matches!(error, FtpError::PermissionDenied(_)));
+        let error = FtpError::from_reply(reply(421, "Timeout"));
+        assert!(error.is_network());
+        let error = FtpError::from_reply(reply(425, "Can't open data connection"));
+        assert!(matches!(error, FtpError::UnexpectedReply(_)));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::ftp::error::{FtpError, Result};
+use crate::ftp::myftp::{progress_style, FTP};
+use crate::ftp::parser::FtpUrl;
+use crate::ftp::stream::FtpConfig;
+use colorful::{Color, Colorful};
+use indicatif::ProgressBar;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 需要下载的远程文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteFile {
+    pub name: String,
+    // 服务器不支持 SIZE 时为None
+    pub size: Option<u64>,
+}
+
+/// 单个文件的下载结果
+struct FileResult {
+    name: String,
+    result: Result<u64>,
+}
+
+/// 判断文件名中是否含有通配符
+pub fn has_wildcard(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// 通配符匹配,支持 * ? [abc] [a-z] [!abc]
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_from(&pattern, &name)
+}
+
+fn match_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| match_from(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && match_from(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), match_class(&pattern[1..])) {
+            (Some(c), Some((class, negate, rest))) => {
+                let found = class.iter().any(|(low, high)| low <= c && c <= high);
+                found != negate && match_from(&pattern[rest + 1..], &name[1..])
+            }
+            // 没有闭合的 [ 按普通字符处理
+            (Some(c), None) => *c == '[' && match_from(&pattern[1..], &name[1..]),
+            (None, _) => false,
+        },
+        Some(p) => name.first() == Some(p) && match_from(&pattern[1..], &name[1..]),
+    }
+}
+
+/// [] 中的字符集合: (字符范围,是否取反,] 之后的位置)
+type CharClass = (Vec<(char, char)>, bool, usize);
+
+/// 解析 [] 中的字符集合
+fn match_class(pattern: &[char]) -> Option<CharClass> {
+    let mut i = 0;
+    let negate = matches!(pattern.first(), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut class = Vec::new();
+    let mut first = true;
+    while i < pattern.len() {
+        let c = pattern[i];
+        if c == ']' && !first {
+            return Some((class, negate, i + 1));
+        }
+        first = false;
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            class.push((c, pattern[i + 2]));
+            i += 3;
+        } else {
+            class.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+/// 在当前目录中展开通配符,返回匹配的文件
+/// SIZE 返回550的条目视为目录,不会被下载;服务器不支持 SIZE 时大小未知
+pub async fn expand(ftp: &mut FTP, pattern: &str) -> Result<Vec<RemoteFile>> {
+    let mut names: Vec<String> = ftp
+        .nlst(None)
+        .await?
+        .into_iter()
+        // 部分服务器返回的是路径
+        .map(|name| name.rsplit('/').next().unwrap_or_default().to_string())
+        .filter(|name| wildcard_match(pattern, name))
+        .collect();
+    names.sort();
+    names.dedup();
+    let mut files = Vec::new();
+    for name in names {
+        match ftp.size(&name).await {
+            Ok(size) => files.push(RemoteFile { name, size }),
+            Err(e) if e.is_network() => return Err(e),
+            Err(FtpError::NotFound(_)) => {}
+            Err(_) => files.push(RemoteFile { name, size: None }),
+        }
+    }
+    Ok(files)
+}
+
+/// 下载链接中通配符匹配的所有文件
+/// jobs 大于1时会建立多个会话同时下载,单个文件失败只在汇总中显示
+pub async fn download_all(
+    url: &FtpUrl,
+    config: &FtpConfig,
+    output: &str,
+    jobs: usize,
+) -> Result<()> {
+    let begin = Instant::now();
+    let mut ftp = login(url, config).await?;
+    let files = expand(&mut ftp, &url.filename).await?;
+    if files.is_empty() {
+        println!("{}", format!("no file matches {}", url.filename).color(Color::Red));
+        return ftp.disconnect().await;
+    }
+    let total_size: u64 = files.iter().filter_map(|file| file.size).sum();
+    let total_files = files.len();
+    println!(
+        "{}",
+        format!("download {} files ({} bytes).......", total_files, total_size)
+            .gradient(Color::Green)
+    );
+    // 总体进度条
+    let pb = ProgressBar::new(total_size);
+    pb.set_style(progress_style());
+
+    let task = Arc::new(BatchTask {
+        url: url.clone(),
+        config: config.clone(),
+        output: output.to_string(),
+        queue: Mutex::new(files.into_iter().collect()),
+        results: Mutex::new(Vec::new()),
+        total_files,
+        pb: pb.clone(),
+    });
+    let jobs = jobs.clamp(1, total_files);
+    let mut handles = Vec::new();
+    // 第一个会话复用已经登录的连接
+    let mut first = Some(ftp);
+    for _ in 0..jobs {
+        let session = first.take();
+        handles.push(tokio::spawn(worker(session, task.clone())));
+    }
+    futures_util::future::join_all(handles).await;
+    pb.finish_with_message("done");
+    print_summary(&task.results.lock().unwrap(), total_files, begin);
+    Ok(())
+}
+
+/// 批量下载任务,由多个会话共享
+struct BatchTask {
+    url: FtpUrl,
+    config: FtpConfig,
+    output: String,
+    queue: Mutex<VecDeque<RemoteFile>>,
+    results: Mutex<Vec<FileResult>>,
+    total_files: usize,
+    pb: ProgressBar,
+}
+
+/// 一个会话依次下载队列中的文件
+/// 会话断开时重新登录并从断点继续下载当前文件
+async fn worker(ftp: Option<FTP>, task: Arc<BatchTask>) {
+    let mut ftp = match ftp {
+        Some(ftp) => ftp,
+        None => match login(&task.url, &task.config).await {
+            Ok(ftp) => ftp,
+            Err(e) => {
+                task.pb.println(format!("could not open session: {}", e));
+                return;
+            }
+        },
+    };
+    let mut retries = task.config.get_retries();
+    loop {
+        let file = match task.queue.lock().unwrap().pop_front() {
+            Some(file) => file,
+            None => break,
+        };
+        task.pb.set_message(file.name.clone());
+        let mut resume = false;
+        let result = loop {
+            match ftp.fetch(&file.name, &task.output, &task.pb, resume).await {
+                Ok(size) => {
+                    let local = Path::new(&task.output).join(&file.name);
+                    break ftp.verify(&file.name, &local).await.map(|_| size);
+                }
+                // 只有网络错误需要重新连接,其它错误属于文件本身
+                Err(e) if !e.is_network() => break Err(e),
+                Err(e) => {
+                    task.pb
+                        .println(format!("session lost ({}), reconnecting", e));
+                    match reconnect(&task, &mut retries, e).await {
+                        Ok(session) => ftp = session,
+                        Err(e) => break Err(e),
+                    }
+                    // 大小未知时无法判断本地文件是否完整,重新下载
+                    resume = file.size.is_some();
+                }
+            }
+        };
+        // 无法重新建立会话时由其他会话继续下载
+        let lost = matches!(&result, Err(e) if e.is_network());
+        let mut results = task.results.lock().unwrap();
+        let index = results.len() + 1;
+        let line = match &result {
+            Ok(size) => format!(
+                "[{}/{}] {} {} bytes ok",
+                index, task.total_files, file.name, size
+            ),
+            Err(e) => format!(
+                "[{}/{}] {} failed: {}",
+                index, task.total_files, file.name, e
+            ),
+        };
+        task.pb.println(line);
+        results.push(FileResult {
+            name: file.name,
+            result,
+        });
+        drop(results);
+        if lost {
+            return;
+        }
+    }
+    ftp.disconnect().await.ok();
+}
+
+/// 重新登录,每次失败后等待更长的时间
+/// 重试次数用完时返回最后一次的错误
+async fn reconnect(task: &BatchTask, retries: &mut u32, cause: FtpError) -> Result<FTP> {
+    let mut last_error = cause;
+    let mut attempt = 0;
+    while *retries > 0 {
+        *retries -= 1;
+        attempt += 1;
+        tokio::time::sleep(Duration::from_secs(attempt)).await;
+        match login(&task.url, &task.config).await {
+            Ok(ftp) => return Ok(ftp),
+            Err(e) => {
+                task.pb.println(format!("reconnect failed: {}", e));
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// 登录并进入链接中的目录
+async fn login(url: &FtpUrl, config: &FtpConfig) -> Result<FTP> {
+    let mut ftp =
+        FTP::login_with_config(&url.address(), &url.username, &url.password, config).await?;
+    if !url.directory.is_empty() {
+        ftp.cwd(url.directory.as_str()).await?;
+    }
+    Ok(ftp)
+}
+
+/// 打印下载结果汇总
+fn print_summary(results: &[FileResult], total_files: usize, begin: Instant) {
+    let downloaded: u64 = results.iter().filter_map(|r| r.result.as_ref().ok()).sum();
+    let failed: Vec<&FileResult> = results.iter().filter(|r| r.result.is_err()).collect();
+    println!(
+        "{}",
+        format!(
+            "{}/{} files downloaded, {} bytes in {:.1}s",
+            results.len() - failed.len(),
+            total_files,
+            downloaded,
+            begin.elapsed().as_secs_f64()
+        )
+        .gradient(Color::Green)
+    );
+    for result in failed {
+        println!("{}", format!("failed: {}", result.name).color(Color::Red));
+    }
+    // 会话异常退出时未下载的文件
+    if results.len() < total_files {
+        println!(
+            "{}",
+            format!("{} files were not downloaded", total_files - results.len()).color(Color::Red)
+        );
+    }
+}
+
+#[cfg(test)]
+mod mget_test {
+    use super::{has_wildcard, wildcard_match};
+
+    #[test]
+    fn test_has_wildcard() {
+        assert!(has_wildcard("2026-10-*.gz"));
+        assert!(has_wildcard("file?.txt"));
+        assert!(!has_wildcard("file.txt"));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("2026-10-*.gz", "2026-10-01.gz"));
+        assert!(!wildcard_match("2026-10-*.gz", "2026-11-01.gz"));
+        assert!(!wildcard_match("2026-10-*.gz", "2026-10-01.gz.bak"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("log?.txt", "log1.txt"));
+        assert!(!wildcard_match("log?.txt", "log.txt"));
+        assert!(wildcard_match("log[0-2].txt", "log1.txt"));
+        assert!(!wildcard_match("log[!0-2].txt", "log1.txt"));
+        assert!(wildcard_match("log[!0-2].txt", "log5.txt"));
+        assert!(wildcard_match("[]]x", "]x"));
+        assert!(wildcard_match("a[b", "a[b"));
+    }
+}
@@ -1,23 +1,78 @@
+pub mod checksum;
+pub mod error;
+pub mod mget;
 pub mod myftp;
 pub mod parser;
+pub mod shell;
+pub mod stream;
 
 use colorful::{Color, Colorful};
-use parser::CommandArgument;
+use error::Result;
+use parser::{CommandArgument, FtpUrl};
+use stream::{FtpConfig, Security};
+
+/// 按照ftp链接登录服务器,链接指向文件时下载该文件,指向目录时打印目录内容
+/// 文件名中含有通配符时下载所有匹配的文件,jobs为同时使用的会话数
+/// ftps:// 链接总是使用隐式加密
+pub async fn download_url(
+    url: &FtpUrl,
+    config: &FtpConfig,
+    output: &str,
+    jobs: usize,
+) -> Result<()> {
+    let mut config = config.clone();
+    if url.scheme == "ftps" {
+        config = config.set_security(Security::Implicit);
+    }
+    if mget::has_wildcard(&url.filename) {
+        return mget::download_all(url, &config, output, jobs).await;
+    }
+    let mut ftp =
+        myftp::FTP::login_with_config(&url.address(), &url.username, &url.password, &config)
+            .await?;
+    if !url.directory.is_empty() {
+        ftp.cwd(url.directory.as_str()).await?;
+    }
+    if url.filename.is_empty() {
+        ftp.list(None)
+            .await?
+            .into_iter()
+            .for_each(|line| println!("{}", line));
+    } else {
+        ftp.download(url.filename.as_str(), output).await?;
+    }
+    ftp.disconnect().await
+}
+
+/// 登录后进入链接指向的目录并打开交互模式
+pub async fn open_shell(url: &FtpUrl, config: &FtpConfig, output: &str) -> Result<()> {
+    let mut ftp =
+        myftp::FTP::login_with_config(&url.address(), &url.username, &url.password, config).await?;
+    // 交互模式下链接的整个路径都视为目录
+    for dir in [&url.directory, &url.filename] {
+        if !dir.is_empty() {
+            ftp.cwd(dir).await?;
+        }
+    }
+    shell::run(ftp, output).await
+}
 
 pub async fn execute() {
     let mut command = CommandArgument::new();
     if let Ok(()) = command.parse() {
         //如果参数解析正确
-        let username = command.get_username().unwrap();
-        let password = command.get_password().unwrap();
-        let address = command.get_address().unwrap();
+        let url = command.get_url().unwrap();
         let output = command.get_output().unwrap();
-        let target = command.get_target_path().unwrap();
-        println!("target: {:?}",target);
-        let mut ftp = myftp::FTP::login(&address, &username, &password).await;
-        ftp.cwd(target.0.as_str()).await;
-        ftp.list(None).await;
-        ftp.download(target.1.as_str(), output.as_str()).await;
+        let config = command.get_config();
+        println!("target: {:?}", command.get_target_path().unwrap());
+        let result = if command.is_shell() {
+            open_shell(&url, &config, output.as_str()).await
+        } else {
+            download_url(&url, &config, output.as_str(), command.get_jobs()).await
+        };
+        if let Err(e) = result {
+            println!("{}", e.to_string().color(Color::Red));
+        }
     } else {
         println!("{}", "Please check your entry".color(Color::Red));
     }
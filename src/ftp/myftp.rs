@@ -1,60 +1,273 @@
-#![allow(dead_code)]
-use async_ftp::FtpStream;
-use async_std::fs::File;
+use crate::ftp::checksum::{self, Algorithm, Method};
+use crate::ftp::error::{FtpError, Result};
+use crate::ftp::stream::{
+    DataStream, FtpConfig, FtpStream, COMMAND_OK, FILE_STATUS, REQUESTED_FILE_ACTION_OK,
+};
 use colorful::{Color, Colorful};
-use futures_util::AsyncWriteExt;
-use std::str::from_utf8;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+// 每次从数据连接读取的大小
+const BUFFER_SIZE: usize = 64 * 1024;
 
 pub struct FTP {
     ftpstream: FtpStream,
+    // 下载后是否校验摘要
+    verify: bool,
+    // 获取服务器摘要的方式,None表示还没有发送FEAT
+    checksum: Option<Option<Method>>,
 }
 
 impl FTP {
     /// 登录ftp服务器
-    pub async fn login(address: &str, user: &str, password: &str) -> Self {
-        let mut ftp_stream = FtpStream::connect(address).await.unwrap();
-        ftp_stream.login(&user, &password).await.unwrap();
-        println!("{}", "Login Ok!".color(Color::Red));
-        FTP {
-            ftpstream: ftp_stream,
+    pub async fn login(address: &str, user: &str, password: &str) -> Result<Self> {
+        FTP::login_with_config(address, user, password, &FtpConfig::new()).await
+    }
+
+    /// 按照指定的配置登录ftp服务器
+    pub async fn login_with_config(
+        address: &str,
+        user: &str,
+        password: &str,
+        config: &FtpConfig,
+    ) -> Result<Self> {
+        let mut ftp_stream = FtpStream::connect(address, config).await?;
+        ftp_stream.login(user, password).await?;
+        if ftp_stream.is_secure() {
+            println!("{}", "Login Ok! (TLS)".color(Color::Red));
+        } else {
+            println!("{}", "Login Ok!".color(Color::Red));
         }
+        Ok(FTP {
+            ftpstream: ftp_stream,
+            verify: config.get_verify(),
+            checksum: None,
+        })
     }
-    /// 打印当前目录文件
-    pub async fn list(&mut self, path: Option<&str>) {
-        println!("{:?}", path);
-        let name_list = self.ftpstream.list(path).await.unwrap_or(Vec::new());
-        name_list.into_iter().for_each(|name| {
-            println!("{}", name);
-        });
+
+    /// 获取目录的详细信息
+    pub async fn list(&mut self, path: Option<&str>) -> Result<Vec<String>> {
+        self.ftpstream.list(path).await
+    }
+
+    /// 获取目录下的文件名称
+    pub async fn nlst(&mut self, path: Option<&str>) -> Result<Vec<String>> {
+        self.ftpstream.nlst(path).await
+    }
+
+    /// 获取文件大小,不支持SIZE命令时返回None
+    pub async fn size(&mut self, filename: &str) -> Result<Option<u64>> {
+        self.ftpstream.size(filename).await
     }
 
     /// 进入某个目录下
-    pub async fn cwd(&mut self, path: &str) {
-        self.ftpstream.cwd(path).await.unwrap();
+    pub async fn cwd(&mut self, path: &str) -> Result<()> {
+        self.ftpstream.cwd(path).await
     }
 
-    /// 下载某个文件到指定目录下
-    pub async fn download(&mut self, filename: &str, target: &str) -> bool {
+    /// 获取当前目录
+    pub async fn pwd(&mut self) -> Result<String> {
+        self.ftpstream.pwd().await
+    }
+
+    /// 获取服务器状态,指定路径时获取文件或目录的状态
+    pub async fn stat(&mut self, path: Option<&str>) -> Result<String> {
+        self.ftpstream.stat(path).await
+    }
+
+    /// 下载某个文件到指定目录下,返回下载的字节数
+    pub async fn download(&mut self, filename: &str, target: &str) -> Result<u64> {
         println!(
             "{}",
             format!("download {}.......", filename).gradient(Color::Green)
         );
-        let remote_file = self.ftpstream.simple_retr(filename).await.unwrap();
-        let vec_data = remote_file.into_inner();
-        let target_path = target.to_string() + filename;
-        let mut file = File::create(target_path).await.unwrap();
-        let file_content = from_utf8(vec_data.as_slice()).unwrap();
-        file.write_all(file_content.as_bytes()).await.unwrap();
+        // 创建进度条,获取不到大小时只显示已下载的字节数
+        let size = match self.size(filename).await {
+            Ok(size) => size,
+            Err(e) if e.is_network() => return Err(e),
+            Err(_) => None,
+        };
+        let pb = ProgressBar::new(size.unwrap_or(0));
+        pb.set_style(progress_style());
+        let downloaded = self.fetch(filename, target, &pb, false).await;
+        pb.finish();
+        let downloaded = downloaded?;
         println!("{}", "download oK.......".gradient(Color::Green));
-        true
+        let target_path = Path::new(target).join(filename);
+        if let Some(algorithm) = self.verify(filename, &target_path).await? {
+            println!(
+                "{}",
+                format!("{} checksum ok", algorithm.hash_name()).gradient(Color::Green)
+            );
+        }
+        Ok(downloaded)
+    }
+
+    /// 向服务器请求文件的摘要并与本地文件比较,返回使用的算法
+    /// 服务器不支持或者无法获取摘要时跳过校验,返回None
+    pub async fn verify(&mut self, filename: &str, local: &Path) -> Result<Option<Algorithm>> {
+        let method = match self.checksum_method().await? {
+            Some(method) => method,
+            None => return Ok(None),
+        };
+        let algorithm = method.algorithm();
+        let reply = match method {
+            Method::Hash { select, .. } => {
+                if select {
+                    // 只需要选择一次算法,选择失败时不再校验
+                    let cmd = format!("OPTS HASH {}", algorithm.hash_name());
+                    let selected = self.ftpstream.command(&cmd, &[COMMAND_OK]).await;
+                    self.checksum = match &selected {
+                        Ok(_) => Some(Some(Method::Hash {
+                            algorithm,
+                            select: false,
+                        })),
+                        Err(_) => Some(None),
+                    };
+                    match selected {
+                        Err(e) if e.is_network() => return Err(e),
+                        Err(_) => return Ok(None),
+                        Ok(_) => {}
+                    }
+                }
+                let cmd = format!("HASH {}", filename);
+                self.ftpstream.checksum_command(&cmd, &[FILE_STATUS]).await
+            }
+            Method::Command(_) => {
+                let cmd = format!("{} {}", algorithm.command(), filename);
+                self.ftpstream
+                    .checksum_command(&cmd, &[REQUESTED_FILE_ACTION_OK, COMMAND_OK])
+                    .await
+            }
+        };
+        let remote = match reply {
+            Ok(reply) => checksum::extract_digest(&reply.message, method),
+            Err(e) if e.is_network() => return Err(e),
+            Err(e) => {
+                debug!("skip checksum of {}: {}", filename, e);
+                None
+            }
+        };
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Ok(None),
+        };
+        // 计算大文件的摘要比较耗时,放到阻塞线程中
+        let path = local.to_path_buf();
+        let local = tokio::task::spawn_blocking(move || checksum::file_digest(&path, algorithm))
+            .await
+            .map_err(|e| FtpError::Io(io::Error::other(e)))?
+            .map_err(FtpError::Io)?;
+        if checksum::same_digest(algorithm, &remote, &local) {
+            Ok(Some(algorithm))
+        } else {
+            Err(FtpError::ChecksumMismatch {
+                filename: filename.to_string(),
+                algorithm: algorithm.hash_name(),
+                remote,
+                local,
+            })
+        }
+    }
+
+    /// 第一次校验时通过FEAT选择获取摘要的方式
+    async fn checksum_method(&mut self) -> Result<Option<Method>> {
+        if !self.verify {
+            return Ok(None);
+        }
+        if self.checksum.is_none() {
+            let method = match self.ftpstream.feat().await {
+                Ok(features) => checksum::choose(&features),
+                Err(e) if e.is_network() => return Err(e),
+                Err(_) => None,
+            };
+            self.checksum = Some(method);
+        }
+        Ok(self.checksum.flatten())
+    }
+
+    /// 下载文件并更新进度条,返回本次下载的字节数
+    /// resume为true时从本地已有文件的末尾继续下载
+    pub async fn fetch(
+        &mut self,
+        filename: &str,
+        target: &str,
+        pb: &ProgressBar,
+        resume: bool,
+    ) -> Result<u64> {
+        let target_path = Path::new(target).join(filename);
+        let offset = match tokio::fs::metadata(&target_path).await {
+            Ok(metadata) if resume => metadata.len(),
+            _ => 0,
+        };
+        if offset > 0 {
+            self.ftpstream.restart_from(offset).await?;
+        }
+        let mut remote_file = self.ftpstream.retr(filename).await?;
+        match self.receive(&mut remote_file, &target_path, offset, pb).await {
+            Ok(downloaded) => {
+                remote_file.close().await;
+                self.ftpstream.finish_transfer().await?;
+                Ok(downloaded)
+            }
+            // 网络错误时会话已经不可用,由调用者重新连接
+            Err(e) if e.is_network() => Err(e),
+            Err(e) => {
+                // 本地文件出错时中止传输,读取剩下的响应后会话仍然可以使用
+                drop(remote_file);
+                self.ftpstream.abort().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 从数据连接读取文件写入本地文件,返回读取的字节数
+    async fn receive(
+        &mut self,
+        remote_file: &mut DataStream,
+        target_path: &Path,
+        offset: u64,
+        pb: &ProgressBar,
+    ) -> Result<u64> {
+        // 服务器开始发送后再创建本地文件,避免失败时留下空文件
+        let mut file = if offset > 0 {
+            OpenOptions::new().append(true).open(target_path).await
+        } else {
+            File::create(target_path).await
+        }
+        .map_err(FtpError::Io)?;
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut downloaded = 0;
+        loop {
+            let n = self.ftpstream.read_data(remote_file, &mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n]).await.map_err(FtpError::Io)?;
+            downloaded += n as u64;
+            pb.inc(n as u64);
+        }
+        file.flush().await.map_err(FtpError::Io)?;
+        Ok(downloaded)
     }
 
     /// 断开链接
-    pub async fn disconnect(&mut self) {
-        self.ftpstream.quit().await.unwrap();
+    pub async fn disconnect(&mut self) -> Result<()> {
+        self.ftpstream.quit().await
     }
 }
 
+/// 下载进度条的样式
+pub fn progress_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{spinner:.green} {bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}% {msg}")
+        .progress_chars("#>-")
+}
+
+#[cfg(test)]
 mod ftptest {
     use super::FTP;
     use std::fs;
@@ -68,26 +281,26 @@ mod ftptest {
         let address = "192.168.1.4:21";
         let user = "God";
         let passward = "52531225253.";
-        let mut ftp = FTP::login(address, user, passward).await;
-        ftp.disconnect().await;
+        let mut ftp = FTP::login(address, user, passward).await.unwrap();
+        ftp.disconnect().await.unwrap();
     }
 
     async fn async_ftp_list() {
         let address = "192.168.1.4:21";
         let user = "God";
         let passward = "52531225253.";
-        let mut ftp = FTP::login(address, user, passward).await;
-        ftp.list(None).await;
-        ftp.disconnect().await;
+        let mut ftp = FTP::login(address, user, passward).await.unwrap();
+        ftp.list(None).await.unwrap();
+        ftp.disconnect().await.unwrap();
     }
     async fn async_ftp_download() {
         // fs::remove_file("test.txt").unwrap();
         let address = "192.168.1.4:21";
         let user = "God";
         let passward = "52531225253.";
-        let mut ftp = FTP::login(address, user, passward).await;
-        ftp.download("test.txt", "").await;
-        ftp.disconnect().await;
+        let mut ftp = FTP::login(address, user, passward).await.unwrap();
+        ftp.download("test.txt", "").await.unwrap();
+        ftp.disconnect().await.unwrap();
         let dir = fs::read_dir("").unwrap();
         let find = dir
             .into_iter()
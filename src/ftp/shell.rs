@@ -0,0 +1,361 @@
+use crate::ftp::error::{FtpError, Result};
+use crate::ftp::mget::{expand, has_wildcard};
+use crate::ftp::myftp::FTP;
+use colorful::{Color, Colorful};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::io;
+use std::path::Path;
+
+/// 交互模式支持的命令
+const COMMANDS: [&str; 8] = ["ls", "cd", "pwd", "get", "size", "stat", "help", "quit"];
+
+const HELP: &str = "\
+ls [path]          list the current or the given directory
+cd <dir>           change the remote directory
+pwd                print the remote directory
+get [-r] <name>..  download files, names may contain wildcards, -r downloads directories
+size <name>..      print the size of files
+stat [name]        print the status of the server or a file
+quit               close the session";
+
+/// 命令行补全,远程文件名来自当前目录的缓存
+#[derive(Default)]
+struct ShellHelper {
+    names: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(line, pos, &self.names))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// 交互模式的会话
+struct Shell {
+    ftp: FTP,
+    // 下载文件的保存目录
+    output: String,
+    // 当前目录,用于提示符
+    cwd: String,
+    // 切换目录后需要刷新缓存的文件名
+    stale: bool,
+}
+
+/// 在已经登录的会话上运行交互模式,直到输入 quit 或者 Ctrl-D
+pub async fn run(ftp: FTP, output: &str) -> Result<()> {
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper::default()));
+    let mut shell = Shell {
+        ftp,
+        output: output.to_string(),
+        cwd: String::new(),
+        stale: true,
+    };
+    loop {
+        if shell.stale {
+            match shell.refresh().await {
+                Ok(names) => {
+                    if let Some(helper) = editor.helper_mut() {
+                        helper.names = names;
+                    }
+                }
+                Err(e) if e.is_network() => return Err(e),
+                Err(e) => println!("{}", e.to_string().color(Color::Red)),
+            }
+        }
+        let prompt = format!("ftp:{}> ", shell.cwd);
+        // 读取输入会阻塞线程,放到阻塞线程中读取后再取回编辑器
+        let (returned, line) = tokio::task::spawn_blocking(move || {
+            let line = editor.readline(&prompt);
+            (editor, line)
+        })
+        .await
+        .map_err(|e| FtpError::Io(io::Error::other(e)))?;
+        editor = returned;
+        let line = match line {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Io(e)) => return Err(FtpError::Io(e)),
+            Err(e) => return Err(FtpError::Io(io::Error::other(e))),
+        };
+        editor.add_history_entry(line.as_str());
+        let args = split_args(&line);
+        let (cmd, args) = match args.split_first() {
+            Some((cmd, args)) => (cmd.as_str(), args),
+            None => continue,
+        };
+        match shell.execute(cmd, args).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            // 连接断开时退出交互模式
+            Err(e) if e.is_network() => return Err(e),
+            Err(e) => println!("{}", e.to_string().color(Color::Red)),
+        }
+    }
+    shell.ftp.disconnect().await
+}
+
+impl Shell {
+    /// 刷新当前目录和文件名缓存
+    async fn refresh(&mut self) -> Result<Vec<String>> {
+        // 出错时也不再重试,直到下一次切换目录
+        self.stale = false;
+        self.cwd = self.ftp.pwd().await?;
+        match self.ftp.nlst(None).await {
+            Ok(names) => Ok(names.into_iter().map(base_name).collect()),
+            Err(e) if e.is_network() => Err(e),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// 执行一条命令,返回是否退出
+    async fn execute(&mut self, cmd: &str, args: &[String]) -> Result<bool> {
+        match cmd {
+            "ls" | "dir" => {
+                for line in self.ftp.list(args.first().map(|s| s.as_str())).await? {
+                    println!("{}", line);
+                }
+            }
+            "cd" => match args.first() {
+                Some(dir) => {
+                    self.ftp.cwd(dir).await?;
+                    self.stale = true;
+                }
+                None => println!("usage: cd <dir>"),
+            },
+            "pwd" => println!("{}", self.ftp.pwd().await?),
+            "get" => self.get(args).await?,
+            "size" => {
+                for name in args {
+                    match self.ftp.size(name).await? {
+                        Some(size) => println!("{} {}", name, size),
+                        None => println!("{}: size unknown", name),
+                    }
+                }
+            }
+            "stat" => println!("{}", self.ftp.stat(args.first().map(|s| s.as_str())).await?),
+            "help" | "?" => println!("{}", HELP),
+            "quit" | "exit" | "bye" => return Ok(true),
+            _ => println!(
+                "{}",
+                format!("unknown command {}, type help for a list of commands", cmd)
+                    .color(Color::Red)
+            ),
+        }
+        Ok(false)
+    }
+
+    /// 下载文件,支持通配符,-r 时递归下载目录
+    async fn get(&mut self, args: &[String]) -> Result<()> {
+        let recursive = args.iter().any(|arg| arg == "-r");
+        let names: Vec<&String> = args.iter().filter(|arg| *arg != "-r").collect();
+        if names.is_empty() {
+            println!("usage: get [-r] <name>..");
+            return Ok(());
+        }
+        let output = self.output.clone();
+        for name in names {
+            if has_wildcard(name) {
+                let files = expand(&mut self.ftp, name).await?;
+                if files.is_empty() {
+                    println!("{}", format!("no file matches {}", name).color(Color::Red));
+                }
+                for file in files {
+                    self.download(&file.name, &output).await?;
+                }
+            } else if recursive && self.is_directory(name).await? {
+                self.get_tree(name).await?;
+            } else {
+                self.download(name, &output).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 下载单个文件,只有网络错误会中断后续的下载
+    async fn download(&mut self, name: &str, target: &str) -> Result<()> {
+        match self.ftp.download(name, target).await {
+            Err(e) if !e.is_network() => {
+                println!("{}", format!("{}: {}", name, e).color(Color::Red));
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// 无法获取大小的条目视为目录
+    async fn is_directory(&mut self, name: &str) -> Result<bool> {
+        match self.ftp.size(name).await {
+            Ok(Some(_)) => Ok(false),
+            Err(e) if e.is_network() => Err(e),
+            _ => Ok(true),
+        }
+    }
+
+    /// 递归下载目录,保存在 output/目录名 下,结束后回到原来的目录
+    async fn get_tree(&mut self, dir: &str) -> Result<()> {
+        let home = self.ftp.pwd().await?;
+        // 远程目录使用绝对路径,本地只使用目录名
+        let root = if dir.starts_with('/') {
+            dir.trim_end_matches('/').to_string()
+        } else {
+            format!("{}/{}", home.trim_end_matches('/'), dir.trim_end_matches('/'))
+        };
+        let local = Path::new(&self.output).join(base_name(root.clone()));
+        let mut stack = vec![(root, local)];
+        while let Some((remote, local)) = stack.pop() {
+            if let Err(e) = self.ftp.cwd(&remote).await {
+                if e.is_network() {
+                    return Err(e);
+                }
+                println!("{}", format!("skip {}: {}", remote, e).color(Color::Red));
+                continue;
+            }
+            std::fs::create_dir_all(&local).map_err(FtpError::Io)?;
+            let target = local.to_string_lossy().to_string();
+            for name in self.ftp.nlst(None).await? {
+                let name = base_name(name);
+                if name == "." || name == ".." || name.is_empty() {
+                    continue;
+                }
+                if self.is_directory(&name).await? {
+                    stack.push((format!("{}/{}", remote, name), local.join(&name)));
+                } else {
+                    self.download(&name, &target).await?;
+                }
+            }
+        }
+        self.ftp.cwd(&home).await
+    }
+}
+
+/// 部分服务器的 NLST 返回的是路径,只保留最后一段
+fn base_name(name: String) -> String {
+    match name.rsplit_once('/') {
+        Some((_, name)) => name.to_string(),
+        None => name,
+    }
+}
+
+/// 按照空白切分命令参数,双引号中的空白不切分
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut has_arg = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// 补全光标所在的单词,第一个单词补全命令,其余补全远程文件名
+/// 返回单词的起始位置和候选项,含有空白的文件名会加上双引号
+fn complete(line: &str, pos: usize, names: &[String]) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in before.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c.is_whitespace() && !quoted {
+            start = i + c.len_utf8();
+        }
+    }
+    let word = before[start..].trim_start_matches('"');
+    let candidates: Vec<String> = if before[..start].trim().is_empty() {
+        COMMANDS
+            .iter()
+            .filter(|cmd| cmd.starts_with(word))
+            .map(|cmd| cmd.to_string())
+            .collect()
+    } else {
+        names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .map(|name| {
+                if name.contains(char::is_whitespace) {
+                    format!("\"{}\"", name)
+                } else {
+                    name.to_string()
+                }
+            })
+            .collect()
+    };
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod shell_test {
+    use super::{complete, split_args};
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args("get -r  logs"), vec!["get", "-r", "logs"]);
+        assert_eq!(
+            split_args("get \"my file.txt\" b"),
+            vec!["get", "my file.txt", "b"]
+        );
+        assert_eq!(split_args("cd \"\""), vec!["cd", ""]);
+        assert!(split_args("   ").is_empty());
+    }
+
+    #[test]
+    fn test_complete() {
+        let names = vec![
+            "hello.txt".to_string(),
+            "help me.txt".to_string(),
+            "pub".to_string(),
+        ];
+        assert_eq!(complete("g", 1, &names), (0, vec!["get".to_string()]));
+        assert_eq!(complete("get p", 5, &names), (4, vec!["pub".to_string()]));
+        let (start, candidates) = complete("get hel", 7, &names);
+        assert_eq!(start, 4);
+        assert_eq!(candidates, vec!["hello.txt", "\"help me.txt\""]);
+        assert_eq!(
+            complete("get \"help m", 11, &names).1,
+            vec!["\"help me.txt\""]
+        );
+    }
+}
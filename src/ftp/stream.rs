@@ -0,0 +1,1114 @@
+use crate::ftp::error::{FtpError, Result};
+use openssl::ssl::{SslConnector, SslMethod, SslSession, SslSessionCacheMode, SslVerifyMode};
+use openssl::x509::X509;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    ReadBuf,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_openssl::SslStream;
+
+// 服务器响应码
+pub const ABOUT_TO_SEND: u32 = 150;
+pub const ALREADY_OPEN: u32 = 125;
+pub const COMMAND_OK: u32 = 200;
+pub const SYSTEM_STATUS: u32 = 211;
+pub const DIRECTORY_STATUS: u32 = 212;
+pub const FILE_STATUS: u32 = 213;
+pub const RESTART_PENDING: u32 = 350;
+pub const READY: u32 = 220;
+pub const CLOSING: u32 = 221;
+pub const CLOSING_DATA: u32 = 226;
+pub const PASSIVE_MODE: u32 = 227;
+pub const EXTENDED_PASSIVE_MODE: u32 = 229;
+pub const LOGGED_IN: u32 = 230;
+pub const AUTH_OK: u32 = 234;
+pub const REQUESTED_FILE_ACTION_OK: u32 = 250;
+pub const PATH_CREATED: u32 = 257;
+pub const NEED_PASSWORD: u32 = 331;
+
+/// 连接的加密方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    /// 明文传输
+    Plain,
+    /// 显式加密,连接后发送 AUTH TLS 升级
+    Explicit,
+    /// 隐式加密,建立连接后直接进行TLS握手,默认端口990
+    Implicit,
+}
+
+/// 数据连接模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    /// 被动模式,客户端连接服务器 PASV 返回的地址
+    Passive,
+    /// 扩展被动模式,只返回端口,地址与控制连接相同,适用于NAT和IPv6
+    ExtendedPassive,
+    /// 主动模式,服务器连接客户端 PORT/EPRT 发送的地址
+    Active,
+}
+
+/// ftp连接配置
+#[derive(Debug, Clone)]
+pub struct FtpConfig {
+    security: Security,
+    // 自定义的CA证书文件(PEM格式,可以包含多个证书)
+    ca_file: Option<String>,
+    // 是否跳过证书校验,用于内网的自签名服务器
+    insecure: bool,
+    // 数据连接模式
+    mode: TransferMode,
+    // 替换 PASV 返回的地址,用于配置错误的NAT服务器
+    pasv_address: Option<IpAddr>,
+    // 建立连接(包括TLS握手)的超时时间
+    connect_timeout: Duration,
+    // 等待服务器响应的超时时间
+    read_timeout: Duration,
+    // 数据连接没有收到数据的超时时间
+    transfer_timeout: Duration,
+    // 等待摘要命令响应的超时时间,服务器需要读完整个文件才会回复
+    checksum_timeout: Duration,
+    // 传输过程中在控制连接上发送NOOP的间隔,None表示不发送
+    keepalive: Option<Duration>,
+    // 会话断开后重新连接的次数
+    retries: u32,
+    // 下载完成后是否向服务器请求摘要进行校验
+    verify: bool,
+}
+
+impl FtpConfig {
+    pub fn new() -> Self {
+        Self {
+            security: Security::Plain,
+            ca_file: None,
+            insecure: false,
+            mode: TransferMode::Passive,
+            pasv_address: None,
+            connect_timeout: Duration::from_secs(15),
+            read_timeout: Duration::from_secs(30),
+            transfer_timeout: Duration::from_secs(60),
+            checksum_timeout: Duration::from_secs(300),
+            keepalive: Some(Duration::from_secs(30)),
+            retries: 3,
+            verify: true,
+        }
+    }
+    /// 设置加密方式
+    pub fn set_security(mut self, security: Security) -> Self {
+        self.security = security;
+        self
+    }
+    /// 设置CA证书文件
+    pub fn set_ca_file(mut self, ca_file: Option<String>) -> Self {
+        self.ca_file = ca_file;
+        self
+    }
+    /// 设置是否跳过证书校验
+    pub fn set_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+    /// 设置数据连接模式
+    pub fn set_mode(mut self, mode: TransferMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    /// 设置替换 PASV 响应的地址
+    pub fn set_pasv_address(mut self, pasv_address: Option<IpAddr>) -> Self {
+        self.pasv_address = pasv_address;
+        self
+    }
+    /// 设置连接超时时间
+    pub fn set_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+    /// 设置等待响应的超时时间
+    pub fn set_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+    /// 设置数据传输的超时时间
+    pub fn set_transfer_timeout(mut self, transfer_timeout: Duration) -> Self {
+        self.transfer_timeout = transfer_timeout;
+        self
+    }
+    /// 设置等待摘要命令响应的超时时间
+    pub fn set_checksum_timeout(mut self, checksum_timeout: Duration) -> Self {
+        self.checksum_timeout = checksum_timeout;
+        self
+    }
+    /// 设置发送NOOP的间隔
+    pub fn set_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+    /// 设置重新连接的次数
+    pub fn set_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// 设置下载后是否校验摘要
+    pub fn set_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+    /// 下载后是否校验摘要
+    pub fn get_verify(&self) -> bool {
+        self.verify
+    }
+    /// 获取重新连接的次数
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+    /// 获取加密方式
+    pub fn get_security(&self) -> Security {
+        self.security
+    }
+    /// 获取数据连接模式
+    pub fn get_mode(&self) -> TransferMode {
+        self.mode
+    }
+
+    /// 根据配置构建TLS连接器,记录服务器发送的会话供数据连接复用
+    fn tls_context(&self, domain: &str) -> Result<TlsContext> {
+        let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+        if let Some(ca_file) = &self.ca_file {
+            let bundle = std::fs::read_to_string(ca_file).map_err(FtpError::Io)?;
+            for pem in split_pem_bundle(&bundle) {
+                builder
+                    .cert_store_mut()
+                    .add_cert(X509::from_pem(pem.as_bytes())?)?;
+            }
+        }
+        if self.insecure {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        let session = Arc::new(Mutex::new(None));
+        let latest = session.clone();
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+        builder.set_new_session_callback(move |_, new_session| {
+            *latest.lock().unwrap() = Some(new_session);
+        });
+        Ok(TlsContext {
+            connector: builder.build(),
+            domain: domain.to_string(),
+            verify_hostname: !self.insecure,
+            session,
+        })
+    }
+}
+
+impl Default for FtpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 控制连接的TLS设置,数据连接复用控制连接的TLS会话
+/// 开启 require_ssl_reuse 的服务器拒绝没有复用会话的数据连接
+#[derive(Clone)]
+struct TlsContext {
+    connector: SslConnector,
+    // 校验证书使用的域名
+    domain: String,
+    verify_hostname: bool,
+    // 服务器最近发送的会话
+    session: Arc<Mutex<Option<SslSession>>>,
+}
+
+impl TlsContext {
+    /// 在tcp连接上进行TLS握手,已经有会话时复用这个会话
+    async fn connect(&self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        let mut ssl = self
+            .connector
+            .configure()?
+            .verify_hostname(self.verify_hostname)
+            .into_ssl(&self.domain)?;
+        if let Some(session) = self.session.lock().unwrap().as_ref() {
+            // 会话来自同一个连接器创建的连接
+            unsafe { ssl.set_session(session)? };
+        }
+        let mut stream = SslStream::new(ssl, stream)?;
+        Pin::new(&mut stream).connect().await?;
+        Ok(stream)
+    }
+}
+
+/// 控制连接和数据连接使用的底层流
+pub enum DataStream {
+    Tcp(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl DataStream {
+    /// 获取底层的tcp连接
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            DataStream::Tcp(stream) => stream,
+            DataStream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// 是否已经加密
+    pub fn is_tls(&self) -> bool {
+        matches!(self, DataStream::Tls(_))
+    }
+
+    /// 读到结尾后关闭数据连接,服务器已经关闭连接时出错不影响传输的结果
+    pub async fn close(mut self) {
+        if let Err(e) = self.shutdown().await {
+            debug!("could not shut down data connection: {}", e);
+        }
+    }
+
+    fn into_tcp_stream(self) -> TcpStream {
+        match self {
+            DataStream::Tcp(stream) => stream,
+            DataStream::Tls(_) => unreachable!("connection is already secured"),
+        }
+    }
+}
+
+impl AsyncRead for DataStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            DataStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for DataStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            DataStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            DataStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            DataStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            DataStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// 服务器返回的响应
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub code: u32,
+    pub message: String,
+}
+
+/// ftp控制连接
+pub struct FtpStream {
+    reader: BufReader<DataStream>,
+    // 数据连接加密时使用的TLS设置
+    tls: Option<TlsContext>,
+    // 是否已经发送 PROT P
+    protected: bool,
+    welcome_msg: Option<String>,
+    config: FtpConfig,
+    // 传输过程中发送的还未收到响应的NOOP数量
+    pending_noops: usize,
+    // 上一次在控制连接上发送命令的时间
+    last_command: Instant,
+}
+
+impl FtpStream {
+    fn new(stream: DataStream, tls: Option<TlsContext>, config: FtpConfig) -> Self {
+        FtpStream {
+            reader: BufReader::new(stream),
+            tls,
+            protected: false,
+            welcome_msg: None,
+            config,
+            pending_noops: 0,
+            last_command: Instant::now(),
+        }
+    }
+
+    /// 连接ftp服务器,按照配置完成TLS握手
+    pub async fn connect(address: &str, config: &FtpConfig) -> Result<FtpStream> {
+        match timeout(config.connect_timeout, FtpStream::open(address, config)).await {
+            Ok(result) => result,
+            Err(_) => Err(FtpError::timeout(&format!(
+                "timed out connecting to {}",
+                address
+            ))),
+        }
+    }
+
+    async fn open(address: &str, config: &FtpConfig) -> Result<FtpStream> {
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            FtpError::Network(io::Error::new(
+                e.kind(),
+                format!("could not connect to {}: {}", address, e),
+            ))
+        })?;
+        let domain = host_of(address);
+        let mut ftp_stream = match config.get_security() {
+            Security::Implicit => {
+                // 隐式加密,直接握手
+                let tls = config.tls_context(&domain)?;
+                let stream = tls.connect(stream).await?;
+                FtpStream::new(DataStream::Tls(Box::new(stream)), Some(tls), config.clone())
+            }
+            _ => FtpStream::new(DataStream::Tcp(stream), None, config.clone()),
+        };
+        let reply = ftp_stream.expect_reply(&[READY]).await?;
+        ftp_stream.welcome_msg = Some(reply.message);
+        if config.get_security() == Security::Explicit {
+            ftp_stream = ftp_stream.into_secure(config.tls_context(&domain)?).await?;
+        }
+        Ok(ftp_stream)
+    }
+
+    /// 显式加密: 发送 AUTH TLS 后将控制连接升级为TLS连接
+    async fn into_secure(mut self, tls: TlsContext) -> Result<FtpStream> {
+        self.command("AUTH TLS", &[AUTH_OK]).await?;
+        let stream = self.reader.into_inner().into_tcp_stream();
+        let stream = tls.connect(stream).await?;
+        let mut ftp_stream =
+            FtpStream::new(DataStream::Tls(Box::new(stream)), Some(tls), self.config);
+        ftp_stream.welcome_msg = self.welcome_msg;
+        Ok(ftp_stream)
+    }
+
+    /// 获取服务器的欢迎信息
+    pub fn get_welcome_msg(&self) -> Option<&str> {
+        self.welcome_msg.as_deref()
+    }
+
+    /// 控制连接是否已经加密
+    pub fn is_secure(&self) -> bool {
+        self.reader.get_ref().is_tls()
+    }
+
+    /// 登录,加密连接在登录后设置数据连接保护级别
+    pub async fn login(&mut self, user: &str, password: &str) -> Result<()> {
+        let reply = self
+            .command(&format!("USER {}", user), &[LOGGED_IN, NEED_PASSWORD])
+            .await?;
+        if reply.code == NEED_PASSWORD {
+            self.command(&format!("PASS {}", password), &[LOGGED_IN, 202])
+                .await?;
+        }
+        if self.is_secure() {
+            self.command("PBSZ 0", &[COMMAND_OK]).await?;
+            self.command("PROT P", &[COMMAND_OK]).await?;
+            self.protected = true;
+        }
+        // 所有文件都按照二进制传输
+        self.command("TYPE I", &[COMMAND_OK]).await?;
+        Ok(())
+    }
+
+    /// 进入目录
+    pub async fn cwd(&mut self, path: &str) -> Result<()> {
+        self.command(&format!("CWD {}", path), &[REQUESTED_FILE_ACTION_OK])
+            .await?;
+        Ok(())
+    }
+
+    /// 获取当前目录
+    pub async fn pwd(&mut self) -> Result<String> {
+        let reply = self.command("PWD", &[PATH_CREATED]).await?;
+        match (reply.message.find('"'), reply.message.rfind('"')) {
+            (Some(begin), Some(end)) if begin < end => Ok(reply.message[begin + 1..end].to_string()),
+            _ => Err(FtpError::Protocol(format!(
+                "invalid PWD response: {}",
+                reply.message
+            ))),
+        }
+    }
+
+    /// 空操作
+    pub async fn noop(&mut self) -> Result<()> {
+        self.command("NOOP", &[COMMAND_OK]).await?;
+        Ok(())
+    }
+
+    /// 获取文件大小
+    pub async fn size(&mut self, path: &str) -> Result<Option<u64>> {
+        let reply = self.command(&format!("SIZE {}", path), &[FILE_STATUS]).await?;
+        Ok(reply.message.trim().parse::<u64>().ok())
+    }
+
+    /// 获取服务器支持的扩展特性,每行一个
+    pub async fn feat(&mut self) -> Result<Vec<String>> {
+        let reply = self.command("FEAT", &[SYSTEM_STATUS]).await?;
+        Ok(reply
+            .message
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    /// 获取服务器状态,指定路径时获取文件或目录的状态
+    pub async fn stat(&mut self, path: Option<&str>) -> Result<String> {
+        let cmd = match path {
+            Some(path) => format!("STAT {}", path),
+            None => "STAT".to_string(),
+        };
+        let reply = self
+            .command(&cmd, &[SYSTEM_STATUS, DIRECTORY_STATUS, FILE_STATUS])
+            .await?;
+        Ok(reply.message)
+    }
+
+    /// 列出目录的详细信息
+    pub async fn list(&mut self, path: Option<&str>) -> Result<Vec<String>> {
+        let cmd = match path {
+            Some(path) => format!("LIST {}", path),
+            None => "LIST".to_string(),
+        };
+        self.list_command(&cmd).await
+    }
+
+    /// 列出目录下的文件名称
+    pub async fn nlst(&mut self, path: Option<&str>) -> Result<Vec<String>> {
+        let cmd = match path {
+            Some(path) => format!("NLST {}", path),
+            None => "NLST".to_string(),
+        };
+        self.list_command(&cmd).await
+    }
+
+    async fn list_command(&mut self, cmd: &str) -> Result<Vec<String>> {
+        let mut data = self.data_command(cmd).await?;
+        let mut content = Vec::new();
+        let mut buf = vec![0; 8192];
+        loop {
+            let n = self.read_data(&mut data, &mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            content.extend_from_slice(&buf[..n]);
+        }
+        data.close().await;
+        let content = String::from_utf8_lossy(&content);
+        self.finish_transfer().await?;
+        Ok(content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    /// 开始下载文件,返回数据连接
+    /// 读取完毕后需要关闭数据连接并调用 finish_transfer
+    pub async fn retr(&mut self, filename: &str) -> Result<DataStream> {
+        self.data_command(&format!("RETR {}", filename)).await
+    }
+
+    /// 设置下一次下载的起始位置,用于断点续传
+    pub async fn restart_from(&mut self, offset: u64) -> Result<()> {
+        self.command(&format!("REST {}", offset), &[RESTART_PENDING])
+            .await?;
+        Ok(())
+    }
+
+    /// 从数据连接读取数据
+    /// 数据连接长时间没有数据时返回超时错误,期间按照间隔在控制连接上发送NOOP
+    pub async fn read_data(&mut self, data: &mut DataStream, buf: &mut [u8]) -> Result<usize> {
+        let deadline = Instant::now() + self.config.transfer_timeout;
+        loop {
+            self.keepalive().await?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(FtpError::timeout("timed out waiting for data from server"));
+            }
+            let mut wait = deadline - now;
+            if let Some(interval) = self.config.keepalive {
+                wait = wait.min(interval);
+            }
+            if let Ok(n) = timeout(wait, data.read(buf)).await {
+                return Ok(n?);
+            }
+        }
+    }
+
+    /// 距离上一次命令超过间隔时发送NOOP,响应在传输结束时读取
+    async fn keepalive(&mut self) -> Result<()> {
+        if let Some(interval) = self.config.keepalive {
+            if self.last_command.elapsed() >= interval {
+                self.write_line("NOOP").await?;
+                self.pending_noops += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取数据传输结束的响应以及传输过程中NOOP的响应
+    pub async fn finish_transfer(&mut self) -> Result<()> {
+        let result = self.read_transfer_replies().await;
+        // 失败时无法确定还有多少响应没有读取,不再等待之前的NOOP
+        if result.is_err() {
+            self.pending_noops = 0;
+        }
+        result
+    }
+
+    async fn read_transfer_replies(&mut self) -> Result<()> {
+        // 传输结束的响应之前可能有NOOP的响应
+        let reply = loop {
+            let reply = self.next_reply().await?;
+            if reply.code != COMMAND_OK {
+                break reply;
+            }
+            self.pending_noops = self.pending_noops.saturating_sub(1);
+        };
+        while self.pending_noops > 0 {
+            self.expect_reply(&[COMMAND_OK]).await?;
+            self.pending_noops -= 1;
+        }
+        // 服务器传输失败时也已经读取了所有响应,会话仍然可用
+        match reply.code {
+            CLOSING_DATA | REQUESTED_FILE_ACTION_OK => Ok(()),
+            _ => Err(FtpError::from_reply(reply)),
+        }
+    }
+
+    /// 中止正在进行的传输,调用前需要关闭数据连接
+    /// 读取传输结束和 ABOR 的响应以及之前NOOP的响应,之后会话可以继续使用
+    /// 失败时控制连接的状态无法确定,返回网络错误,需要重新连接
+    pub async fn abort(&mut self) -> Result<()> {
+        let result = self.read_abort_replies().await;
+        self.pending_noops = 0;
+        result.map_err(|e| {
+            FtpError::Network(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("could not abort transfer: {}", e),
+            ))
+        })
+    }
+
+    async fn read_abort_replies(&mut self) -> Result<()> {
+        self.write_line("ABOR").await?;
+        // 传输结束的响应(226或426)和 ABOR 的响应(225或226),其余的是NOOP的响应
+        let mut replies = 0;
+        while replies < 2 {
+            if self.next_reply().await?.code != COMMAND_OK {
+                replies += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// 退出登录
+    pub async fn quit(&mut self) -> Result<()> {
+        self.command("QUIT", &[CLOSING]).await?;
+        Ok(())
+    }
+
+    /// 建立数据连接并发送需要数据连接的命令
+    async fn data_command(&mut self, cmd: &str) -> Result<DataStream> {
+        let ipv6 = self.control_ref().peer_addr()?.is_ipv6();
+        let stream = match self.config.get_mode() {
+            TransferMode::Active => {
+                let listener = self.port(ipv6).await?;
+                self.command(cmd, &[ABOUT_TO_SEND, ALREADY_OPEN]).await?;
+                let (stream, _) = timeout(self.config.connect_timeout, listener.accept())
+                    .await
+                    .map_err(|_| FtpError::timeout("timed out waiting for data connection"))??;
+                stream
+            }
+            mode => {
+                // IPv6 无法使用 PASV,只能使用 EPSV
+                let address = if mode == TransferMode::ExtendedPassive || ipv6 {
+                    self.epsv().await?
+                } else {
+                    self.pasv().await?
+                };
+                let stream = timeout(self.config.connect_timeout, TcpStream::connect(address))
+                    .await
+                    .map_err(|_| FtpError::timeout("timed out opening data connection"))??;
+                self.command(cmd, &[ABOUT_TO_SEND, ALREADY_OPEN]).await?;
+                stream
+            }
+        };
+        let tls = match (&self.tls, self.protected) {
+            (Some(tls), true) => tls.clone(),
+            _ => return Ok(DataStream::Tcp(stream)),
+        };
+        let secured = match timeout(self.config.connect_timeout, tls.connect(stream)).await {
+            Ok(result) => result,
+            Err(_) => Err(FtpError::timeout("timed out securing data connection")),
+        };
+        match secured {
+            Ok(stream) => Ok(DataStream::Tls(Box::new(stream))),
+            Err(e) => {
+                // 服务器已经开始传输,中止后才能发送下一个命令
+                self.abort().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 进入被动模式,获取数据连接的地址
+    async fn pasv(&mut self) -> Result<SocketAddr> {
+        let reply = self.command("PASV", &[PASSIVE_MODE]).await?;
+        let address = parse_pasv(&reply.message).ok_or_else(|| {
+            FtpError::Protocol(format!("invalid PASV response: {}", reply.message))
+        })?;
+        // 优先使用用户指定的地址,服务器返回0.0.0.0时使用控制连接的地址
+        if let Some(ip) = self.config.pasv_address {
+            return Ok(SocketAddr::new(ip, address.port()));
+        }
+        if address.ip().is_unspecified() {
+            let ip = self.control_ref().peer_addr()?.ip();
+            return Ok(SocketAddr::new(ip, address.port()));
+        }
+        Ok(address)
+    }
+
+    /// 进入扩展被动模式,数据连接使用控制连接的地址
+    async fn epsv(&mut self) -> Result<SocketAddr> {
+        let reply = self.command("EPSV", &[EXTENDED_PASSIVE_MODE]).await?;
+        let port = parse_epsv(&reply.message).ok_or_else(|| {
+            FtpError::Protocol(format!("invalid EPSV response: {}", reply.message))
+        })?;
+        let ip = match self.config.pasv_address {
+            Some(ip) => ip,
+            None => self.control_ref().peer_addr()?.ip(),
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// 进入主动模式,在控制连接的本地地址上监听并通知服务器
+    async fn port(&mut self, ipv6: bool) -> Result<TcpListener> {
+        let ip = self.control_ref().local_addr()?.ip();
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let address = listener.local_addr()?;
+        let cmd = if ipv6 {
+            eprt_command(address)
+        } else {
+            port_command(address)
+        };
+        self.command(&cmd, &[COMMAND_OK]).await?;
+        Ok(listener)
+    }
+
+    /// 控制连接的tcp连接
+    fn control_ref(&self) -> &TcpStream {
+        self.reader.get_ref().get_ref()
+    }
+
+    /// 发送命令并检查响应码
+    pub async fn command(&mut self, cmd: &str, expected: &[u32]) -> Result<Reply> {
+        self.write_line(cmd).await?;
+        self.expect_reply(expected).await
+    }
+
+    /// 发送 HASH XMD5 等摘要命令,等待响应的时间比普通命令长
+    pub async fn checksum_command(&mut self, cmd: &str, expected: &[u32]) -> Result<Reply> {
+        self.write_line(cmd).await?;
+        let reply = self.reply_within(self.config.checksum_timeout).await?;
+        if expected.contains(&reply.code) {
+            Ok(reply)
+        } else {
+            Err(FtpError::from_reply(reply))
+        }
+    }
+
+    /// 发送一行命令
+    async fn write_line(&mut self, cmd: &str) -> Result<()> {
+        debug!("CMD {}", if cmd.starts_with("PASS ") { "PASS ****" } else { cmd });
+        let stream = self.reader.get_mut();
+        stream.write_all(format!("{}\r\n", cmd).as_bytes()).await?;
+        stream.flush().await?;
+        self.last_command = Instant::now();
+        Ok(())
+    }
+
+    /// 读取一个响应,不检查响应码
+    async fn next_reply(&mut self) -> Result<Reply> {
+        self.reply_within(self.config.read_timeout).await
+    }
+
+    /// 在指定的时间内读取一个响应
+    async fn reply_within(&mut self, duration: Duration) -> Result<Reply> {
+        timeout(duration, read_reply(&mut self.reader))
+            .await
+            .map_err(|_| FtpError::timeout("timed out waiting for reply from server"))?
+    }
+
+    /// 读取响应并检查响应码
+    async fn expect_reply(&mut self, expected: &[u32]) -> Result<Reply> {
+        let reply = self.next_reply().await?;
+        if expected.contains(&reply.code) {
+            Ok(reply)
+        } else {
+            Err(FtpError::from_reply(reply))
+        }
+    }
+}
+
+/// 读取一个完整的响应,多行响应以 "xyz-" 开头,以 "xyz " 结尾
+pub async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Reply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(connection_closed());
+    }
+    debug!("FTP {}", line.trim_end());
+    // 响应码之后可能紧跟多字节字符,按字节检查边界
+    let (prefix, message) = match (line.get(0..3), line.get(4..)) {
+        (Some(prefix), Some(message)) => (prefix, message),
+        _ => return Err(FtpError::Protocol(format!("could not read reply code: {}", line.trim_end()))),
+    };
+    let code: u32 = prefix.parse().map_err(|_| {
+        FtpError::Protocol(format!("could not parse reply code: {}", line.trim_end()))
+    })?;
+    let end = format!("{} ", prefix);
+    let mut message = message.trim_end().to_string();
+    if line.as_bytes()[3] == b'-' {
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(connection_closed());
+            }
+            debug!("FTP {}", line.trim_end());
+            if line.starts_with(&end) {
+                break;
+            }
+            // 中间行可能带有 "xyz-" 前缀
+            let text = line.strip_prefix(&format!("{}-", code)).unwrap_or(&line);
+            message.push('\n');
+            message.push_str(text.trim_end());
+        }
+    }
+    Ok(Reply { code, message })
+}
+
+/// 服务器关闭了控制连接
+fn connection_closed() -> FtpError {
+    FtpError::Network(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by server",
+    ))
+}
+
+/// 解析 PASV 响应: 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+pub fn parse_pasv(message: &str) -> Option<SocketAddr> {
+    let begin = message.find('(')?;
+    let end = message[begin..].find(')')? + begin;
+    let numbers: Vec<u8> = message[begin + 1..end]
+        .split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect::<std::result::Result<_, _>>()
+        .ok()?;
+    if numbers.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(numbers[0], numbers[1], numbers[2], numbers[3]);
+    let port = ((numbers[4] as u16) << 8) + numbers[5] as u16;
+    Some(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+/// 解析 EPSV 响应: 229 Entering Extended Passive Mode (|||port|)
+pub fn parse_epsv(message: &str) -> Option<u16> {
+    let begin = message.find('(')?;
+    let end = message[begin..].find(')')? + begin;
+    let fields: Vec<&str> = message[begin + 1..end].split('|').collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    fields[3].parse::<u16>().ok()
+}
+
+/// 构建 PORT 命令: PORT h1,h2,h3,h4,p1,p2
+fn port_command(address: SocketAddr) -> String {
+    let ip = address.ip().to_string().replace('.', ",");
+    format!("PORT {},{},{}", ip, address.port() >> 8, address.port() & 0xff)
+}
+
+/// 构建 EPRT 命令: EPRT |协议|地址|端口|,协议1为IPv4,2为IPv6
+fn eprt_command(address: SocketAddr) -> String {
+    let protocol = if address.is_ipv6() { 2 } else { 1 };
+    format!("EPRT |{}|{}|{}|", protocol, address.ip(), address.port())
+}
+
+/// 从 host:port 中取出主机名,用于TLS校验
+fn host_of(address: &str) -> String {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']').to_string()
+}
+
+/// 切分包含多个证书的PEM文件
+fn split_pem_bundle(bundle: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    bundle
+        .split_inclusive(END)
+        .filter(|pem| pem.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|pem| pem.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod stream_test {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{Ssl, SslAcceptor};
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    /// 本地FTPS服务器使用的自签名证书
+    fn tls_acceptor() -> SslAcceptor {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert.build()).unwrap();
+        acceptor.set_session_id_context(b"ftp").unwrap();
+        acceptor.build()
+    }
+
+    async fn accept_tls(acceptor: &SslAcceptor, stream: TcpStream) -> SslStream<TcpStream> {
+        let ssl = Ssl::new(acceptor.context()).unwrap();
+        let mut stream = SslStream::new(ssl, stream).unwrap();
+        Pin::new(&mut stream).accept().await.unwrap();
+        stream
+    }
+
+    /// 只接受一个客户端的FTPS服务器,和 vsftpd 的 require_ssl_reuse 一样拒绝没有复用会话的数据连接
+    async fn serve_ftps(listener: TcpListener, acceptor: SslAcceptor, content: &[u8]) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"220 ready\r\n").await.unwrap();
+        let mut line = [0; 10];
+        stream.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"AUTH TLS\r\n");
+        stream.write_all(b"234 AUTH TLS ok\r\n").await.unwrap();
+        let mut control = BufReader::new(accept_tls(&acceptor, stream).await);
+
+        let mut data_listener = None;
+        loop {
+            let mut line = String::new();
+            control.read_line(&mut line).await.unwrap();
+            let reply = match line.split_whitespace().next().unwrap_or("") {
+                "USER" => "331 password required".to_string(),
+                "PASS" => "230 logged in".to_string(),
+                "PBSZ" | "PROT" | "TYPE" => "200 ok".to_string(),
+                "PASV" => {
+                    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                    let port = listener.local_addr().unwrap().port();
+                    data_listener = Some(listener);
+                    format!(
+                        "227 Entering Passive Mode (127,0,0,1,{},{})",
+                        port >> 8,
+                        port & 0xff
+                    )
+                }
+                "RETR" => {
+                    control.write_all(b"150 opening\r\n").await.unwrap();
+                    let (data, _) = data_listener.take().unwrap().accept().await.unwrap();
+                    let mut data = accept_tls(&acceptor, data).await;
+                    if data.ssl().session_reused() {
+                        data.write_all(content).await.unwrap();
+                        data.shutdown().await.unwrap();
+                        "226 transfer complete".to_string()
+                    } else {
+                        "522 session reuse required".to_string()
+                    }
+                }
+                _ => break,
+            };
+            control
+                .write_all(format!("{}\r\n", reply).as_bytes())
+                .await
+                .unwrap();
+        }
+        control.write_all(b"221 bye\r\n").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ftps_reuse_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve_ftps(listener, tls_acceptor(), b"hello ftps"));
+
+        let config = FtpConfig::new()
+            .set_security(Security::Explicit)
+            .set_insecure(true);
+        let mut ftp_stream = FtpStream::connect(&address, &config).await.unwrap();
+        assert!(ftp_stream.is_secure());
+        ftp_stream.login("user", "pass").await.unwrap();
+        // 每个数据连接都复用控制连接的会话
+        for _ in 0..2 {
+            let mut data = ftp_stream.retr("file").await.unwrap();
+            assert!(data.is_tls());
+            let mut content = Vec::new();
+            data.read_to_end(&mut content).await.unwrap();
+            data.close().await;
+            ftp_stream.finish_transfer().await.unwrap();
+            assert_eq!(content, b"hello ftps");
+        }
+        ftp_stream.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    /// 明文的FTP服务器,在第一个文件传输过程中等待客户端中止
+    async fn serve_abort(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut control = BufReader::new(stream);
+        control.write_all(b"220 ready\r\n").await.unwrap();
+        let mut data_listener = None;
+        let mut data = None;
+        loop {
+            let mut line = String::new();
+            control.read_line(&mut line).await.unwrap();
+            let reply = match line.split_whitespace().next().unwrap_or("") {
+                "USER" => "230 logged in".to_string(),
+                "TYPE" | "NOOP" => "200 ok".to_string(),
+                "PASV" => {
+                    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                    let port = listener.local_addr().unwrap().port();
+                    data_listener = Some(listener);
+                    format!("227 Entering Passive Mode (127,0,0,1,{},{})", port >> 8, port & 0xff)
+                }
+                "RETR" => {
+                    let (mut stream, _) = data_listener.take().unwrap().accept().await.unwrap();
+                    stream.write_all(b"partial").await.unwrap();
+                    data = Some(stream);
+                    "150 opening".to_string()
+                }
+                "ABOR" => {
+                    data = None;
+                    "426 transfer aborted\r\n226 ABOR successful".to_string()
+                }
+                _ => break,
+            };
+            control.write_all(format!("{}\r\n", reply).as_bytes()).await.unwrap();
+        }
+        drop(data);
+        control.write_all(b"221 bye\r\n").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_abort_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve_abort(listener));
+
+        let mut ftp_stream = FtpStream::connect(&address, &FtpConfig::new()).await.unwrap();
+        ftp_stream.login("anonymous", "").await.unwrap();
+        let mut data = ftp_stream.retr("file").await.unwrap();
+        let mut buf = [0; 7];
+        data.read_exact(&mut buf).await.unwrap();
+        drop(data);
+        // 中止后读取了所有响应,下一个命令得到自己的响应
+        ftp_stream.abort().await.unwrap();
+        ftp_stream.noop().await.unwrap();
+        ftp_stream.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let mut input: &[u8] = b"220-Welcome\r\n220-to the server\r\n220 ready\r\n230 Login ok\r\n";
+        let reply = tokio_test::block_on(read_reply(&mut input)).unwrap();
+        assert_eq!(reply.code, 220);
+        assert_eq!(reply.message, "Welcome\nto the server");
+        let reply = tokio_test::block_on(read_reply(&mut input)).unwrap();
+        assert_eq!(reply, Reply { code: 230, message: "Login ok".to_string() });
+        assert!(tokio_test::block_on(read_reply(&mut input)).is_err());
+        // 多字节字符跨过响应码的边界时返回错误
+        for line in ["22\u{e9}\r\n", "220\u{e9}\r\n"] {
+            let mut input = line.as_bytes();
+            let result = tokio_test::block_on(read_reply(&mut input));
+            assert!(matches!(result, Err(FtpError::Protocol(_))));
+        }
+        let mut input = "226 \u{e9}t\u{e9}\r\n".as_bytes();
+        assert_eq!(tokio_test::block_on(read_reply(&mut input)).unwrap().message, "\u{e9}t\u{e9}");
+    }
+
+    #[test]
+    fn test_parse_pasv() {
+        let address = parse_pasv("Entering Passive Mode (192,168,1,4,39,16).").unwrap();
+        assert_eq!(address, "192.168.1.4:10000".parse().unwrap());
+        assert!(parse_pasv("Entering Passive Mode (192,168,1,4,39)").is_none());
+    }
+
+    #[test]
+    fn test_parse_epsv() {
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||6446|)"), Some(6446));
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||)"), None);
+    }
+
+    #[test]
+    fn test_port_command() {
+        assert_eq!(port_command("192.168.1.4:10000".parse().unwrap()), "PORT 192,168,1,4,39,16");
+        assert_eq!(eprt_command("192.168.1.4:10000".parse().unwrap()), "EPRT |1|192.168.1.4|10000|");
+        assert_eq!(eprt_command("[::1]:2121".parse().unwrap()), "EPRT |2|::1|2121|");
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("ftp.example.com:21"), "ftp.example.com");
+        assert_eq!(host_of("[::1]:990"), "::1");
+        assert_eq!(host_of("192.168.1.4"), "192.168.1.4");
+    }
+
+    #[test]
+    fn test_config() {
+        let config = FtpConfig::new();
+        assert_eq!(config.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(config.get_retries(), 3);
+        let config = config.set_keepalive(None).set_retries(0);
+        assert_eq!(config.keepalive, None);
+        assert_eq!(config.get_retries(), 0);
+    }
+
+    #[test]
+    fn test_split_pem_bundle() {
+        let bundle = "-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(split_pem_bundle(bundle).len(), 2);
+    }
+}
@@ -1,5 +1,12 @@
+use crate::ftp;
+use crate::ftp::parser::FtpUrl;
+use crate::ftp::stream::FtpConfig;
 use crate::http::http::HttpDownloader;
 use crate::http::parser::CommandArgument;
+use crate::sftp;
+use crate::sftp::mysftp::SftpConfig;
+use crate::sftp::parser::SftpUrl;
+use anyhow::anyhow;
 use colorful::{Color, Colorful};
 
 pub mod http;
@@ -11,10 +18,43 @@ pub async fn execute() {
         //如果有实际的url则开始下载
         let downloader = HttpDownloader::new();
         let urls = command.get_url();
+        let output_path = command.get_output_path().unwrap();
         let mut downloader = downloader
             .set_concurrency(command.get_concurrency().unwrap())
-            .set_output_path(command.get_output_path().unwrap());
+            .set_output_path(output_path.clone());
         for url in urls {
+            // ftp链接交给ftp模块下载
+            if FtpUrl::is_ftp_url(&url) {
+                match FtpUrl::parse(&url) {
+                    Ok(ftp_url) => {
+                        if let Err(e) =
+                            ftp::download_url(&ftp_url, &FtpConfig::new(), &output_path, 1).await
+                        {
+                            println!("{}", format!("{}: {}", e, url).color(Color::Red))
+                        }
+                    }
+                    Err(e) => println!("{}", format!("{}: {}", e, url).color(Color::Red)),
+                }
+                continue;
+            }
+            // sftp链接交给sftp模块在阻塞线程中下载
+            if SftpUrl::is_sftp_url(&url) {
+                match SftpUrl::parse(&url) {
+                    Ok(sftp_url) => {
+                        let output = output_path.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            sftp::download_url(&sftp_url, &SftpConfig::new(), &output, false)
+                        })
+                        .await
+                        .unwrap_or_else(|e| Err(anyhow!("sftp download stopped: {}", e)));
+                        if let Err(e) = result {
+                            println!("{}", format!("{}: {}", e, url).color(Color::Red))
+                        }
+                    }
+                    Err(e) => println!("{}", format!("{}: {}", e, url).color(Color::Red)),
+                }
+                continue;
+            }
             downloader = downloader.set_url(url);
             downloader.download().await.unwrap();
         }
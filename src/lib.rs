@@ -4,3 +4,4 @@ extern crate log;
 pub mod bittorrent;
 pub mod ftp;
 pub mod http;
+pub mod sftp;
@@ -0,0 +1,53 @@
+pub mod mysftp;
+pub mod parser;
+
+use anyhow::Result;
+use colorful::{Color, Colorful};
+use mysftp::{SftpConfig, SFTP};
+use parser::{CommandArgument, SftpUrl};
+
+/// 按照sftp链接登录服务器,链接指向文件时下载该文件
+/// 指向目录时 recursive 为true则下载整个目录,否则打印目录内容
+pub fn download_url(
+    url: &SftpUrl,
+    config: &SftpConfig,
+    output: &str,
+    recursive: bool,
+) -> Result<()> {
+    let sftp = SFTP::login(
+        &url.host,
+        url.port,
+        &url.username,
+        url.password.as_deref(),
+        config,
+    )?;
+    if sftp.stat(&url.path)?.is_dir() {
+        if recursive {
+            sftp.download_dir(&url.path, output)?;
+        } else {
+            for (name, stat) in sftp.list(&url.path)? {
+                let kind = if stat.is_dir() { "d" } else { "-" };
+                println!("{} {:>12} {}", kind, stat.size.unwrap_or(0), name);
+            }
+        }
+    } else {
+        sftp.download(&url.path, output)?;
+    }
+    sftp.disconnect()
+}
+
+pub fn execute() {
+    let mut command = CommandArgument::new();
+    if let Ok(()) = command.parse() {
+        //如果参数解析正确
+        let url = command.get_url().unwrap();
+        let output = command.get_output().unwrap();
+        let config = command.get_config();
+        println!("target: {:?}", url.path);
+        if let Err(e) = download_url(&url, &config, output.as_str(), command.is_recursive()) {
+            println!("{}", e.to_string().color(Color::Red));
+        }
+    } else {
+        println!("{}", "Please check your entry".color(Color::Red));
+    }
+}
@@ -0,0 +1,441 @@
+use crate::ftp::myftp::progress_style;
+use anyhow::{anyhow, Result};
+use colorful::{Color, Colorful};
+use indicatif::ProgressBar;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, Session, Sftp};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// 每次从远程文件读取的大小
+const BUFFER_SIZE: usize = 64 * 1024;
+// 未指定密钥和密码时依次尝试的默认密钥
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// sftp连接配置
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    // 登录使用的私钥文件
+    identity_file: Option<String>,
+    // 私钥的密码
+    passphrase: Option<String>,
+    // 是否跳过 known_hosts 校验
+    insecure: bool,
+    // 是否从本地已有文件的末尾继续下载
+    resume: bool,
+    // 建立连接的超时时间
+    connect_timeout: Duration,
+    // 等待服务器响应的超时时间
+    timeout: Duration,
+}
+
+impl SftpConfig {
+    pub fn new() -> Self {
+        SftpConfig {
+            identity_file: None,
+            passphrase: None,
+            insecure: false,
+            resume: false,
+            connect_timeout: Duration::from_secs(15),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn set_identity_file(mut self, identity_file: Option<String>) -> Self {
+        self.identity_file = identity_file;
+        self
+    }
+
+    pub fn set_passphrase(mut self, passphrase: Option<String>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    pub fn set_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    pub fn set_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    pub fn set_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SFTP {
+    session: Session,
+    sftp: Sftp,
+    resume: bool,
+}
+
+impl SFTP {
+    /// 连接服务器,校验主机密钥后使用密码或者密钥登录
+    pub fn login(
+        host: &str,
+        port: u16,
+        user: &str,
+        password: Option<&str>,
+        config: &SftpConfig,
+    ) -> Result<Self> {
+        let address = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve {}", host))?;
+        let stream = TcpStream::connect_timeout(&address, config.connect_timeout)
+            .map_err(|e| anyhow!("could not connect to {}: {}", address, e))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(config.timeout.as_millis() as u32);
+        session
+            .handshake()
+            .map_err(|e| anyhow!("ssh handshake with {} failed: {}", address, e))?;
+        if !config.insecure {
+            check_host_key(&session, host, port)?;
+        }
+        authenticate(&session, user, password, config)?;
+        let sftp = session.sftp()?;
+        println!("{}", "Login Ok!".color(Color::Red));
+        Ok(SFTP {
+            session,
+            sftp,
+            resume: config.resume,
+        })
+    }
+
+    /// 获取文件或目录的信息
+    pub fn stat(&self, path: &str) -> Result<FileStat> {
+        Ok(self.sftp.stat(Path::new(path))?)
+    }
+
+    /// 列出目录下的文件名称和信息
+    pub fn list(&self, path: &str) -> Result<Vec<(String, FileStat)>> {
+        let mut entries: Vec<(String, FileStat)> = self
+            .sftp
+            .readdir(Path::new(path))?
+            .into_iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some((name, stat))
+            })
+            .filter(|(name, _)| name != "." && name != "..")
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// 下载某个文件到指定目录下,返回下载的字节数
+    pub fn download(&self, path: &str, target: &str) -> Result<u64> {
+        let filename = file_name(path)?;
+        println!(
+            "{}",
+            format!("download {}.......", filename).gradient(Color::Green)
+        );
+        let size = self.stat(path)?.size.unwrap_or(0);
+        let pb = ProgressBar::new(size);
+        pb.set_style(progress_style());
+        let downloaded = self.fetch(path, &Path::new(target).join(filename), &pb);
+        pb.finish();
+        let downloaded = downloaded?;
+        println!("{}", "download oK.......".gradient(Color::Green));
+        Ok(downloaded)
+    }
+
+    /// 递归下载目录,远程目录下的文件保存在 target/目录名 下
+    /// 目录没有名称时(例如用户目录 .)直接保存在 target 下
+    pub fn download_dir(&self, path: &str, target: &str) -> Result<()> {
+        let begin = Instant::now();
+        let root = local_root(path, target);
+        let mut files = Vec::new();
+        self.walk(Path::new(path), &root, &mut files)?;
+        let total_size: u64 = files.iter().map(|(_, _, size)| size).sum();
+        println!(
+            "{}",
+            format!(
+                "download {} files ({} bytes).......",
+                files.len(),
+                total_size
+            )
+            .gradient(Color::Green)
+        );
+        let pb = ProgressBar::new(total_size);
+        pb.set_style(progress_style());
+        let mut downloaded = 0;
+        let mut failed = Vec::new();
+        for (index, (remote, local, _)) in files.iter().enumerate() {
+            let name = local.strip_prefix(target).unwrap_or(local).display();
+            pb.set_message(name.to_string());
+            let line = match self.fetch(&remote.to_string_lossy(), local, &pb) {
+                Ok(size) => {
+                    downloaded += size;
+                    format!("[{}/{}] {} {} bytes ok", index + 1, files.len(), name, size)
+                }
+                Err(e) => {
+                    failed.push(name.to_string());
+                    format!("[{}/{}] {} failed: {}", index + 1, files.len(), name, e)
+                }
+            };
+            pb.println(line);
+        }
+        pb.finish_with_message("done");
+        println!(
+            "{}",
+            format!(
+                "{}/{} files downloaded, {} bytes in {:.1}s",
+                files.len() - failed.len(),
+                files.len(),
+                downloaded,
+                begin.elapsed().as_secs_f64()
+            )
+            .gradient(Color::Green)
+        );
+        for name in failed {
+            println!("{}", format!("failed: {}", name).color(Color::Red));
+        }
+        Ok(())
+    }
+
+    /// 遍历远程目录,创建对应的本地目录并收集需要下载的文件
+    /// 符号链接和特殊文件会被跳过
+    fn walk(
+        &self,
+        remote: &Path,
+        local: &Path,
+        files: &mut Vec<(PathBuf, PathBuf, u64)>,
+    ) -> Result<()> {
+        fs::create_dir_all(local)?;
+        for (name, stat) in self.list(&remote.to_string_lossy())? {
+            if stat.is_dir() {
+                self.walk(&remote.join(&name), &local.join(&name), files)?;
+            } else if stat.is_file() {
+                files.push((
+                    remote.join(&name),
+                    local.join(&name),
+                    stat.size.unwrap_or(0),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 下载文件并更新进度条,返回本次下载的字节数
+    /// 设置了断点续传时从本地已有文件的末尾继续下载
+    pub fn fetch(&self, path: &str, target: &Path, pb: &ProgressBar) -> Result<u64> {
+        let size = self.stat(path)?.size.unwrap_or(0);
+        let local = fs::metadata(target).ok().map(|metadata| metadata.len());
+        let offset = resume_offset(self.resume, local, size);
+        let mut remote_file = self.sftp.open(Path::new(path))?;
+        let mut file = if offset > 0 {
+            remote_file.seek(SeekFrom::Start(offset))?;
+            pb.inc(offset);
+            OpenOptions::new().append(true).open(target)?
+        } else {
+            File::create(target)?
+        };
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut downloaded = 0;
+        loop {
+            let n = remote_file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n])?;
+            downloaded += n as u64;
+            pb.inc(n as u64);
+        }
+        file.flush()?;
+        Ok(downloaded)
+    }
+
+    /// 断开链接
+    pub fn disconnect(&self) -> Result<()> {
+        self.session.disconnect(None, "bye", None)?;
+        Ok(())
+    }
+}
+
+/// 使用 ~/.ssh/known_hosts 校验服务器的主机密钥
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let mut known_hosts = session.known_hosts()?;
+    let file = ssh_dir().join("known_hosts");
+    if file.exists() {
+        known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+    }
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("could not get host key"))?;
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "host key for {} does not match the one in known_hosts",
+            host
+        )),
+        _ => Err(anyhow!(
+            "host {} is not in known_hosts, use --insecure to skip the check",
+            host
+        )),
+    }
+}
+
+/// 登录,依次尝试指定的密钥、密码、ssh-agent 和默认密钥
+fn authenticate(
+    session: &Session,
+    user: &str,
+    password: Option<&str>,
+    config: &SftpConfig,
+) -> Result<()> {
+    if let Some(identity) = &config.identity_file {
+        session.userauth_pubkey_file(
+            user,
+            None,
+            Path::new(identity),
+            config.passphrase.as_deref(),
+        )?;
+    } else if let Some(password) = password {
+        session.userauth_password(user, password)?;
+    } else if session.userauth_agent(user).is_err() {
+        for key in DEFAULT_KEYS.iter().map(|name| ssh_dir().join(name)) {
+            if key.exists()
+                && session
+                    .userauth_pubkey_file(user, None, &key, config.passphrase.as_deref())
+                    .is_ok()
+            {
+                break;
+            }
+        }
+    }
+    if session.authenticated() {
+        Ok(())
+    } else {
+        Err(anyhow!("authentication failed for user {}", user))
+    }
+}
+
+/// 用户的 ~/.ssh 目录
+fn ssh_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    Path::new(&home).join(".ssh")
+}
+
+/// 断点续传的起始位置,本地文件比远程文件大时重新下载
+fn resume_offset(resume: bool, local: Option<u64>, remote: u64) -> u64 {
+    match local {
+        Some(local) if resume && local <= remote => local,
+        _ => 0,
+    }
+}
+
+/// 下载目录时保存的本地目录,目录没有名称时直接保存在 target 下
+fn local_root(path: &str, target: &str) -> PathBuf {
+    match file_name(path) {
+        Ok(name) => Path::new(target).join(name),
+        Err(_) => PathBuf::from(target),
+    }
+}
+
+/// 取出路径的最后一段作为本地文件名
+fn file_name(path: &str) -> Result<String> {
+    Path::new(path.trim_end_matches('/'))
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow!("could not get file name of {}", path))
+}
+
+#[cfg(test)]
+mod sftp_test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("/data/file.txt").unwrap(), "file.txt");
+        assert_eq!(file_name("backup/").unwrap(), "backup");
+        assert!(file_name("/").is_err());
+        assert!(file_name("..").is_err());
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        assert_eq!(resume_offset(true, Some(100), 300), 100);
+        assert_eq!(resume_offset(true, Some(300), 300), 300);
+        // 本地文件更大或者没有设置断点续传时重新下载
+        assert_eq!(resume_offset(true, Some(400), 300), 0);
+        assert_eq!(resume_offset(false, Some(100), 300), 0);
+        assert_eq!(resume_offset(true, None, 300), 0);
+    }
+
+    #[test]
+    fn test_local_root() {
+        assert_eq!(local_root("/var/log/", "out"), Path::new("out/log"));
+        assert_eq!(local_root("~/backup", "out"), Path::new("out/backup"));
+        assert_eq!(local_root(".", "out"), Path::new("out"));
+    }
+
+    /// 需要本机运行的 OpenSSH 服务器,使用 cargo test -- --ignored 运行
+    /// SFTP_TEST_USER 和 SFTP_TEST_PASSWORD 指定登录的用户,没有密码时使用 ssh-agent 或默认密钥
+    /// 设置 SFTP_TEST_KEY 时同时测试指定私钥登录
+    #[test]
+    #[ignore]
+    fn test_sftp_download() {
+        let user = env::var("SFTP_TEST_USER").unwrap();
+        let password = env::var("SFTP_TEST_PASSWORD").ok();
+        let config = SftpConfig::new().set_insecure(true);
+        let dir = env::temp_dir().join(format!("sftp_test_{}", std::process::id()));
+        let remote = dir.join("remote");
+        let local = dir.join("local");
+        fs::create_dir_all(remote.join("sub")).unwrap();
+        fs::create_dir_all(&local).unwrap();
+        let content = vec![7; 200_000];
+        fs::write(remote.join("a.txt"), b"hello sftp").unwrap();
+        fs::write(remote.join("sub").join("b.bin"), &content).unwrap();
+
+        // 递归下载目录
+        let sftp = SFTP::login("127.0.0.1", 22, &user, password.as_deref(), &config).unwrap();
+        sftp.download_dir(remote.to_str().unwrap(), local.to_str().unwrap())
+            .unwrap();
+        assert_eq!(fs::read(local.join("remote/a.txt")).unwrap(), b"hello sftp");
+        assert_eq!(fs::read(local.join("remote/sub/b.bin")).unwrap(), content);
+        sftp.disconnect().unwrap();
+
+        // 断点续传只下载剩下的部分
+        let target = local.join("b.bin");
+        fs::write(&target, &content[..1000]).unwrap();
+        let config = config.set_resume(true);
+        let sftp = SFTP::login("127.0.0.1", 22, &user, password.as_deref(), &config).unwrap();
+        let remote_file = remote.join("sub/b.bin");
+        let downloaded = sftp
+            .fetch(
+                remote_file.to_str().unwrap(),
+                &target,
+                &ProgressBar::hidden(),
+            )
+            .unwrap();
+        assert_eq!(downloaded, 199_000);
+        assert_eq!(fs::read(&target).unwrap(), content);
+        sftp.disconnect().unwrap();
+
+        if let Ok(key) = env::var("SFTP_TEST_KEY") {
+            let config = config.set_identity_file(Some(key));
+            let sftp = SFTP::login("127.0.0.1", 22, &user, None, &config).unwrap();
+            sftp.disconnect().unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}